@@ -0,0 +1,280 @@
+#define_import_path manoka::chunk
+
+const CHUNK_SIZE: u32 = #{CHUNK_SIZE};
+const CHUNK_VOXEL_COUNT: u32 = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+const CHUNK_VOXEL_COUNT_DIV_32: u32 = CHUNK_VOXEL_COUNT / 32;
+
+// mip levels 1 (half the chunk size) to 1^3, see `chunk::occupancy`
+const OCCUPANCY_MIP_LEVELS: u32 = #{OCCUPANCY_MIP_LEVELS};
+const OCCUPANCY_MIP_WORDS: u32 = #{OCCUPANCY_MIP_WORDS};
+
+struct ChunkOccupancy {
+  occupancy: array<u32, CHUNK_VOXEL_COUNT_DIV_32>,
+  mips:      array<u32, OCCUPANCY_MIP_WORDS>,
+}
+
+// The occupancy of every chunk asset, shared by the entities using it.
+@group(0) @binding(0) var<storage> om_array: array<ChunkOccupancy>;
+// The chunk-to-world transform of every rendered chunk.
+@group(0) @binding(1) var<storage> transform_array: array<mat4x4<f32>>;
+// The entry in `om_array` of every rendered chunk.
+@group(0) @binding(4) var<storage> occupancy_indices: array<u32>;
+
+// A node of the BVH over the world space bounds of every rendered chunk,
+// depth first. See `bvh::BvhNode`.
+struct BvhNode {
+  min:   vec3<f32>,
+  // the index of the first node after this node's subtree
+  skip:  u32,
+  max:   vec3<f32>,
+  // the leaf's index in `transform_array`, or `BVH_INTERIOR`
+  chunk: u32,
+}
+
+const BVH_INTERIOR: u32 = 0xffffffffu;
+
+@group(0) @binding(5) var<storage> chunk_bvh: array<BvhNode>;
+
+// `chunk` is an index into `om_array`, e.g. `current.occupancy_index`.
+fn is_occupied(chunk: u32, pos: vec3<i32>) -> bool {
+  let size = i32(CHUNK_SIZE);
+  if any(pos < vec3<i32>(0)) || any(pos >= vec3<i32>(size)) {
+    return false;
+  }
+  let index = u32(pos.z * size * size + pos.y * size + pos.x);
+  return (om_array[chunk].occupancy[index / 32] & (1u << (index % 32))) != 0;
+}
+
+// The word offset of mip `level` in `ChunkOccupancy::mips`, for levels from
+// 1. Mirrors `MIP_OFFSETS` in `chunk::occupancy`.
+fn occupancy_mip_offset(level: u32) -> u32 {
+  var offset = 0u;
+  for (var l = 1u; l < level; l++) {
+    let size = CHUNK_SIZE >> l;
+    offset += (size * size * size + 31) / 32;
+  }
+  return offset;
+}
+
+// Whether anything is occupied within the node at `pos` of `level`, where
+// level 0 is single voxels. Mirrors `GpuChunkOccupancy::is_occupied`.
+fn is_occupied_at_level(chunk: u32, level: u32, pos: vec3<u32>) -> bool {
+  let size = CHUNK_SIZE >> level;
+  let index = pos.z * size * size + pos.y * size + pos.x;
+  if level == 0 {
+    return (om_array[chunk].occupancy[index / 32] & (1u << (index % 32))) != 0;
+  }
+  let i = occupancy_mip_offset(level) * 32 + index;
+  return (om_array[chunk].mips[i / 32] & (1u << (i % 32))) != 0;
+}
+
+// Whether the voxel containing the world space point `p` is occupied in any
+// rendered chunk. Walks `chunk_bvh` like `raycast_scene`, visiting only the
+// chunks whose bounds contain the point.
+fn is_occupied_world(p: vec3<f32>) -> bool {
+  var i = 0u;
+  while i < arrayLength(&chunk_bvh) {
+    let node = chunk_bvh[i];
+    if any(p < node.min) || any(p > node.max) {
+      i = node.skip;
+      continue;
+    }
+
+    if node.chunk != BVH_INTERIOR {
+      let chunk_from_world = affine_inverse(transform_array[node.chunk]);
+      let local = (chunk_from_world * vec4<f32>(p, 1.0)).xyz;
+      if is_occupied(occupancy_indices[node.chunk], vec3<i32>(floor(local))) {
+        return true;
+      }
+    }
+    i++;
+  }
+  return false;
+}
+
+// The inverse of an affine transform, e.g. an entry of `transform_array`.
+fn affine_inverse(m: mat4x4<f32>) -> mat4x4<f32> {
+  let x = m[0].xyz;
+  let y = m[1].xyz;
+  let z = m[2].xyz;
+  let rows = mat3x3<f32>(cross(y, z), cross(z, x), cross(x, y));
+  let linear = transpose(rows) * (1.0 / dot(x, rows[0]));
+  let translation = -(linear * m[3].xyz);
+  return mat4x4<f32>(
+    vec4<f32>(linear[0], 0.0),
+    vec4<f32>(linear[1], 0.0),
+    vec4<f32>(linear[2], 0.0),
+    vec4<f32>(translation, 1.0),
+  );
+}
+
+// Where a ray first hits an occupied voxel. Mirrors `OccupancyHit`.
+struct ChunkHit {
+  hit:    bool,
+  voxel:  vec3<u32>,
+  // the ray parameter at which the ray enters the voxel
+  t:      f32,
+  // the normal of the face the ray entered through
+  normal: vec3<f32>,
+}
+
+fn min_axis(v: vec3<f32>) -> u32 {
+  if v.x <= v.y && v.x <= v.z {
+    return 0u;
+  }
+  return select(2u, 1u, v.y <= v.z);
+}
+
+fn max_axis(v: vec3<f32>) -> u32 { return min_axis(-v); }
+
+// Finds the first occupied voxel along a ray in chunk coordinates, skipping
+// empty space a whole mip node at a time. Mirrors `occupancy::raycast_levels`.
+fn raycast_chunk(chunk: u32, origin: vec3<f32>, ray_dir: vec3<f32>) -> ChunkHit {
+  var result = ChunkHit(false, vec3<u32>(0u), 0.0, vec3<f32>(0.0));
+
+  // nudge axes the ray runs parallel to, so they are just never crossed
+  let dir = select(ray_dir, vec3<f32>(1e-8), ray_dir == vec3<f32>(0.0));
+  let inv_dir = 1.0 / dir;
+  let size = vec3<f32>(f32(CHUNK_SIZE));
+
+  // clip the ray to the chunk
+  let t0 = -origin * inv_dir;
+  let t1 = (size - origin) * inv_dir;
+  let near = min(t0, t1);
+  let far = max(t0, t1);
+  var t = max(max(near.x, max(near.y, near.z)), 0.0);
+  let t_exit = min(far.x, min(far.y, far.z));
+  if t > t_exit {
+    return result;
+  }
+  var axis = max_axis(near);
+  var pos = origin + dir * t;
+  if t > 0.0 {
+    pos[axis] = select(size[axis], 0.0, dir[axis] > 0.0);
+  }
+
+  // the previous cell, so the ray can't step back into it
+  var last = select(vec3<f32>(3.4e38), vec3<f32>(-3.4e38), dir > vec3<f32>(0.0));
+
+  // each step leaves a node, so this is never reached before the ray exits
+  for (var steps = 0u; steps < 3u * CHUNK_SIZE + 1u; steps++) {
+    var cell = select(floor(pos), ceil(pos) - 1.0, dir < vec3<f32>(0.0));
+    cell = select(
+      select(cell, min(cell, last), dir < vec3<f32>(0.0)),
+      max(cell, last),
+      dir > vec3<f32>(0.0),
+    );
+    last = cell;
+    let voxel = vec3<u32>(clamp(cell, vec3<f32>(0.0), size - 1.0));
+
+    // the coarsest empty node containing the voxel, if any
+    var level = -1;
+    for (var l = i32(OCCUPANCY_MIP_LEVELS); l >= 0; l--) {
+      if !is_occupied_at_level(chunk, u32(l), voxel >> vec3<u32>(u32(l))) {
+        level = l;
+        break;
+      }
+    }
+    if level < 0 {
+      result.hit = true;
+      result.voxel = voxel;
+      result.t = t;
+      result.normal[axis] = -sign(dir[axis]);
+      return result;
+    }
+
+    // jump to where the ray leaves that node
+    let shift = vec3<u32>(u32(level));
+    let node_min = vec3<f32>((voxel >> shift) << shift);
+    let node_size = f32(1u << u32(level));
+    let bound = select(node_min, node_min + node_size, dir > vec3<f32>(0.0));
+    let t_bound = (bound - origin) * inv_dir;
+    axis = min_axis(t_bound);
+    t = max(t_bound[axis], t);
+    if t >= t_exit {
+      return result;
+    }
+    pos = origin + dir * t;
+    pos[axis] = bound[axis];
+  }
+  return result;
+}
+
+// Where a world space ray first hits any rendered chunk. Mirrors `SceneHit`.
+struct SceneHit {
+  hit:    bool,
+  // the hit chunk's index in `transform_array`
+  chunk:  u32,
+  voxel:  vec3<u32>,
+  t:      f32,
+  // the world space normal of the face the ray entered through
+  normal: vec3<f32>,
+}
+
+// Marches one chunk, keeping the hit if it is nearer than `nearest`.
+fn raycast_scene_chunk(
+  chunk: u32,
+  origin: vec3<f32>,
+  dir: vec3<f32>,
+  nearest: ptr<function, SceneHit>,
+) {
+  // chunk space rays keep the world space ray parameter
+  let chunk_from_world = affine_inverse(transform_array[chunk]);
+  let chunk_origin = (chunk_from_world * vec4<f32>(origin, 1.0)).xyz;
+  let chunk_dir = (chunk_from_world * vec4<f32>(dir, 0.0)).xyz;
+  let hit = raycast_chunk(occupancy_indices[chunk], chunk_origin, chunk_dir);
+  if !hit.hit || ((*nearest).hit && (*nearest).t <= hit.t) {
+    return;
+  }
+
+  let normal_from_chunk = transpose(mat3x3<f32>(
+    chunk_from_world[0].xyz,
+    chunk_from_world[1].xyz,
+    chunk_from_world[2].xyz,
+  ));
+  *nearest = SceneHit(
+    true,
+    chunk,
+    hit.voxel,
+    hit.t,
+    normalize(normal_from_chunk * hit.normal),
+  );
+}
+
+// Finds the nearest voxel of any rendered chunk along a world space ray, or
+// just any voxel in the way if `any_hit` is set. Walks `chunk_bvh` without a
+// stack, skipping the subtrees whose bounds the ray misses or enters beyond
+// the nearest hit so far. Mirrors `ChunkScene::traverse`.
+fn raycast_scene(origin: vec3<f32>, ray_dir: vec3<f32>, any_hit: bool) -> SceneHit {
+  var nearest = SceneHit(false, 0u, vec3<u32>(0u), 0.0, vec3<f32>(0.0));
+
+  // nudge axes the ray runs parallel to, so they are just never crossed
+  let dir = select(ray_dir, vec3<f32>(1e-8), ray_dir == vec3<f32>(0.0));
+  let inv_dir = 1.0 / dir;
+
+  // every node is entered at most once, as `skip` only ever moves forward
+  var i = 0u;
+  while i < arrayLength(&chunk_bvh) {
+    let node = chunk_bvh[i];
+    let t0 = (node.min - origin) * inv_dir;
+    let t1 = (node.max - origin) * inv_dir;
+    let near = min(t0, t1);
+    let far = max(t0, t1);
+    let t_enter = max(max(near.x, max(near.y, near.z)), 0.0);
+    let t_exit = min(far.x, min(far.y, far.z));
+    let t_max = select(3.4e38, nearest.t, nearest.hit);
+    if t_enter > t_exit || t_enter > t_max {
+      i = node.skip;
+      continue;
+    }
+
+    if node.chunk != BVH_INTERIOR {
+      raycast_scene_chunk(node.chunk, origin, dir, &nearest);
+      if any_hit && nearest.hit {
+        return nearest;
+      }
+    }
+    i++;
+  }
+  return nearest;
+}
@@ -1,9 +1,7 @@
-
-const CHUNK_VOXEL_COUNT: u32 = 64*64*64;
-const CHUNK_VOXEL_COUNT_DIV_32: u32 = CHUNK_VOXEL_COUNT / 32;
-
-struct ChunkOccupancy {
-  occupancy: array<u32, CHUNK_VOXEL_COUNT_DIV_32>,
+#import bevy_render::view::View
+#import manoka::chunk::{
+  CHUNK_SIZE, CHUNK_VOXEL_COUNT, affine_inverse, is_occupied,
+  is_occupied_world, raycast_scene, transform_array,
 }
 
 struct SunLight {
@@ -12,36 +10,149 @@ struct SunLight {
   direction:   vec3<f32>,
 }
 
-struct FullVoxel {
-  normal: vec3<f32>,
-  color: vec3<f32>,
-}
-
-struct ChunkAttributes {
-  attributes: array<FullVoxel>,
+struct VoxelMaterial {
+  albedo:    vec3<f32>,
+  emissive:  vec3<f32>,
+  roughness: f32,
+  metallic:  f32,
+  opacity:   f32,
 }
 
 struct DirectPassUniform {
-  current_chunk: u32,
+  current_chunk:   u32,
+  occupancy_index: u32,
 }
 
 struct DirectPassOutput {
   output: array<vec3<f32>, CHUNK_VOXEL_COUNT>,
 }
 
-@group(0) @binding(0) var<storage> om_array: array<ChunkOccupancy>; 
-@group(0) @binding(1) var<storage> transform_array: array<mat4x4<f32>>;
+const BRICK_SIZE: u32 = #{BRICK_SIZE};
+const BRICK_GRID_SIZE: u32 = CHUNK_SIZE / BRICK_SIZE;
+const BRICK_VOXEL_COUNT: u32 = BRICK_SIZE * BRICK_SIZE * BRICK_SIZE;
+
+// bindings 0, 1, 4 and 5 of group 0 are in `manoka::chunk`
 @group(0) @binding(2) var<storage> light_array: array<SunLight>;
+@group(0) @binding(3) var<storage> materials: array<VoxelMaterial>;
+// the voxels of every occupied brick, see `chunk::render::BrickPool`
+@group(0) @binding(6) var<storage> brick_materials: array<u32>;
+#ifndef DERIVED_NORMALS
+@group(0) @binding(7) var<storage> brick_normals: array<vec3<f32>>;
+#endif
 
-@group(1) @binding(0) var<storage> attributes: ChunkAttributes;
+// the brick in the brick pool of each cell of the chunk's brick grid
+@group(1) @binding(0) var<storage> brick_grid: array<u32>;
 @group(1) @binding(1) var<uniform> current: DirectPassUniform;
-@group(1) @binding(2) var<storage, read_write> output: DirectPassOutput;
+// the outputs of every rendered chunk, indexed by `current.current_chunk`
+@group(1) @binding(2) var<storage, read_write> outputs: array<DirectPassOutput>;
+
+@group(2) @binding(0) var<uniform> view: View;
+
+#ifdef DERIVED_NORMALS
+const NORMAL_KERNEL_RADIUS: i32 = #{NORMAL_KERNEL_RADIUS};
+
+// Whether the voxel at `pos` relative to the current chunk is occupied,
+// looking into whichever rendered chunks cover it beyond the chunk's bounds.
+fn is_occupied_around(pos: vec3<i32>) -> bool {
+  let size = i32(CHUNK_SIZE);
+  if all(pos >= vec3<i32>(0)) && all(pos < vec3<i32>(size)) {
+    return is_occupied(current.occupancy_index, pos);
+  }
+  let world_from_chunk = transform_array[current.current_chunk];
+  let center = vec3<f32>(pos) + 0.5;
+  return is_occupied_world((world_from_chunk * vec4<f32>(center, 1.0)).xyz);
+}
+
+// Mirrors `normals::estimate_normal`: the sum of the offsets to every empty
+// voxel within the kernel. Voxels beyond the chunk are read from the chunks
+// around it, like `Chunk::derive_normals` does through `VoxelWorld`.
+fn derive_normal(pos: vec3<i32>) -> vec3<f32> {
+  let r = NORMAL_KERNEL_RADIUS;
+  var normal = vec3<f32>(0.0);
+  for (var z = -r; z <= r; z++) {
+    for (var y = -r; y <= r; y++) {
+      for (var x = -r; x <= r; x++) {
+        let offset = vec3<i32>(x, y, z);
+        let in_kernel = dot(offset, offset) < (r + 1) * (r + 1);
+        if in_kernel && !is_occupied_around(pos + offset) {
+          normal += vec3<f32>(offset);
+        }
+      }
+    }
+  }
+  if dot(normal, normal) == 0.0 {
+    return vec3<f32>(0.0, 1.0, 0.0);
+  }
+  return normalize(normal);
+}
+#endif
+
+fn voxel_normal(pos: vec3<i32>, pool_index: u32) -> vec3<f32> {
+#ifdef DERIVED_NORMALS
+  return derive_normal(pos);
+#else
+  return brick_normals[pool_index];
+#endif
+}
+
+// The entry in the brick pool of the occupied voxel at `pos`.
+fn brick_pool_index(pos: vec3<u32>) -> u32 {
+  let cell = pos / BRICK_SIZE;
+  let local = pos % BRICK_SIZE;
+  let brick = brick_grid[(cell.z * BRICK_GRID_SIZE + cell.y) * BRICK_GRID_SIZE + cell.x];
+  return brick * BRICK_VOXEL_COUNT + (local.z * BRICK_SIZE + local.y) * BRICK_SIZE + local.x;
+}
+
+const PI: f32 = 3.141592653589793;
+const SHADOW_RAY_BIAS: f32 = 1.001;
+
+// Where a shadow ray from the center of a voxel of the current chunk leaves
+// the voxel, in world space. Mirrors `lighting::shadow_ray_origin`.
+fn shadow_ray_origin(pos: vec3<i32>, to_light: vec3<f32>) -> vec3<f32> {
+  let world_from_chunk = transform_array[current.current_chunk];
+  let center = vec3<f32>(pos) + 0.5;
+  let chunk_dir = abs((affine_inverse(world_from_chunk) * vec4<f32>(to_light, 0.0)).xyz);
+  let t = 0.5 / max(chunk_dir.x, max(chunk_dir.y, chunk_dir.z)) * SHADOW_RAY_BIAS;
+  return (world_from_chunk * vec4<f32>(center, 1.0)).xyz + to_light * t;
+}
+
+// Mirrors `lighting::voxel_radiance`.
+fn voxel_radiance(
+  pos: vec3<i32>,
+  normal: vec3<f32>,
+  material: VoxelMaterial,
+) -> vec3<f32> {
+  let diffuse = material.albedo / PI;
+
+  var radiance = material.emissive;
+  for (var i = 0u; i < arrayLength(&light_array); i++) {
+    let light = light_array[i];
+    let to_light = -normalize(light.direction);
+    let n_dot_l = dot(normal, to_light);
+    if n_dot_l <= 0.0 {
+      continue;
+    }
+    if raycast_scene(shadow_ray_origin(pos, to_light), to_light, true).hit {
+      continue;
+    }
+    radiance += diffuse * light.color.rgb * light.illuminance * n_dot_l;
+  }
+  return radiance;
+}
 
 @compute @workgroup_size(4, 4, 4)
-fn update(
-  @builtin(global_invocation_id) invocation_id: vec3<u32>,
-  @builtin(num_workgroups) num_workgroups: vec3<u32>,
-) {
-  let index = invocation_id.z * 64 * 64 + invocation_id.y * 64 + invocation_id.x;
-  output.output[index] = vec3<f32>(invocation_id) / 64;
+fn update(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+  let size = CHUNK_SIZE;
+  let index = invocation_id.z * size * size + invocation_id.y * size + invocation_id.x;
+  let pos = vec3<i32>(invocation_id);
+  if !is_occupied(current.occupancy_index, pos) {
+    outputs[current.current_chunk].output[index] = vec3<f32>(0.0);
+    return;
+  }
+
+  let pool_index = brick_pool_index(invocation_id);
+  let normal = voxel_normal(pos, pool_index);
+  let material = materials[brick_materials[pool_index]];
+  let radiance = voxel_radiance(pos, normal, material);
+  outputs[current.current_chunk].output[index] = radiance * view.exposure;
 }
@@ -0,0 +1,58 @@
+#import bevy_render::view::View
+#import manoka::chunk::{CHUNK_SIZE, raycast_scene}
+
+// group 0 is the direct pass' common bind group, see `manoka::chunk`
+@group(1) @binding(0) var<uniform> view: View;
+
+@group(2) @binding(0) var color_texture: texture_storage_2d<rgba16float, write>;
+@group(2) @binding(1) var depth_texture: texture_storage_2d<r32float, write>;
+@group(2) @binding(2) var normal_texture: texture_storage_2d<rgba16float, write>;
+@group(2) @binding(3) var visibility_texture: texture_storage_2d<rg32uint, write>;
+
+// `visibility_texture`'s chunk index for pixels that hit nothing
+const NO_CHUNK: u32 = 0xffffffffu;
+
+// The world space ray through the center of a pixel of the viewport.
+// Unprojecting two depths works for both perspective and orthographic views.
+fn primary_ray(pixel: vec2<u32>) -> array<vec3<f32>, 2> {
+  let uv = (vec2<f32>(pixel) + 0.5) / view.viewport.zw;
+  let ndc = vec2<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+  let near = view.inverse_view_proj * vec4<f32>(ndc, 1.0, 1.0);
+  let far = view.inverse_view_proj * vec4<f32>(ndc, 0.5, 1.0);
+  let origin = near.xyz / near.w;
+  return array<vec3<f32>, 2>(origin, normalize(far.xyz / far.w - origin));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn primary(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+  let viewport = vec4<u32>(view.viewport);
+  if any(invocation_id.xy >= viewport.zw) {
+    return;
+  }
+  let pixel = viewport.xy + invocation_id.xy;
+  let ray = primary_ray(invocation_id.xy);
+  let origin = ray[0];
+  let dir = ray[1];
+
+  var depth = 3.4e38;
+  var normal = vec3<f32>(0.0);
+  var visibility = vec2<u32>(NO_CHUNK, 0u);
+  let hit = raycast_scene(origin, dir, false);
+  if hit.hit {
+    depth = hit.t;
+    normal = hit.normal;
+    let voxel = hit.voxel;
+    visibility = vec2<u32>(
+      hit.chunk,
+      voxel.z * CHUNK_SIZE * CHUNK_SIZE + voxel.y * CHUNK_SIZE + voxel.x,
+    );
+  }
+
+  // the resolve pass colors the pixels that hit a chunk
+  if visibility.x == NO_CHUNK {
+    textureStore(color_texture, pixel, vec4<f32>(dir * 0.5 + 0.5, 1.0));
+  }
+  textureStore(depth_texture, pixel, vec4<f32>(depth, 0.0, 0.0, 0.0));
+  textureStore(normal_texture, pixel, vec4<f32>(normal, 0.0));
+  textureStore(visibility_texture, pixel, vec4<u32>(visibility, 0u, 0u));
+}
@@ -0,0 +1,27 @@
+const CHUNK_SIZE: u32 = #{CHUNK_SIZE};
+const CHUNK_VOXEL_COUNT: u32 = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+// `visibility_texture`'s chunk index for pixels that hit nothing
+const NO_CHUNK: u32 = 0xffffffffu;
+
+@group(0) @binding(0) var visibility_texture: texture_2d<u32>;
+@group(0) @binding(1) var color_texture: texture_storage_2d<rgba16float, write>;
+
+// the `DirectPassOutput` of every rendered chunk, back to back
+@group(1) @binding(0) var<storage> radiance: array<vec3<f32>>;
+
+// Colors the pixels whose primary ray hit a chunk with the radiance the
+// direct pass computed for the voxel that was hit.
+@compute @workgroup_size(8, 8, 1)
+fn resolve(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+  let pixel = invocation_id.xy;
+  if any(pixel >= textureDimensions(visibility_texture)) {
+    return;
+  }
+  let visibility = textureLoad(visibility_texture, pixel, 0).xy;
+  if visibility.x == NO_CHUNK {
+    return;
+  }
+  let index = visibility.x * CHUNK_VOXEL_COUNT + visibility.y;
+  textureStore(color_texture, pixel, vec4<f32>(radiance[index], 1.0));
+}
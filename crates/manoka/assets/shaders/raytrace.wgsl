@@ -25,7 +25,8 @@ struct RayHit {
   through_empty: u32,
 };
 
-const VOXEL_COUNT: u32 = 64*64*64;
+const CHUNK_SIZE: u32 = #{CHUNK_SIZE};
+const VOXEL_COUNT: u32 = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
 
 @group(0) @binding(0) var<uniform> camera: Camera;
 @group(0) @binding(1) var<storage> voxels: array<Voxel>; 
@@ -88,11 +89,11 @@ fn calculate_ray_dir(invocation_id: vec3<u32>) -> vec3<f32> {
 }
 
 fn is_valid_voxel(coords: vec3<u32>) -> bool {
-  return all(coords < vec3(64));
+  return all(coords < vec3(CHUNK_SIZE));
 }
 
 fn sample_voxel(coords: vec3<u32>) -> Voxel {
-  return voxels[coords.x + coords.y*64 + coords.z*64*64];
+  return voxels[coords.x + coords.y*CHUNK_SIZE + coords.z*CHUNK_SIZE*CHUNK_SIZE];
 }
 
 fn cast_ray(ray: Ray) -> RayHit {
@@ -113,7 +114,7 @@ fn cast_ray(ray: Ray) -> RayHit {
     if (i > 100) { break; } else { i++; }
 
     hit.point = p;
-    hit.chunk_coords = vec3<u32>(p + 32);
+    hit.chunk_coords = vec3<u32>(p + f32(CHUNK_SIZE / 2));
     hit.steps = i;
 
     // see if we've converged
@@ -0,0 +1,168 @@
+//! Building and refitting the chunk BVH over chunks that move every frame,
+//! and how far refitting lets its SAH cost drift from a fresh build.
+
+use bevy::prelude::*;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use manoka::{
+  render::{bvh::Bvh, traversal::chunk_bounds},
+  CHUNK_SIZE, MAX_CHUNKS,
+};
+
+/// The length of a frame, in seconds.
+const FRAME: f32 = 1.0 / 60.0;
+
+/// A vector with each component in `-1..1`.
+fn random_vector(rng: &mut fastrand::Rng) -> Vec3 {
+  Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 2.0 - 1.0
+}
+
+/// Chunks that start packed in a grid like a streamed world, of which a
+/// share drifts and spins, each at its own random speed.
+struct MovingChunks {
+  translations: Vec<Vec3>,
+  rotations:    Vec<Quat>,
+  /// Linear and angular velocity, in chunks and radians per second.
+  velocities:   Vec<(Vec3, Vec3)>,
+}
+
+impl MovingChunks {
+  /// `count` chunks, each moving with probability `moving` at up to `speed`
+  /// chunks per second.
+  fn new(
+    rng: &mut fastrand::Rng,
+    count: usize,
+    moving: f32,
+    speed: f32,
+  ) -> Self {
+    let side = (count as f32).cbrt().ceil() as usize;
+    let translations = (0..count)
+      .map(|i| {
+        let cell = UVec3::new(
+          (i % side) as u32,
+          (i / side % side) as u32,
+          (i / side / side) as u32,
+        );
+        cell.as_vec3() * CHUNK_SIZE as f32
+      })
+      .collect();
+    let velocities = (0..count)
+      .map(|_| {
+        if rng.f32() >= moving {
+          return (Vec3::ZERO, Vec3::ZERO);
+        }
+        let linear = random_vector(rng) * speed * CHUNK_SIZE as f32;
+        (linear, random_vector(rng) * speed)
+      })
+      .collect();
+    Self {
+      translations,
+      rotations: vec![Quat::IDENTITY; count],
+      velocities,
+    }
+  }
+
+  fn step(&mut self) {
+    let chunks = self.translations.iter_mut().zip(&mut self.rotations);
+    for ((translation, rotation), (linear, angular)) in
+      chunks.zip(&self.velocities)
+    {
+      *translation += *linear * FRAME;
+      *rotation = Quat::from_scaled_axis(*angular * FRAME) * *rotation;
+    }
+  }
+
+  fn bounds(&self) -> Vec<(Vec3, Vec3)> {
+    self
+      .translations
+      .iter()
+      .zip(&self.rotations)
+      .map(|(translation, rotation)| {
+        chunk_bounds(Mat4::from_rotation_translation(*rotation, *translation))
+      })
+      .collect()
+  }
+}
+
+/// Which chunks move and how fast, in chunks per second.
+const SCENES: [(&str, f32, f32); 3] = [
+  ("all_slow", 1.0, 0.25),
+  ("all_fast", 1.0, 2.0),
+  ("few_fast", 0.1, 2.0),
+];
+
+/// Prints the SAH cost of refitting a tree built on the first frame,
+/// relative to rebuilding it, as the chunks move, and how many frames each
+/// rebuild ratio would let a refit tree live.
+fn report_sah_cost() {
+  let ratios = [1.1, 1.25, 1.5, 2.0];
+  println!("refit / rebuilt SAH cost over {MAX_CHUNKS} chunks");
+  for (name, moving, speed) in SCENES {
+    let mut rng = fastrand::Rng::with_seed(0);
+    let mut chunks = MovingChunks::new(&mut rng, MAX_CHUNKS, moving, speed);
+    let mut bvh = Bvh::build(&chunks.bounds());
+
+    let mut lifetimes = [None; 4];
+    let mut samples = Vec::new();
+    for frame in 1..=600 {
+      chunks.step();
+      let bounds = chunks.bounds();
+      bvh.refit(&bounds);
+      let ratio = bvh.sah_cost() / Bvh::build(&bounds).sah_cost();
+      for (lifetime, threshold) in lifetimes.iter_mut().zip(ratios) {
+        if lifetime.is_none() && ratio > threshold {
+          *lifetime = Some(frame);
+        }
+      }
+      if [1, 10, 60, 300, 600].contains(&frame) {
+        samples.push(format!("{frame}: {ratio:.3}"));
+      }
+    }
+
+    println!("  {name}: by frame {}", samples.join(", "));
+    let lifetimes = ratios
+      .iter()
+      .zip(lifetimes)
+      .map(|(threshold, lifetime)| match lifetime {
+        Some(frame) => format!("{threshold} after {frame}"),
+        None => format!("{threshold} never"),
+      })
+      .collect::<Vec<_>>();
+    println!("  {name}: exceeds {}", lifetimes.join(", "));
+  }
+}
+
+fn build_and_refit(c: &mut Criterion) {
+  report_sah_cost();
+
+  let mut group = c.benchmark_group("bvh");
+  for count in [16, 64, MAX_CHUNKS] {
+    let mut rng = fastrand::Rng::with_seed(0);
+    let mut chunks = MovingChunks::new(&mut rng, count, 1.0, 2.0);
+    let bvh = Bvh::build(&chunks.bounds());
+    chunks.step();
+    let bounds = chunks.bounds();
+
+    group.bench_with_input(
+      BenchmarkId::new("build", count),
+      &bounds,
+      |b, bounds| b.iter(|| Bvh::build(bounds)),
+    );
+    group.bench_with_input(
+      BenchmarkId::new("refit", count),
+      &bounds,
+      |b, bounds| {
+        let mut bvh = bvh.clone();
+        b.iter(|| bvh.refit(bounds))
+      },
+    );
+    group.bench_with_input(
+      BenchmarkId::new("sah_cost", count),
+      &bvh,
+      |b, bvh| b.iter(|| bvh.sah_cost()),
+    );
+  }
+  group.finish();
+}
+
+criterion_group!(benches, build_and_refit);
+criterion_main!(benches);
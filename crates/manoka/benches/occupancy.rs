@@ -0,0 +1,55 @@
+//! Packing chunk occupancy from each representation, against cloning the
+//! chunk to dense voxels first.
+
+use bevy::prelude::*;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use manoka::{
+  chunk::{Chunk, ChunkData, FullVoxel},
+  CHUNK_VOXEL_COUNT,
+};
+
+/// A dense chunk where each voxel is occupied with probability `density`.
+fn random_chunk(rng: &mut fastrand::Rng, density: f32) -> Chunk {
+  let data = (0..CHUNK_VOXEL_COUNT)
+    .map(|_| {
+      (rng.f32() < density).then(|| FullVoxel::new(Vec3::Y, rng.u32(0..4)))
+    })
+    .collect();
+  Chunk::new(ChunkData::Full { data })
+}
+
+fn prepare_occupancy(c: &mut Criterion) {
+  let mut rng = fastrand::Rng::with_seed(0);
+  let chunks = [
+    ("empty", Chunk::new_empty()),
+    ("sparse", random_chunk(&mut rng, 0.05)),
+    ("dense", random_chunk(&mut rng, 1.0)),
+  ];
+
+  let mut group = c.benchmark_group("prepare_occupancy");
+  for (name, chunk) in &chunks {
+    let representations = [
+      ("full", chunk.to_full()),
+      ("palette", chunk.to_palette()),
+      ("octree", chunk.to_octree()),
+      ("brickmap", chunk.to_brickmap()),
+    ];
+    for (representation, chunk) in &representations {
+      group.bench_with_input(
+        BenchmarkId::new(*representation, name),
+        chunk,
+        |b, chunk| b.iter(|| chunk.prepare_occupancy()),
+      );
+      // what packing cost before reading representations directly
+      group.bench_with_input(
+        BenchmarkId::new(format!("{representation}_cloned"), name),
+        chunk,
+        |b, chunk| b.iter(|| chunk.to_full().prepare_occupancy()),
+      );
+    }
+  }
+  group.finish();
+}
+
+criterion_group!(benches, prepare_occupancy);
+criterion_main!(benches);
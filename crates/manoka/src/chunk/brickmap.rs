@@ -0,0 +1,266 @@
+//! Two-level brickmap storage.
+//!
+//! The chunk is split into a grid of 8³ bricks. Each grid cell holds the
+//! index of its brick, or [`EMPTY_BRICK`] when the whole brick is empty, so
+//! empty space costs one word per 512 voxels and only occupied bricks store
+//! voxels.
+
+use bevy::prelude::*;
+
+use super::{
+  occupancy::{raycast_levels, OccupancyRaycast},
+  voxel_index, FullVoxel,
+};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+/// The edge length of a brick, in voxels.
+pub const BRICK_SIZE: usize = 8;
+pub const BRICK_VOXEL_COUNT: usize = BRICK_SIZE * BRICK_SIZE * BRICK_SIZE;
+/// The edge length of a chunk's brick grid, in bricks.
+pub const BRICK_GRID_SIZE: usize = CHUNK_SIZE / BRICK_SIZE;
+pub const BRICK_GRID_COUNT: usize =
+  BRICK_GRID_SIZE * BRICK_GRID_SIZE * BRICK_GRID_SIZE;
+
+/// The grid entry of a brick with no voxels.
+pub const EMPTY_BRICK: u32 = u32::MAX;
+
+/// The mip level of [`super::occupancy`] a brick covers.
+const BRICK_LEVEL: usize = BRICK_SIZE.trailing_zeros() as usize;
+
+/// The index of `pos` within a dense grid of `size`³ cells.
+fn dense_index(pos: UVec3, size: usize) -> usize {
+  let (x, y, z) = (pos.x as usize, pos.y as usize, pos.z as usize);
+  z * size * size + y * size + x
+}
+
+/// The index of the grid cell containing `pos`, and of `pos` within it.
+fn brick_indices(pos: UVec3) -> (usize, usize) {
+  let size = BRICK_SIZE as u32;
+  (
+    dense_index(pos / size, BRICK_GRID_SIZE),
+    dense_index(pos % size, BRICK_SIZE),
+  )
+}
+
+/// The chunk position of voxel `local` of the brick in grid cell `cell`.
+fn brick_voxel_pos(cell: usize, local: usize) -> UVec3 {
+  let unpack = |i: usize, size: usize| {
+    UVec3::new(
+      (i % size) as u32,
+      (i / size % size) as u32,
+      (i / (size * size)) as u32,
+    )
+  };
+  unpack(cell, BRICK_GRID_SIZE) * BRICK_SIZE as u32 + unpack(local, BRICK_SIZE)
+}
+
+/// Brickmap storage for a chunk.
+#[derive(Clone, Debug, Reflect)]
+pub struct BrickmapData {
+  /// The index into `bricks` of every grid cell, or [`EMPTY_BRICK`].
+  pub(super) grid:   Vec<u32>,
+  /// The voxels of each occupied brick, in brick-local dense order.
+  pub(super) bricks: Vec<Vec<Option<FullVoxel>>>,
+  /// The grid cell of each brick, so the brick moved into a freed slot can
+  /// be found without searching the grid.
+  pub(super) cells:  Vec<u32>,
+}
+
+impl Default for BrickmapData {
+  fn default() -> Self {
+    Self {
+      grid:   vec![EMPTY_BRICK; BRICK_GRID_COUNT],
+      bricks: Vec::new(),
+      cells:  Vec::new(),
+    }
+  }
+}
+
+impl BrickmapData {
+  /// Splits dense voxel data into bricks, dropping the empty ones.
+  pub fn from_full(data: &[Option<FullVoxel>]) -> Self {
+    let mut this = Self::default();
+    for (i, voxel) in data.iter().enumerate() {
+      let Some(voxel) = voxel else {
+        continue;
+      };
+      let (cell, local) = brick_indices(super::voxel_pos(i));
+      if this.grid[cell] == EMPTY_BRICK {
+        this.grid[cell] = this.alloc_brick(cell);
+      }
+      this.bricks[this.grid[cell] as usize][local] = Some(voxel.clone());
+    }
+    this
+  }
+
+  /// Expands the bricks back into dense voxel data.
+  pub fn to_full(&self) -> Vec<Option<FullVoxel>> {
+    let mut data = vec![None; CHUNK_VOXEL_COUNT];
+    for (pos, voxel) in self.iter_occupied() {
+      data[voxel_index(pos)] = Some(voxel.clone());
+    }
+    data
+  }
+
+  /// Borrows every voxel in dense order, without cloning them.
+  pub fn dense_refs(&self) -> Vec<Option<&FullVoxel>> {
+    let mut data = vec![None; CHUNK_VOXEL_COUNT];
+    for (pos, voxel) in self.iter_occupied() {
+      data[voxel_index(pos)] = Some(voxel);
+    }
+    data
+  }
+
+  /// The number of allocated bricks.
+  pub fn brick_count(&self) -> usize { self.bricks.len() }
+
+  /// Returns the voxel at `pos`, or `None` if it is empty or out of bounds.
+  pub fn get(&self, pos: UVec3) -> Option<&FullVoxel> {
+    if pos.cmpge(UVec3::splat(CHUNK_SIZE as u32)).any() {
+      return None;
+    }
+
+    let (cell, local) = brick_indices(pos);
+    match self.grid[cell] {
+      EMPTY_BRICK => None,
+      brick => self.bricks[brick as usize][local].as_ref(),
+    }
+  }
+
+  /// Writes the voxel at `pos`, allocating its brick if needed and freeing
+  /// it once it's empty.
+  pub fn set(&mut self, pos: UVec3, voxel: Option<FullVoxel>) {
+    let (cell, local) = brick_indices(pos);
+    let brick = match (self.grid[cell], &voxel) {
+      (EMPTY_BRICK, None) => return,
+      (EMPTY_BRICK, Some(_)) => {
+        self.grid[cell] = self.alloc_brick(cell);
+        self.grid[cell]
+      }
+      (brick, _) => brick,
+    };
+
+    let cleared = voxel.is_none();
+    self.bricks[brick as usize][local] = voxel;
+    if cleared && self.bricks[brick as usize].iter().all(Option::is_none) {
+      self.free_brick(cell);
+    }
+  }
+
+  /// Appends an empty brick for grid cell `cell`, returning its index.
+  fn alloc_brick(&mut self, cell: usize) -> u32 {
+    self.bricks.push(vec![None; BRICK_VOXEL_COUNT]);
+    self.cells.push(cell as u32);
+    self.bricks.len() as u32 - 1
+  }
+
+  /// Removes the brick of grid cell `cell`, moving the last brick into its
+  /// slot.
+  fn free_brick(&mut self, cell: usize) {
+    let brick = self.grid[cell];
+    self.grid[cell] = EMPTY_BRICK;
+    self.bricks.swap_remove(brick as usize);
+    self.cells.swap_remove(brick as usize);
+
+    if let Some(moved_cell) = self.cells.get(brick as usize) {
+      self.grid[*moved_cell as usize] = brick;
+    }
+  }
+
+  /// Iterates the position and contents of every occupied voxel, brick by
+  /// brick.
+  pub fn iter_occupied(&self) -> impl Iterator<Item = (UVec3, &FullVoxel)> {
+    self
+      .grid
+      .iter()
+      .enumerate()
+      .filter(|(_, brick)| **brick != EMPTY_BRICK)
+      .flat_map(move |(cell, brick)| {
+        self.bricks[*brick as usize].iter().enumerate().filter_map(
+          move |(local, voxel)| {
+            Some((brick_voxel_pos(cell, local), voxel.as_ref()?))
+          },
+        )
+      })
+  }
+
+  /// One bit per voxel in dense order, set where the voxel is occupied.
+  pub fn occupancy_mask(&self) -> [u32; CHUNK_VOXEL_COUNT / 32] {
+    let mut mask = [0; CHUNK_VOXEL_COUNT / 32];
+    for (pos, _) in self.iter_occupied() {
+      let i = voxel_index(pos);
+      mask[i / 32] |= 1 << (i % 32);
+    }
+    mask
+  }
+
+  /// Finds the first occupied voxel along a ray in chunk coordinates,
+  /// skipping empty bricks in one step.
+  pub fn raycast(&self, origin: Vec3, dir: Vec3) -> OccupancyRaycast {
+    raycast_levels(origin, dir, BRICK_LEVEL, |level, pos| match level {
+      0 => self.get(pos).is_some(),
+      BRICK_LEVEL => {
+        self.grid[dense_index(pos, BRICK_GRID_SIZE)] != EMPTY_BRICK
+      }
+      // levels in between have no data, so descend to voxels
+      _ => true,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::{
+    test_utils::{dense_mask, random_blocks, random_ray, random_voxels},
+    voxel_pos, GpuChunkOccupancy,
+  };
+
+  #[test]
+  fn set_matches_dense_writes() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let mut data = random_blocks(&mut rng, BRICK_SIZE as u32, 0.3, 2);
+    let mut brickmap = BrickmapData::from_full(&data);
+    for _ in 0..5000 {
+      // clustered writes, so bricks fill up and empty out again
+      let i = rng.usize(0..CHUNK_VOXEL_COUNT / 8);
+      let voxel = rng.bool().then(|| FullVoxel::new(Vec3::Y, 0));
+      brickmap.set(voxel_pos(i), voxel.clone());
+      data[i] = voxel;
+    }
+
+    assert_eq!(brickmap.to_full(), data);
+    let occupied_cells = brickmap.grid.iter().filter(|b| **b != EMPTY_BRICK);
+    assert_eq!(occupied_cells.count(), brickmap.brick_count());
+    for (brick, cell) in brickmap.cells.iter().enumerate() {
+      assert_eq!(brickmap.grid[*cell as usize], brick as u32);
+      assert!(brickmap.bricks[brick].iter().any(Option::is_some));
+    }
+  }
+
+  #[test]
+  fn raycast_matches_flat_traversal() {
+    let mut rng = fastrand::Rng::with_seed(2);
+    let mut hits = 0;
+    for data in [
+      random_voxels(&mut rng, 0.001, 1),
+      random_blocks(&mut rng, BRICK_SIZE as u32, 0.1, 1),
+      random_blocks(&mut rng, 2, 0.05, 1),
+    ] {
+      let brickmap = BrickmapData::from_full(&data);
+      let flat = GpuChunkOccupancy::from_mask(dense_mask(&data));
+      for _ in 0..500 {
+        let (origin, dir) = random_ray(&mut rng);
+        let hit = brickmap.raycast(origin, dir).hit;
+        let expected = flat.raycast_flat(origin, dir).hit;
+        assert_eq!(hit.map(|hit| hit.voxel), expected.map(|hit| hit.voxel));
+        assert_eq!(hit.map(|hit| hit.normal), expected.map(|hit| hit.normal));
+        if let (Some(hit), Some(expected)) = (hit, expected) {
+          assert!((hit.t - expected.t).abs() < 1e-3);
+          hits += 1;
+        }
+      }
+    }
+    assert!(hits > 100);
+  }
+}
@@ -0,0 +1,541 @@
+use bevy::{
+  prelude::*,
+  render::render_resource::ShaderType,
+  utils::{HashMap, HashSet},
+};
+
+use super::{voxel_index, Chunk, FullVoxel, VoxelKey};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+/// The edge length of the bricks at the bottom of the DAG.
+pub const DAG_BRICK_SIZE: usize = 8;
+const DAG_BRICK_VOXEL_COUNT: usize =
+  DAG_BRICK_SIZE * DAG_BRICK_SIZE * DAG_BRICK_SIZE;
+
+/// Flag marking a GPU child reference as pointing into the brick buffer.
+pub const GPU_DAG_BRICK_FLAG: u32 = 1 << 31;
+
+/// A reference to a deduplicated subtree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DagRef {
+  Empty,
+  Node(u32),
+  Brick(u32),
+}
+
+impl DagRef {
+  /// Encodes the reference for the GPU: `0` is empty, bricks carry
+  /// [`GPU_DAG_BRICK_FLAG`], and nodes are offset by one.
+  pub fn to_gpu(self) -> u32 {
+    match self {
+      DagRef::Empty => 0,
+      DagRef::Node(i) => i + 1,
+      DagRef::Brick(i) => i | GPU_DAG_BRICK_FLAG,
+    }
+  }
+}
+
+/// A shared-subtree voxel DAG over every chunk asset.
+///
+/// Chunks are split into an octree down to [`DAG_BRICK_SIZE`] bricks, and
+/// identical bricks and interior nodes are stored once no matter how many
+/// chunks (or places within a chunk) contain them.
+///
+/// Nodes and bricks count their references from roots and parent nodes, so
+/// that replacing or removing a chunk frees whatever no other chunk shares.
+/// Freed slots are reused by later insertions.
+#[derive(Resource, Default)]
+pub struct VoxelDag {
+  nodes:        Vec<[DagRef; 8]>,
+  node_refs:    Vec<u32>,
+  node_lookup:  HashMap<[DagRef; 8], u32>,
+  free_nodes:   Vec<u32>,
+  bricks:       Vec<Vec<Option<FullVoxel>>>,
+  brick_refs:   Vec<u32>,
+  brick_lookup: HashMap<Vec<Option<VoxelKey>>, u32>,
+  free_bricks:  Vec<u32>,
+  roots:        HashMap<AssetId<Chunk>, DagRoot>,
+  stats:        DagStats,
+}
+
+/// A chunk's root, with how many bricks and nodes its tree has before
+/// deduplication.
+#[derive(Clone, Copy, Debug)]
+struct DagRoot {
+  root:   DagRef,
+  bricks: usize,
+  nodes:  usize,
+}
+
+/// Counts of subtrees before and after deduplication.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DagStats {
+  pub chunks:        usize,
+  pub total_bricks:  usize,
+  pub unique_bricks: usize,
+  pub total_nodes:   usize,
+  pub unique_nodes:  usize,
+}
+
+impl DagStats {
+  /// The ratio of dense chunk storage to DAG storage.
+  pub fn compression_ratio(&self) -> f32 {
+    let dense_bytes = self.chunks
+      * CHUNK_VOXEL_COUNT
+      * std::mem::size_of::<Option<FullVoxel>>();
+    let dag_bytes = self.unique_bricks
+      * DAG_BRICK_VOXEL_COUNT
+      * std::mem::size_of::<Option<FullVoxel>>()
+      + self.unique_nodes * std::mem::size_of::<[DagRef; 8]>();
+    dense_bytes as f32 / dag_bytes.max(1) as f32
+  }
+}
+
+/// Child `i` of a node at `origin` with half-size `half`.
+fn child_origin(origin: UVec3, i: usize, half: u32) -> UVec3 {
+  let i = i as u32;
+  origin + UVec3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1) * half
+}
+
+/// The key identical bricks share in the brick lookup.
+fn brick_key(brick: &[Option<FullVoxel>]) -> Vec<Option<VoxelKey>> {
+  brick
+    .iter()
+    .map(|v| v.as_ref().map(FullVoxel::bit_key))
+    .collect()
+}
+
+fn brick_index(pos: UVec3) -> usize {
+  let (x, y, z) = (pos.x as usize, pos.y as usize, pos.z as usize);
+  z * DAG_BRICK_SIZE * DAG_BRICK_SIZE + y * DAG_BRICK_SIZE + x
+}
+
+impl VoxelDag {
+  /// Builds a DAG containing every chunk in `chunks`.
+  pub fn from_assets(chunks: &Assets<Chunk>) -> Self {
+    let mut dag = Self::default();
+    for (id, chunk) in chunks.iter() {
+      dag.insert(id, chunk);
+    }
+    dag
+  }
+
+  /// Adds a chunk to the DAG, sharing any subtrees already present. A chunk
+  /// already in the DAG under `id` is replaced.
+  pub fn insert(&mut self, id: AssetId<Chunk>, chunk: &Chunk) {
+    let data = chunk.into_full();
+    let (bricks, nodes) = (self.stats.total_bricks, self.stats.total_nodes);
+    let root = self.build(&data, UVec3::ZERO, CHUNK_SIZE as u32);
+    let root = DagRoot {
+      root,
+      bricks: self.stats.total_bricks - bricks,
+      nodes: self.stats.total_nodes - nodes,
+    };
+    // released after building, so subtrees the old and new versions share
+    // are kept
+    if let Some(old) = self.roots.insert(id, root) {
+      self.release_root(old);
+    }
+    self.stats.chunks = self.roots.len();
+  }
+
+  /// Removes a chunk from the DAG, returning whether it was present.
+  pub fn remove(&mut self, id: AssetId<Chunk>) -> bool {
+    let Some(root) = self.roots.remove(&id) else {
+      return false;
+    };
+    self.release_root(root);
+    self.stats.chunks = self.roots.len();
+    true
+  }
+
+  fn release_root(&mut self, root: DagRoot) {
+    self.stats.total_bricks -= root.bricks;
+    self.stats.total_nodes -= root.nodes;
+    self.release(root.root);
+  }
+
+  /// Drops one reference to a subtree, freeing it once nothing refers to it.
+  fn release(&mut self, subtree: DagRef) {
+    match subtree {
+      DagRef::Empty => {}
+      DagRef::Node(i) => {
+        self.node_refs[i as usize] -= 1;
+        if self.node_refs[i as usize] > 0 {
+          return;
+        }
+        let children =
+          std::mem::replace(&mut self.nodes[i as usize], [DagRef::Empty; 8]);
+        self.node_lookup.remove(&children);
+        self.free_nodes.push(i);
+        self.stats.unique_nodes -= 1;
+        for child in children {
+          self.release(child);
+        }
+      }
+      DagRef::Brick(i) => {
+        self.brick_refs[i as usize] -= 1;
+        if self.brick_refs[i as usize] > 0 {
+          return;
+        }
+        let brick = std::mem::take(&mut self.bricks[i as usize]);
+        self.brick_lookup.remove(&brick_key(&brick));
+        self.free_bricks.push(i);
+        self.stats.unique_bricks -= 1;
+      }
+    }
+  }
+
+  fn build(
+    &mut self,
+    data: &[Option<FullVoxel>],
+    origin: UVec3,
+    size: u32,
+  ) -> DagRef {
+    if size as usize == DAG_BRICK_SIZE {
+      return self.insert_brick(data, origin);
+    }
+
+    let half = size / 2;
+    let children: [DagRef; 8] = std::array::from_fn(|i| {
+      self.build(data, child_origin(origin, i, half), half)
+    });
+    if children.iter().all(|c| *c == DagRef::Empty) {
+      return DagRef::Empty;
+    }
+
+    self.stats.total_nodes += 1;
+    if let Some(&index) = self.node_lookup.get(&children) {
+      // the existing node already refers to the children
+      self.node_refs[index as usize] += 1;
+      for child in children {
+        self.release(child);
+      }
+      return DagRef::Node(index);
+    }
+
+    let index = match self.free_nodes.pop() {
+      Some(index) => {
+        self.nodes[index as usize] = children;
+        self.node_refs[index as usize] = 1;
+        index
+      }
+      None => {
+        self.nodes.push(children);
+        self.node_refs.push(1);
+        self.nodes.len() as u32 - 1
+      }
+    };
+    self.node_lookup.insert(children, index);
+    self.stats.unique_nodes += 1;
+    DagRef::Node(index)
+  }
+
+  fn insert_brick(
+    &mut self,
+    data: &[Option<FullVoxel>],
+    origin: UVec3,
+  ) -> DagRef {
+    let mut brick = Vec::with_capacity(DAG_BRICK_VOXEL_COUNT);
+    for z in 0..DAG_BRICK_SIZE as u32 {
+      for y in 0..DAG_BRICK_SIZE as u32 {
+        for x in 0..DAG_BRICK_SIZE as u32 {
+          brick.push(data[voxel_index(origin + UVec3::new(x, y, z))].clone());
+        }
+      }
+    }
+    if brick.iter().all(Option::is_none) {
+      return DagRef::Empty;
+    }
+
+    self.stats.total_bricks += 1;
+    let key = brick_key(&brick);
+    if let Some(&index) = self.brick_lookup.get(&key) {
+      self.brick_refs[index as usize] += 1;
+      return DagRef::Brick(index);
+    }
+
+    let index = match self.free_bricks.pop() {
+      Some(index) => {
+        self.bricks[index as usize] = brick;
+        self.brick_refs[index as usize] = 1;
+        index
+      }
+      None => {
+        self.bricks.push(brick);
+        self.brick_refs.push(1);
+        self.bricks.len() as u32 - 1
+      }
+    };
+    self.brick_lookup.insert(key, index);
+    self.stats.unique_bricks += 1;
+    DagRef::Brick(index)
+  }
+
+  /// Returns the voxel at `pos` in the given chunk, if it is in the DAG and
+  /// occupied.
+  pub fn get(&self, id: AssetId<Chunk>, pos: UVec3) -> Option<&FullVoxel> {
+    if pos.cmpge(UVec3::splat(CHUNK_SIZE as u32)).any() {
+      return None;
+    }
+
+    let mut node = self.roots.get(&id)?.root;
+    let mut size = CHUNK_SIZE as u32;
+    loop {
+      match node {
+        DagRef::Empty => return None,
+        DagRef::Brick(i) => {
+          let local = pos % DAG_BRICK_SIZE as u32;
+          return self.bricks[i as usize][brick_index(local)].as_ref();
+        }
+        DagRef::Node(i) => {
+          size /= 2;
+          let child = pos / size % 2;
+          node = self.nodes[i as usize]
+            [(child.x | child.y << 1 | child.z << 2) as usize];
+        }
+      }
+    }
+  }
+
+  pub fn stats(&self) -> DagStats { self.stats }
+
+  /// Flattens the DAG into buffers suitable for upload. Freed slots are
+  /// kept as empty nodes and bricks, so references stay valid.
+  pub fn to_gpu(&self) -> GpuVoxelDag {
+    let nodes = self
+      .nodes
+      .iter()
+      .map(|children| GpuDagNode {
+        children: children.map(DagRef::to_gpu),
+      })
+      .collect();
+
+    let mut bricks = Vec::with_capacity(self.bricks.len());
+    let mut attributes = Vec::new();
+    for brick in self.bricks.iter() {
+      let mut occupancy = [0; DAG_BRICK_VOXEL_COUNT / 32];
+      let first_attribute = attributes.len() as u32;
+      for (i, voxel) in brick.iter().enumerate() {
+        if let Some(voxel) = voxel {
+          occupancy[i / 32] |= 1 << (i % 32);
+          attributes.push(voxel.clone());
+        }
+      }
+      bricks.push(GpuDagBrick {
+        occupancy,
+        first_attribute,
+      });
+    }
+
+    GpuVoxelDag {
+      roots: self
+        .roots
+        .iter()
+        .map(|(id, root)| (*id, root.root.to_gpu()))
+        .collect(),
+      nodes,
+      bricks,
+      attributes,
+    }
+  }
+}
+
+#[derive(Clone, Debug, ShaderType)]
+pub struct GpuDagNode {
+  pub children: [u32; 8],
+}
+
+#[derive(Clone, Debug, ShaderType)]
+pub struct GpuDagBrick {
+  pub occupancy:       [u32; DAG_BRICK_VOXEL_COUNT / 32],
+  /// Index of the brick's first occupied voxel in the attribute buffer.
+  pub first_attribute: u32,
+}
+
+/// The flattened DAG. Each `Vec` maps onto its own storage buffer, and
+/// `roots` gives the encoded root reference of each chunk.
+pub struct GpuVoxelDag {
+  pub roots:      HashMap<AssetId<Chunk>, u32>,
+  pub nodes:      Vec<GpuDagNode>,
+  pub bricks:     Vec<GpuDagBrick>,
+  pub attributes: Vec<FullVoxel>,
+}
+
+/// Inserts, replaces or removes the chunks named by asset events.
+pub fn update_voxel_dag(
+  mut events: EventReader<AssetEvent<Chunk>>,
+  chunks: Res<Assets<Chunk>>,
+  mut dag: ResMut<VoxelDag>,
+) {
+  let changed = events
+    .read()
+    .filter_map(|event| match *event {
+      AssetEvent::Added { id }
+      | AssetEvent::Modified { id }
+      | AssetEvent::Removed { id } => Some(id),
+      AssetEvent::Unused { .. } | AssetEvent::LoadedWithDependencies { .. } => {
+        None
+      }
+    })
+    .collect::<HashSet<_>>();
+  if changed.is_empty() {
+    return;
+  }
+
+  for id in changed {
+    match chunks.get(id) {
+      Some(chunk) => dag.insert(id, chunk),
+      None => {
+        dag.remove(id);
+      }
+    }
+  }
+  let stats = dag.stats();
+  debug!(
+    "updated voxel DAG: {} chunks, {}/{} unique bricks, {}/{} unique nodes, \
+     {:.1}x compression",
+    stats.chunks,
+    stats.unique_bricks,
+    stats.total_bricks,
+    stats.unique_nodes,
+    stats.total_nodes,
+    stats.compression_ratio(),
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::utils::Uuid;
+
+  use super::*;
+  use crate::chunk::{
+    test_utils::{random_blocks, random_voxels},
+    voxel_pos, ChunkData,
+  };
+
+  fn chunk_id(i: u128) -> AssetId<Chunk> { AssetId::from(Uuid::from_u128(i)) }
+
+  fn test_chunks() -> Vec<Vec<Option<FullVoxel>>> {
+    let mut rng = fastrand::Rng::with_seed(1);
+    vec![
+      vec![None; CHUNK_VOXEL_COUNT],
+      random_voxels(&mut rng, 0.2, 3),
+      random_blocks(&mut rng, 8, 0.5, 2),
+      random_blocks(&mut rng, 4, 0.3, 1),
+    ]
+  }
+
+  /// The voxel at `pos` of the chunk with the encoded `root`, read through
+  /// the flattened buffers like a shader would.
+  fn gpu_get(dag: &GpuVoxelDag, root: u32, pos: UVec3) -> Option<&FullVoxel> {
+    let mut node = root;
+    let mut size = CHUNK_SIZE as u32;
+    while node != 0 && node & GPU_DAG_BRICK_FLAG == 0 {
+      size /= 2;
+      let child = pos / size % 2;
+      node = dag.nodes[node as usize - 1].children
+        [(child.x | child.y << 1 | child.z << 2) as usize];
+    }
+    if node == 0 {
+      return None;
+    }
+
+    let brick = &dag.bricks[(node & !GPU_DAG_BRICK_FLAG) as usize];
+    let index = brick_index(pos % DAG_BRICK_SIZE as u32);
+    let word = brick.occupancy[index / 32];
+    if word & (1 << (index % 32)) == 0 {
+      return None;
+    }
+    let rank = brick.occupancy[..index / 32]
+      .iter()
+      .map(|word| word.count_ones())
+      .sum::<u32>()
+      + (word & ((1 << (index % 32)) - 1)).count_ones();
+    Some(&dag.attributes[(brick.first_attribute + rank) as usize])
+  }
+
+  #[test]
+  fn point_queries_match_dense_data() {
+    let mut dag = VoxelDag::default();
+    let chunks = test_chunks();
+    for (i, data) in chunks.iter().enumerate() {
+      dag.insert(
+        chunk_id(i as u128),
+        &Chunk::new(ChunkData::Full { data: data.clone() }),
+      );
+    }
+
+    let gpu = dag.to_gpu();
+    for (i, data) in chunks.iter().enumerate() {
+      let id = chunk_id(i as u128);
+      for (j, voxel) in data.iter().enumerate() {
+        assert_eq!(dag.get(id, voxel_pos(j)), voxel.as_ref());
+        assert_eq!(gpu_get(&gpu, gpu.roots[&id], voxel_pos(j)), voxel.as_ref());
+      }
+    }
+    assert_eq!(dag.get(chunk_id(99), UVec3::ZERO), None);
+  }
+
+  #[test]
+  fn shares_identical_chunks() {
+    let data = test_chunks().swap_remove(1);
+    let mut dag = VoxelDag::default();
+    dag.insert(
+      chunk_id(0),
+      &Chunk::new(ChunkData::Full { data: data.clone() }),
+    );
+    let once = dag.stats();
+    dag.insert(chunk_id(1), &Chunk::new(ChunkData::Full { data }));
+    let twice = dag.stats();
+
+    assert_eq!(twice.unique_bricks, once.unique_bricks);
+    assert_eq!(twice.unique_nodes, once.unique_nodes);
+    assert_eq!(twice.total_bricks, 2 * once.total_bricks);
+    assert_eq!(twice.total_nodes, 2 * once.total_nodes);
+  }
+
+  #[test]
+  fn incremental_updates_match_a_rebuild() {
+    let chunks = test_chunks();
+    let mut rng = fastrand::Rng::with_seed(2);
+    let mut dag = VoxelDag::default();
+    let mut present = HashMap::new();
+    for _ in 0..40 {
+      let id = rng.u128(0..6);
+      if rng.u8(0..4) == 0 {
+        dag.remove(chunk_id(id));
+        present.remove(&id);
+      } else {
+        let data = chunks[rng.usize(0..chunks.len())].clone();
+        dag.insert(
+          chunk_id(id),
+          &Chunk::new(ChunkData::Full { data: data.clone() }),
+        );
+        present.insert(id, data);
+      }
+
+      let mut rebuilt = VoxelDag::default();
+      for (id, data) in &present {
+        rebuilt.insert(
+          chunk_id(*id),
+          &Chunk::new(ChunkData::Full { data: data.clone() }),
+        );
+      }
+      let (stats, expected) = (dag.stats(), rebuilt.stats());
+      assert_eq!(stats.chunks, expected.chunks);
+      assert_eq!(stats.unique_bricks, expected.unique_bricks);
+      assert_eq!(stats.unique_nodes, expected.unique_nodes);
+      assert_eq!(stats.total_bricks, expected.total_bricks);
+      assert_eq!(stats.total_nodes, expected.total_nodes);
+      assert_eq!(dag.node_lookup.len(), stats.unique_nodes);
+      assert_eq!(dag.brick_lookup.len(), stats.unique_bricks);
+    }
+
+    for (id, data) in &present {
+      for (i, voxel) in data.iter().enumerate() {
+        assert_eq!(dag.get(chunk_id(*id), voxel_pos(i)), voxel.as_ref());
+      }
+    }
+  }
+}
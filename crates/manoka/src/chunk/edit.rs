@@ -0,0 +1,301 @@
+use std::{
+  fmt,
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+use bevy::prelude::*;
+
+use super::{
+  brickmap::{BRICK_GRID_COUNT, BRICK_GRID_SIZE, BRICK_SIZE},
+  voxel_index, voxel_pos, Chunk, ChunkData, FullVoxel,
+};
+use crate::CHUNK_SIZE;
+
+/// A voxel coordinate that lies outside the chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfBounds(pub IVec3);
+
+impl fmt::Display for OutOfBounds {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "voxel position {} is outside the {}³ chunk",
+      self.0, CHUNK_SIZE
+    )
+  }
+}
+
+impl std::error::Error for OutOfBounds {}
+
+fn check_bounds(pos: UVec3) -> Result<(), OutOfBounds> {
+  check_signed_bounds(pos.as_ivec3())
+}
+
+fn check_signed_bounds(pos: IVec3) -> Result<(), OutOfBounds> {
+  let inside =
+    pos.cmpge(IVec3::ZERO) & pos.cmplt(IVec3::splat(CHUNK_SIZE as i32));
+  match inside.all() {
+    true => Ok(()),
+    false => Err(OutOfBounds(pos)),
+  }
+}
+
+/// Takes the next revision from a counter shared by every chunk, so
+/// revisions never repeat.
+fn next_revision() -> u64 {
+  static NEXT: AtomicU64 = AtomicU64::new(1);
+  NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The revision at which each brick of a chunk was last edited, so the
+/// render world can upload just the bricks edited since it last saw the
+/// chunk.
+///
+/// Every chunk value starts its own lineage, including clones, since a clone
+/// is edited independently of its original. The render world treats a chunk
+/// of a lineage it hasn't seen as entirely changed.
+#[derive(Debug)]
+pub struct BrickRevisions {
+  lineage: u64,
+  latest:  u64,
+  /// The revision of each grid cell's brick, or empty until the first edit.
+  bricks:  Vec<u64>,
+}
+
+impl Default for BrickRevisions {
+  fn default() -> Self {
+    let lineage = next_revision();
+    Self {
+      lineage,
+      latest: lineage,
+      bricks: Vec::new(),
+    }
+  }
+}
+
+impl Clone for BrickRevisions {
+  fn clone(&self) -> Self { Self::default() }
+}
+
+impl BrickRevisions {
+  pub fn lineage(&self) -> u64 { self.lineage }
+
+  /// The revision of the chunk's latest edit.
+  pub fn latest(&self) -> u64 { self.latest }
+
+  /// The grid cells of the bricks edited after `revision`.
+  pub fn edited_since(
+    &self,
+    revision: u64,
+  ) -> impl Iterator<Item = usize> + '_ {
+    self
+      .bricks
+      .iter()
+      .enumerate()
+      .filter(move |(_, edited)| **edited > revision)
+      .map(|(cell, _)| cell)
+  }
+
+  fn touch(&mut self, pos: UVec3) {
+    if self.bricks.is_empty() {
+      self.bricks = vec![self.lineage; BRICK_GRID_COUNT];
+    }
+    let cell = pos / BRICK_SIZE as u32;
+    let grid_size = BRICK_GRID_SIZE as u32;
+    let cell = (cell.z * grid_size + cell.y) * grid_size + cell.x;
+    self.latest = next_revision();
+    self.bricks[cell as usize] = self.latest;
+  }
+}
+
+impl Chunk {
+  /// Returns the voxel at `pos`, or `None` if it is empty.
+  pub fn get(&self, pos: UVec3) -> Result<Option<&FullVoxel>, OutOfBounds> {
+    check_bounds(pos)?;
+    Ok(match &self.data {
+      ChunkData::Full { data } => data[voxel_index(pos)].as_ref(),
+      ChunkData::Palette { data } => data.get(voxel_index(pos)),
+      ChunkData::Octree { data } => data.get(pos),
+      ChunkData::Brickmap { data } => data.get(pos),
+    })
+  }
+
+  /// Places `voxel` at `pos`.
+  pub fn set(
+    &mut self,
+    pos: UVec3,
+    voxel: FullVoxel,
+  ) -> Result<(), OutOfBounds> {
+    check_bounds(pos)?;
+    self.write(pos, Some(voxel));
+    Ok(())
+  }
+
+  /// Empties the voxel at `pos`.
+  pub fn clear(&mut self, pos: UVec3) -> Result<(), OutOfBounds> {
+    check_bounds(pos)?;
+    self.write(pos, None);
+    Ok(())
+  }
+
+  /// Fills the inclusive box `min..=max`. A `voxel` of `None` clears it.
+  pub fn fill_box(
+    &mut self,
+    min: UVec3,
+    max: UVec3,
+    voxel: Option<FullVoxel>,
+  ) -> Result<(), OutOfBounds> {
+    check_bounds(min)?;
+    check_bounds(max)?;
+
+    for z in min.z..=max.z {
+      for y in min.y..=max.y {
+        for x in min.x..=max.x {
+          self.write(UVec3::new(x, y, z), voxel.clone());
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Fills every voxel whose center lies within `radius` of `center`. A
+  /// `voxel` of `None` clears them.
+  ///
+  /// Like the other edits, nothing is written if any of those voxels lies
+  /// outside the chunk.
+  pub fn fill_sphere(
+    &mut self,
+    center: Vec3,
+    radius: f32,
+    voxel: Option<FullVoxel>,
+  ) -> Result<(), OutOfBounds> {
+    // the voxels whose centers can lie within the sphere
+    let min = (center - radius - 0.5).ceil().as_ivec3();
+    let max = (center + radius - 0.5).floor().as_ivec3();
+    if min.cmpgt(max).any() {
+      return Ok(());
+    }
+    check_signed_bounds(min)?;
+    check_signed_bounds(max)?;
+
+    let (min, max) = (min.as_uvec3(), max.as_uvec3());
+    for z in min.z..=max.z {
+      for y in min.y..=max.y {
+        for x in min.x..=max.x {
+          let pos = UVec3::new(x, y, z);
+          if (pos.as_vec3() + 0.5).distance_squared(center) <= radius * radius {
+            self.write(pos, voxel.clone());
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Iterates the position and contents of every occupied voxel.
+  pub fn iter_occupied(
+    &self,
+  ) -> Box<dyn Iterator<Item = (UVec3, &FullVoxel)> + '_> {
+    match &self.data {
+      ChunkData::Full { data } => Box::new(
+        data
+          .iter()
+          .enumerate()
+          .filter_map(|(i, v)| v.as_ref().map(|v| (voxel_pos(i), v))),
+      ),
+      ChunkData::Palette { data } => Box::new(
+        data
+          .iter()
+          .enumerate()
+          .filter_map(|(i, v)| v.map(|v| (voxel_pos(i), v))),
+      ),
+      ChunkData::Octree { data } => Box::new(
+        data
+          .query_region(UVec3::ZERO, UVec3::splat(CHUNK_SIZE as u32 - 1))
+          .into_iter(),
+      ),
+      ChunkData::Brickmap { data } => Box::new(data.iter_occupied()),
+    }
+  }
+
+  /// Writes a voxel whose position has already been bounds-checked.
+  fn write(&mut self, pos: UVec3, voxel: Option<FullVoxel>) {
+    self.revisions.touch(pos);
+    match &mut self.data {
+      ChunkData::Full { data } => data[voxel_index(pos)] = voxel,
+      ChunkData::Palette { data } => data.set(voxel_index(pos), voxel),
+      ChunkData::Octree { data } => data.set(pos, voxel),
+      ChunkData::Brickmap { data } => data.set(pos, voxel),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::CHUNK_VOXEL_COUNT;
+
+  fn voxel() -> FullVoxel { FullVoxel::new(Vec3::Y, 0) }
+
+  #[test]
+  fn rejects_positions_outside_the_chunk() {
+    let size = CHUNK_SIZE as u32;
+    let mut chunk = Chunk::new_empty();
+    let outside = UVec3::new(0, size, 0);
+    assert_eq!(
+      chunk.set(outside, voxel()),
+      Err(OutOfBounds(outside.as_ivec3()))
+    );
+    assert_eq!(chunk.get(outside), Err(OutOfBounds(outside.as_ivec3())));
+    assert!(chunk.fill_box(UVec3::ZERO, outside, Some(voxel())).is_err());
+    assert_eq!(chunk.iter_occupied().count(), 0);
+  }
+
+  #[test]
+  fn rejects_spheres_past_the_chunk() {
+    let half = CHUNK_SIZE as f32 / 2.0;
+    let mut chunk = Chunk::new_empty();
+    assert_eq!(
+      chunk.fill_sphere(Vec3::splat(0.5), 1.0, Some(voxel())),
+      Err(OutOfBounds(IVec3::splat(-1)))
+    );
+    assert!(chunk
+      .fill_sphere(Vec3::splat(half), half + 1.0, Some(voxel()))
+      .is_err());
+    assert_eq!(chunk.iter_occupied().count(), 0);
+
+    // a sphere reaching exactly to the center of the first voxel along x
+    let center = Vec3::new(half, half + 0.5, half + 0.5);
+    assert_eq!(chunk.fill_sphere(center, half - 0.5, Some(voxel())), Ok(()));
+    assert!(chunk
+      .get(UVec3::new(0, half as u32, half as u32))
+      .unwrap()
+      .is_some());
+  }
+
+  #[test]
+  fn edits_agree_across_representations() {
+    let center = Vec3::splat(CHUNK_SIZE as f32 / 2.0);
+    let radius = CHUNK_SIZE as f32 / 4.0;
+    let edit = |chunk: &mut Chunk| {
+      chunk.fill_sphere(center, radius, Some(voxel())).unwrap();
+      chunk
+        .fill_box(UVec3::ZERO, UVec3::splat(3), Some(voxel()))
+        .unwrap();
+      chunk.clear(center.as_uvec3()).unwrap();
+    };
+
+    let mut full = Chunk::new_empty();
+    edit(&mut full);
+    let occupied = full.iter_occupied().count();
+    assert!(occupied > 0 && occupied < CHUNK_VOXEL_COUNT);
+
+    let empty = Chunk::new_empty();
+    for mut chunk in
+      [empty.to_palette(), empty.to_octree(), empty.to_brickmap()]
+    {
+      edit(&mut chunk);
+      assert_eq!(chunk.into_full(), full.into_full());
+    }
+  }
+}
@@ -3,14 +3,24 @@ use bevy_inspector_egui::{
   reflect_inspector::InspectorUi,
 };
 
-use super::Chunk;
+use super::{Chunk, ChunkData};
 
 const CHUNK_INSPECTOR_MESSAGE: &str =
   "I don't really know how to debug it right now, so I made this to avoid \
    crashing because of the default inspector implementation.";
 
-fn debug_chunk_asset(ui: &mut egui::Ui) {
+fn debug_chunk_asset(chunk: &Chunk, ui: &mut egui::Ui) {
   ui.label("You found a Chunk asset!");
+  ui.label(format!(
+    "{} representation, {:.1} KiB",
+    match chunk.data {
+      ChunkData::Full { .. } => "Full",
+      ChunkData::Palette { .. } => "Palette",
+      ChunkData::Octree { .. } => "Octree",
+      ChunkData::Brickmap { .. } => "Brickmap",
+    },
+    chunk.memory_usage() as f32 / 1024.0
+  ));
   ui.weak(CHUNK_INSPECTOR_MESSAGE);
 }
 
@@ -22,7 +32,7 @@ impl InspectorPrimitive for Chunk {
     _id: egui::Id,
     _env: InspectorUi<'_, '_>,
   ) -> bool {
-    debug_chunk_asset(ui);
+    debug_chunk_asset(self, ui);
     false
   }
 
@@ -33,6 +43,6 @@ impl InspectorPrimitive for Chunk {
     _id: egui::Id,
     _env: InspectorUi<'_, '_>,
   ) {
-    debug_chunk_asset(ui);
+    debug_chunk_asset(self, ui);
   }
 }
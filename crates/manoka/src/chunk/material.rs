@@ -0,0 +1,134 @@
+//! The shared table of voxel materials.
+//!
+//! Voxels refer to their material by [`MaterialId`], so redefining a material
+//! changes every voxel using it without touching chunk data. Materials are
+//! interned: adding a material equal to an existing one returns the existing
+//! id.
+
+use std::sync::{Arc, RwLock};
+
+use bevy::{prelude::*, render::render_resource::ShaderType, utils::HashMap};
+
+/// An index into [`VoxelMaterials`].
+pub type MaterialId = u32;
+
+/// The surface properties of a voxel. Colors are linear.
+#[derive(Clone, Debug, PartialEq, Reflect, ShaderType)]
+pub struct VoxelMaterial {
+  pub albedo:    Vec3,
+  pub emissive:  Vec3,
+  pub roughness: f32,
+  pub metallic:  f32,
+  pub opacity:   f32,
+}
+
+impl Default for VoxelMaterial {
+  fn default() -> Self {
+    Self {
+      albedo:    Vec3::ONE,
+      emissive:  Vec3::ZERO,
+      roughness: 1.0,
+      metallic:  0.0,
+      opacity:   1.0,
+    }
+  }
+}
+
+impl VoxelMaterial {
+  /// A plain opaque material of the given linear color, which is what chunks
+  /// from before the material table used.
+  pub fn from_color(albedo: Vec3) -> Self {
+    Self {
+      albedo,
+      ..default()
+    }
+  }
+
+  /// Bitwise identity of the material, for interning.
+  fn bit_key(&self) -> [u32; 9] {
+    let [ar, ag, ab] = self.albedo.to_array().map(f32::to_bits);
+    let [er, eg, eb] = self.emissive.to_array().map(f32::to_bits);
+    [
+      ar,
+      ag,
+      ab,
+      er,
+      eg,
+      eb,
+      self.roughness.to_bits(),
+      self.metallic.to_bits(),
+      self.opacity.to_bits(),
+    ]
+  }
+}
+
+#[derive(Default)]
+struct MaterialTable {
+  materials: Vec<VoxelMaterial>,
+  ids:       HashMap<[u32; 9], MaterialId>,
+  /// Bumped on every change, so the GPU copy knows when to reupload.
+  revision:  u64,
+}
+
+/// Every voxel material in use.
+///
+/// Cloning is cheap and clones share the table, so asset loaders and
+/// generator tasks can resolve materials off the main thread.
+#[derive(Resource, Clone, Default)]
+pub struct VoxelMaterials(Arc<RwLock<MaterialTable>>);
+
+impl VoxelMaterials {
+  /// Returns the id of `material`, adding it to the table if it is new.
+  pub fn intern(&self, material: &VoxelMaterial) -> MaterialId {
+    let key = material.bit_key();
+    if let Some(id) = self.0.read().unwrap().ids.get(&key) {
+      return *id;
+    }
+
+    let mut table = self.0.write().unwrap();
+    // another thread may have added it between the locks
+    if let Some(id) = table.ids.get(&key) {
+      return *id;
+    }
+    let id = table.materials.len() as MaterialId;
+    table.materials.push(material.clone());
+    table.ids.insert(key, id);
+    table.revision += 1;
+    id
+  }
+
+  /// Returns the material with `id`, if there is one.
+  pub fn get(&self, id: MaterialId) -> Option<VoxelMaterial> {
+    self.0.read().unwrap().materials.get(id as usize).cloned()
+  }
+
+  /// Redefines the material with `id`, changing every voxel that uses it.
+  pub fn set(&self, id: MaterialId, material: VoxelMaterial) {
+    let mut table = self.0.write().unwrap();
+    let Some(old) = table.materials.get(id as usize) else {
+      return;
+    };
+    let old_key = old.bit_key();
+    if table.ids.get(&old_key) == Some(&id) {
+      table.ids.remove(&old_key);
+    }
+    table.ids.entry(material.bit_key()).or_insert(id);
+    table.materials[id as usize] = material;
+    table.revision += 1;
+  }
+
+  /// A counter that changes whenever the table does.
+  pub fn revision(&self) -> u64 { self.0.read().unwrap().revision }
+
+  /// A copy of every material, indexed by id.
+  pub fn snapshot(&self) -> Vec<VoxelMaterial> {
+    self.0.read().unwrap().materials.clone()
+  }
+}
+
+/// The material table in the layout of the shader's material buffer.
+#[derive(Default, ShaderType)]
+pub struct GpuVoxelMaterials {
+  #[size(runtime)]
+  pub materials: Vec<VoxelMaterial>,
+}
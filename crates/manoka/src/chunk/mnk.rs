@@ -0,0 +1,873 @@
+//! The native `.mnk` chunk file format.
+//!
+//! A file is a fixed header followed by a run-length encoded payload:
+//!
+//! | bytes | contents                                   |
+//! |-------|--------------------------------------------|
+//! | 4     | magic, `MNK\0`                             |
+//! | 2     | format version                             |
+//! | 1     | representation tag                         |
+//! | 1     | log2 of the chunk size                     |
+//! | 4     | uncompressed payload length                |
+//! | 4     | compressed payload length                  |
+//! | 4     | CRC-32 of the header before it and the     |
+//! |       | compressed payload                         |
+//!
+//! All integers are little-endian. The payload starts with the materials
+//! the chunk uses, which voxels refer to by their index in that list, so a
+//! file doesn't depend on the order of the runtime material table. Voxels
+//! store their normal only if they have one. The rest of the payload depends
+//! on the representation, so chunks load back in the form they were saved
+//! in.
+//!
+//! Files can only be loaded by a build with the same chunk size.
+
+use std::fmt;
+
+use bevy::{
+  asset::{
+    io::{Reader, Writer},
+    saver::{AssetSaver, SavedAsset},
+    AssetLoader, AsyncReadExt, AsyncWriteExt, LoadContext,
+  },
+  prelude::*,
+  utils::{BoxedFuture, HashMap},
+};
+
+use super::{
+  brickmap::{BRICK_GRID_COUNT, BRICK_VOXEL_COUNT, EMPTY_BRICK},
+  material::{MaterialId, VoxelMaterial, VoxelMaterials},
+  octree::OctreeNode,
+  BrickmapData, Chunk, ChunkData, FullVoxel, OctreeData, PaletteData,
+};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+const MNK_MAGIC: &[u8; 4] = b"MNK\0";
+/// The format version this build reads and writes.
+pub const MNK_VERSION: u16 = 4;
+const HEADER_LEN: usize = 20;
+/// The header bytes covered by the checksum, which follows them.
+const CHECKED_HEADER_LEN: usize = 16;
+
+/// More voxels than any representation stores: an octree has fewer than 8/7
+/// nodes per voxel, and a palette one entry more than there are voxels.
+const MAX_STORED_VOXELS: usize = 2 * CHUNK_VOXEL_COUNT;
+/// The largest payload any chunk encodes to, so corrupt lengths are
+/// rejected before allocating. Each material takes 44 bytes, each voxel or
+/// octree node at most 18, and palette indices and brick grid cells at most
+/// 4 bytes per voxel.
+const MAX_PAYLOAD_LEN: usize =
+  16 + MAX_STORED_VOXELS * (44 + 18) + CHUNK_VOXEL_COUNT * 4;
+
+const TAG_FULL: u8 = 0;
+const TAG_PALETTE: u8 = 1;
+const TAG_OCTREE: u8 = 2;
+const TAG_BRICKMAP: u8 = 3;
+
+#[derive(Debug)]
+pub enum MnkError {
+  Io(std::io::Error),
+  /// The file does not start with the `.mnk` magic.
+  InvalidMagic,
+  /// The file has a format version this build can't read.
+  UnsupportedVersion(u16),
+  /// The header names a representation this build doesn't know.
+  UnknownRepresentation(u8),
+  /// The file was written for chunks of a different size, in voxels.
+  ChunkSizeMismatch(usize),
+  /// The payload does not match the checksum in the header.
+  ChecksumMismatch {
+    expected: u32,
+    actual:   u32,
+  },
+  /// The file is truncated or its payload is inconsistent.
+  Corrupt(&'static str),
+}
+
+impl fmt::Display for MnkError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MnkError::Io(e) => write!(f, "failed to read mnk file: {e}"),
+      MnkError::InvalidMagic => write!(f, "not a mnk file"),
+      MnkError::UnsupportedVersion(v) => write!(
+        f,
+        "mnk file has version {v}, but only {MNK_VERSION} is supported"
+      ),
+      MnkError::UnknownRepresentation(tag) => {
+        write!(f, "mnk file has unknown representation tag {tag}")
+      }
+      MnkError::ChunkSizeMismatch(size) => write!(
+        f,
+        "mnk file has {size}³ chunks, but this build uses {CHUNK_SIZE}³"
+      ),
+      MnkError::ChecksumMismatch { expected, actual } => write!(
+        f,
+        "mnk file checksum mismatch: expected {expected:#010x}, got \
+         {actual:#010x}"
+      ),
+      MnkError::Corrupt(what) => write!(f, "corrupt mnk file: {what}"),
+    }
+  }
+}
+
+impl std::error::Error for MnkError {}
+
+impl From<std::io::Error> for MnkError {
+  fn from(value: std::io::Error) -> Self { MnkError::Io(value) }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+  let mut table = [0; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 != 0 {
+        (crc >> 1) ^ 0xedb8_8320
+      } else {
+        crc >> 1
+      };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+};
+
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+  !bytes.into_iter().fold(!0, |crc, byte| {
+    CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+  })
+}
+
+/// Run-length encodes `bytes`. A control byte `n < 128` is followed by
+/// `n + 1` literal bytes, and `n >= 128` by one byte repeated `n - 126`
+/// times.
+fn rle_compress(bytes: &[u8]) -> Vec<u8> {
+  let mut out = Vec::new();
+  let mut literals = Vec::new();
+  let mut i = 0;
+  while i < bytes.len() {
+    let run = bytes[i..]
+      .iter()
+      .take(129)
+      .take_while(|b| **b == bytes[i])
+      .count();
+
+    if run >= 2 {
+      for literal in literals.chunks(128) {
+        out.push(literal.len() as u8 - 1);
+        out.extend_from_slice(literal);
+      }
+      literals.clear();
+      out.push((run + 126) as u8);
+      out.push(bytes[i]);
+    } else {
+      literals.push(bytes[i]);
+    }
+    i += run;
+  }
+  for literal in literals.chunks(128) {
+    out.push(literal.len() as u8 - 1);
+    out.extend_from_slice(literal);
+  }
+  out
+}
+
+fn rle_decompress(bytes: &[u8], len: usize) -> Result<Vec<u8>, MnkError> {
+  let mut out = Vec::with_capacity(len);
+  let mut i = 0;
+  while i < bytes.len() {
+    let control = bytes[i] as usize;
+    i += 1;
+    if control < 128 {
+      let literal = bytes
+        .get(i..i + control + 1)
+        .ok_or(MnkError::Corrupt("truncated literal run"))?;
+      out.extend_from_slice(literal);
+      i += control + 1;
+    } else {
+      let byte = *bytes
+        .get(i)
+        .ok_or(MnkError::Corrupt("truncated repeat run"))?;
+      out.resize(out.len() + control - 126, byte);
+      i += 1;
+    }
+    if out.len() > len {
+      return Err(MnkError::Corrupt("payload longer than declared"));
+    }
+  }
+  if out.len() != len {
+    return Err(MnkError::Corrupt("payload shorter than declared"));
+  }
+  Ok(out)
+}
+
+struct PayloadReader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], MnkError> {
+    if self.bytes.len() < len {
+      return Err(MnkError::Corrupt("payload ended early"));
+    }
+    let (head, tail) = self.bytes.split_at(len);
+    self.bytes = tail;
+    Ok(head)
+  }
+
+  fn u8(&mut self) -> Result<u8, MnkError> { Ok(self.bytes(1)?[0]) }
+
+  fn u32(&mut self) -> Result<u32, MnkError> {
+    Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  fn f32(&mut self) -> Result<f32, MnkError> {
+    Ok(f32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  fn vec3(&mut self) -> Result<Vec3, MnkError> {
+    Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+  }
+
+  fn material(&mut self) -> Result<VoxelMaterial, MnkError> {
+    Ok(VoxelMaterial {
+      albedo:    self.vec3()?,
+      emissive:  self.vec3()?,
+      roughness: self.f32()?,
+      metallic:  self.f32()?,
+      opacity:   self.f32()?,
+    })
+  }
+}
+
+/// The payload's material list, already interned into the runtime table.
+struct PayloadMaterials(Vec<MaterialId>);
+
+impl PayloadMaterials {
+  fn read(&self, reader: &mut PayloadReader) -> Result<MaterialId, MnkError> {
+    self
+      .0
+      .get(reader.u32()? as usize)
+      .copied()
+      .ok_or(MnkError::Corrupt("material index out of range"))
+  }
+
+  fn voxel(&self, reader: &mut PayloadReader) -> Result<FullVoxel, MnkError> {
+    let normal = match reader.u8()? {
+      0 => None,
+      1 => Some(reader.vec3()?),
+      _ => return Err(MnkError::Corrupt("invalid normal flag")),
+    };
+    Ok(FullVoxel {
+      normal,
+      material: self.read(reader)?,
+    })
+  }
+}
+
+/// The voxels a chunk's representation stores, including unused palette
+/// entries and octree leaves.
+fn stored_voxels(chunk: &Chunk) -> Box<dyn Iterator<Item = &FullVoxel> + '_> {
+  match &chunk.data {
+    ChunkData::Full { data } => Box::new(data.iter().flatten()),
+    ChunkData::Palette { data } => Box::new(data.palette.iter()),
+    ChunkData::Octree { data } => Box::new(
+      std::iter::once(&data.root)
+        .chain(data.nodes.iter())
+        .filter_map(|node| match node {
+          OctreeNode::Leaf(voxel) => Some(voxel),
+          _ => None,
+        }),
+    ),
+    ChunkData::Brickmap { data } => {
+      Box::new(data.bricks.iter().flatten().flatten())
+    }
+  }
+}
+
+/// Writes the materials `chunk` uses, returning the index each material id
+/// is written at.
+fn write_materials(
+  out: &mut Vec<u8>,
+  chunk: &Chunk,
+  materials: &VoxelMaterials,
+) -> HashMap<MaterialId, u32> {
+  let mut used = Vec::new();
+  let mut local = HashMap::new();
+  for voxel in stored_voxels(chunk) {
+    local.entry(voxel.material).or_insert_with(|| {
+      used.push(voxel.material);
+      used.len() as u32 - 1
+    });
+  }
+
+  out.extend_from_slice(&(used.len() as u32).to_le_bytes());
+  for id in used {
+    let material = materials.get(id).unwrap_or_default();
+    let floats = material
+      .albedo
+      .to_array()
+      .into_iter()
+      .chain(material.emissive.to_array())
+      .chain([material.roughness, material.metallic, material.opacity]);
+    for c in floats {
+      out.extend_from_slice(&c.to_le_bytes());
+    }
+  }
+  local
+}
+
+fn write_voxel(
+  out: &mut Vec<u8>,
+  voxel: &FullVoxel,
+  local: &HashMap<MaterialId, u32>,
+) {
+  match voxel.normal {
+    Some(normal) => {
+      out.push(1);
+      for c in normal.to_array() {
+        out.extend_from_slice(&c.to_le_bytes());
+      }
+    }
+    None => out.push(0),
+  }
+  out.extend_from_slice(&local[&voxel.material].to_le_bytes());
+}
+
+/// Writes a voxel that may be empty, prefixed by whether it is occupied.
+fn write_optional_voxel(
+  out: &mut Vec<u8>,
+  voxel: &Option<FullVoxel>,
+  local: &HashMap<MaterialId, u32>,
+) {
+  match voxel {
+    Some(voxel) => {
+      out.push(1);
+      write_voxel(out, voxel, local);
+    }
+    None => out.push(0),
+  }
+}
+
+fn read_optional_voxel(
+  reader: &mut PayloadReader<'_>,
+  materials: &PayloadMaterials,
+) -> Result<Option<FullVoxel>, MnkError> {
+  match reader.u8()? {
+    0 => Ok(None),
+    1 => Ok(Some(materials.voxel(reader)?)),
+    _ => Err(MnkError::Corrupt("invalid voxel tag")),
+  }
+}
+
+fn write_octree_node(
+  out: &mut Vec<u8>,
+  node: &OctreeNode,
+  local: &HashMap<MaterialId, u32>,
+) {
+  match node {
+    OctreeNode::Empty => out.push(0),
+    OctreeNode::Leaf(voxel) => {
+      out.push(1);
+      write_voxel(out, voxel, local);
+    }
+    OctreeNode::Branch(first_child) => {
+      out.push(2);
+      out.extend_from_slice(&first_child.to_le_bytes());
+    }
+  }
+}
+
+fn read_octree_node(
+  reader: &mut PayloadReader<'_>,
+  materials: &PayloadMaterials,
+) -> Result<OctreeNode, MnkError> {
+  match reader.u8()? {
+    0 => Ok(OctreeNode::Empty),
+    1 => Ok(OctreeNode::Leaf(materials.voxel(reader)?)),
+    2 => Ok(OctreeNode::Branch(reader.u32()?)),
+    _ => Err(MnkError::Corrupt("unknown octree node")),
+  }
+}
+
+/// Checks that every branch points at its own block of eight nodes inside
+/// the node arena and that the tree is no deeper than a chunk allows, so it
+/// can be traversed safely. A block referenced twice would alias subtrees or
+/// form a cycle.
+fn validate_octree(
+  nodes: &[OctreeNode],
+  root: &OctreeNode,
+) -> Result<(), MnkError> {
+  let mut visited = vec![false; nodes.len() / 8];
+  validate_octree_node(nodes, root, CHUNK_SIZE, &mut visited)
+}
+
+fn validate_octree_node(
+  nodes: &[OctreeNode],
+  node: &OctreeNode,
+  size: usize,
+  visited: &mut [bool],
+) -> Result<(), MnkError> {
+  let OctreeNode::Branch(first_child) = node else {
+    return Ok(());
+  };
+  let first_child = *first_child as usize;
+  // blocks are allocated eight nodes at a time, so they never overlap
+  let block = first_child
+    .is_multiple_of(8)
+    .then(|| visited.get_mut(first_child / 8))
+    .flatten();
+  match block {
+    Some(visited) if size > 1 && !*visited => *visited = true,
+    _ => return Err(MnkError::Corrupt("invalid octree branch")),
+  }
+  for child in &nodes[first_child..first_child + 8] {
+    validate_octree_node(nodes, child, size / 2, visited)?;
+  }
+  Ok(())
+}
+
+fn encode_payload(chunk: &Chunk, materials: &VoxelMaterials) -> (u8, Vec<u8>) {
+  // node blocks and palette entries freed by edits aren't worth saving
+  let compacted = match &chunk.data {
+    ChunkData::Octree { data } if !data.free.is_empty() => {
+      Some(ChunkData::Octree {
+        data: data.compacted(),
+      })
+    }
+    ChunkData::Palette { data } if data.has_free_entries() => {
+      Some(ChunkData::Palette {
+        data: data.compacted(),
+      })
+    }
+    _ => None,
+  };
+  if let Some(data) = compacted {
+    return encode_payload(&Chunk::new(data), materials);
+  }
+
+  let mut out = Vec::new();
+  let local = write_materials(&mut out, chunk, materials);
+  match &chunk.data {
+    ChunkData::Full { data } => {
+      for voxel in data {
+        write_optional_voxel(&mut out, voxel, &local);
+      }
+      (TAG_FULL, out)
+    }
+    ChunkData::Palette { data } => {
+      out.extend_from_slice(&(data.palette.len() as u32).to_le_bytes());
+      for voxel in data.palette.iter() {
+        write_voxel(&mut out, voxel, &local);
+      }
+      out.extend_from_slice(&data.bits.to_le_bytes());
+      for word in data.indices.iter() {
+        out.extend_from_slice(&word.to_le_bytes());
+      }
+      (TAG_PALETTE, out)
+    }
+    ChunkData::Octree { data } => {
+      write_octree_node(&mut out, &data.root, &local);
+      out.extend_from_slice(&(data.nodes.len() as u32).to_le_bytes());
+      for node in data.nodes.iter() {
+        write_octree_node(&mut out, node, &local);
+      }
+      (TAG_OCTREE, out)
+    }
+    ChunkData::Brickmap { data } => {
+      for brick in data.grid.iter() {
+        out.extend_from_slice(&brick.to_le_bytes());
+      }
+      out.extend_from_slice(&(data.bricks.len() as u32).to_le_bytes());
+      for voxel in data.bricks.iter().flatten() {
+        write_optional_voxel(&mut out, voxel, &local);
+      }
+      (TAG_BRICKMAP, out)
+    }
+  }
+}
+
+fn decode_payload(
+  tag: u8,
+  payload: &[u8],
+  table: &VoxelMaterials,
+) -> Result<Chunk, MnkError> {
+  let mut reader = PayloadReader { bytes: payload };
+  let len = reader.u32()? as usize;
+  let materials = PayloadMaterials(
+    (0..len)
+      .map(|_| Ok(table.intern(&reader.material()?)))
+      .collect::<Result<_, MnkError>>()?,
+  );
+
+  let chunk = match tag {
+    TAG_FULL => {
+      let data = (0..CHUNK_VOXEL_COUNT)
+        .map(|_| read_optional_voxel(&mut reader, &materials))
+        .collect::<Result<_, _>>()?;
+      ChunkData::Full { data }
+    }
+    TAG_PALETTE => {
+      let len = reader.u32()? as usize;
+      let palette = (0..len)
+        .map(|_| materials.voxel(&mut reader))
+        .collect::<Result<Vec<_>, _>>()?;
+      let bits = reader.u32()?;
+      if ![1, 2, 4, 8, 16, 32].contains(&bits) {
+        return Err(MnkError::Corrupt("invalid palette index width"));
+      }
+      let words = CHUNK_VOXEL_COUNT.div_ceil(32 / bits as usize);
+      let indices = (0..words)
+        .map(|_| reader.u32())
+        .collect::<Result<Vec<_>, _>>()?;
+      let data = PaletteData::from_packed(palette, bits, indices)
+        .ok_or(MnkError::Corrupt("palette index out of range"))?;
+      ChunkData::Palette { data }
+    }
+    TAG_OCTREE => {
+      let root = read_octree_node(&mut reader, &materials)?;
+      let len = reader.u32()? as usize;
+      let nodes = (0..len)
+        .map(|_| read_octree_node(&mut reader, &materials))
+        .collect::<Result<Vec<_>, _>>()?;
+      validate_octree(&nodes, &root)?;
+      ChunkData::Octree {
+        data: OctreeData {
+          root,
+          nodes,
+          free: Vec::new(),
+        },
+      }
+    }
+    TAG_BRICKMAP => {
+      let grid = (0..BRICK_GRID_COUNT)
+        .map(|_| reader.u32())
+        .collect::<Result<Vec<_>, _>>()?;
+      let len = reader.u32()? as usize;
+      if len > BRICK_GRID_COUNT {
+        return Err(MnkError::Corrupt("more bricks than grid cells"));
+      }
+      // every brick must belong to exactly one grid cell
+      let mut cells = vec![EMPTY_BRICK; len];
+      for (cell, &brick) in grid.iter().enumerate() {
+        if brick == EMPTY_BRICK {
+          continue;
+        }
+        match cells.get_mut(brick as usize) {
+          Some(owner) if *owner == EMPTY_BRICK => *owner = cell as u32,
+          _ => return Err(MnkError::Corrupt("invalid brick reference")),
+        }
+      }
+      if cells.contains(&EMPTY_BRICK) {
+        return Err(MnkError::Corrupt("unreferenced brick"));
+      }
+      let bricks = (0..len)
+        .map(|_| {
+          (0..BRICK_VOXEL_COUNT)
+            .map(|_| read_optional_voxel(&mut reader, &materials))
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+      ChunkData::Brickmap {
+        data: BrickmapData {
+          grid,
+          bricks,
+          cells,
+        },
+      }
+    }
+    tag => return Err(MnkError::UnknownRepresentation(tag)),
+  };
+
+  if !reader.bytes.is_empty() {
+    return Err(MnkError::Corrupt("trailing payload bytes"));
+  }
+  Ok(Chunk::new(chunk))
+}
+
+/// Serializes a chunk to the `.mnk` format, resolving its material ids in
+/// `materials`.
+pub fn encode_chunk(chunk: &Chunk, materials: &VoxelMaterials) -> Vec<u8> {
+  let (tag, payload) = encode_payload(chunk, materials);
+  let compressed = rle_compress(&payload);
+
+  let mut out = Vec::with_capacity(HEADER_LEN + compressed.len());
+  out.extend_from_slice(MNK_MAGIC);
+  out.extend_from_slice(&MNK_VERSION.to_le_bytes());
+  out.push(tag);
+  out.push(CHUNK_SIZE.trailing_zeros() as u8);
+  out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+  out.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+  let checksum = crc32(out.iter().chain(compressed.iter()));
+  out.extend_from_slice(&checksum.to_le_bytes());
+  out.extend_from_slice(&compressed);
+  out
+}
+
+/// Deserializes a chunk from the `.mnk` format, validating its header and
+/// checksum. The chunk's materials are added to `materials`.
+pub fn decode_chunk(
+  bytes: &[u8],
+  materials: &VoxelMaterials,
+) -> Result<Chunk, MnkError> {
+  if bytes.len() < HEADER_LEN {
+    return Err(MnkError::Corrupt("truncated header"));
+  }
+  let (header, body) = bytes.split_at(HEADER_LEN);
+  if &header[0..4] != MNK_MAGIC {
+    return Err(MnkError::InvalidMagic);
+  }
+  let read_u32 = |at: usize| {
+    u32::from_le_bytes(header[at..at + 4].try_into().unwrap()) as usize
+  };
+
+  let version = u16::from_le_bytes([header[4], header[5]]);
+  if version != MNK_VERSION {
+    return Err(MnkError::UnsupportedVersion(version));
+  }
+  let tag = header[6];
+  let chunk_size = 1_usize.checked_shl(header[7] as u32).unwrap_or(0);
+  if chunk_size != CHUNK_SIZE {
+    return Err(MnkError::ChunkSizeMismatch(chunk_size));
+  }
+  let payload_len = read_u32(8);
+  let compressed_len = read_u32(12);
+  let expected = read_u32(16) as u32;
+
+  if payload_len > MAX_PAYLOAD_LEN {
+    return Err(MnkError::Corrupt("payload longer than any chunk"));
+  }
+  if body.len() != compressed_len {
+    return Err(MnkError::Corrupt("payload length does not match header"));
+  }
+  let actual = crc32(header[..CHECKED_HEADER_LEN].iter().chain(body));
+  if actual != expected {
+    return Err(MnkError::ChecksumMismatch { expected, actual });
+  }
+
+  decode_payload(tag, &rle_decompress(body, payload_len)?, materials)
+}
+
+pub struct MnkLoader {
+  materials: VoxelMaterials,
+}
+
+impl FromWorld for MnkLoader {
+  fn from_world(world: &mut World) -> Self {
+    Self {
+      materials: world.resource::<VoxelMaterials>().clone(),
+    }
+  }
+}
+
+impl AssetLoader for MnkLoader {
+  type Asset = Chunk;
+  type Settings = ();
+  type Error = MnkError;
+
+  fn load<'a>(
+    &'a self,
+    reader: &'a mut Reader,
+    _settings: &'a (),
+    _load_context: &'a mut LoadContext,
+  ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+    Box::pin(async move {
+      let mut bytes = Vec::new();
+      reader.read_to_end(&mut bytes).await?;
+      decode_chunk(&bytes, &self.materials)
+    })
+  }
+
+  fn extensions(&self) -> &[&str] { &["mnk"] }
+}
+
+/// Writes chunks with [`encode_chunk`], so processing `.mnk` assets brings
+/// them up to the current format.
+pub struct MnkSaver {
+  materials: VoxelMaterials,
+}
+
+impl FromWorld for MnkSaver {
+  fn from_world(world: &mut World) -> Self {
+    Self {
+      materials: world.resource::<VoxelMaterials>().clone(),
+    }
+  }
+}
+
+impl AssetSaver for MnkSaver {
+  type Asset = Chunk;
+  type Settings = ();
+  type OutputLoader = MnkLoader;
+  type Error = MnkError;
+
+  fn save<'a>(
+    &'a self,
+    writer: &'a mut Writer,
+    asset: SavedAsset<'a, Self::Asset>,
+    _settings: &'a (),
+  ) -> BoxedFuture<'a, Result<(), Self::Error>> {
+    Box::pin(async move {
+      writer
+        .write_all(&encode_chunk(&asset, &self.materials))
+        .await?;
+      Ok(())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::{
+    asset::LoadedAsset,
+    tasks::{block_on, futures_lite::io::Cursor},
+  };
+
+  use super::*;
+  use crate::chunk::test_utils::random_blocks;
+
+  fn encoded_chunk(materials: &VoxelMaterials) -> (Chunk, Vec<u8>) {
+    let mut rng = fastrand::Rng::with_seed(6);
+    let mut data = random_blocks(&mut rng, 4, 0.3, 3);
+    for voxel in data.iter_mut().flatten() {
+      voxel.material =
+        materials.intern(&VoxelMaterial::from_color(Vec3::splat(0.5)));
+    }
+    let chunk = Chunk::new(ChunkData::Full { data });
+    let bytes = encode_chunk(&chunk, materials);
+    (chunk, bytes)
+  }
+
+  #[test]
+  fn round_trips_every_representation() {
+    let materials = VoxelMaterials::default();
+    let (full, _) = encoded_chunk(&materials);
+    let mut stripped = full.clone();
+    stripped.strip_normals();
+    for full in [full, stripped] {
+      for chunk in [
+        full.to_full(),
+        full.to_palette(),
+        full.to_octree(),
+        full.to_brickmap(),
+      ] {
+        let bytes = encode_chunk(&chunk, &materials);
+        let decoded = decode_chunk(&bytes, &materials).unwrap();
+        assert_eq!(
+          std::mem::discriminant(&decoded.data),
+          std::mem::discriminant(&chunk.data)
+        );
+        assert_eq!(decoded.into_full(), full.into_full());
+      }
+    }
+  }
+
+  #[test]
+  fn saver_writes_what_the_loader_reads() {
+    let materials = VoxelMaterials::default();
+    let (chunk, bytes) = encoded_chunk(&materials);
+    let saver = MnkSaver {
+      materials: materials.clone(),
+    };
+
+    let loaded = LoadedAsset::from(chunk).into();
+    let asset = SavedAsset::from_loaded(&loaded).unwrap();
+    let mut writer = Cursor::new(Vec::new());
+    block_on(saver.save(&mut writer, asset, &())).unwrap();
+    assert_eq!(writer.into_inner(), bytes);
+  }
+
+  #[test]
+  fn checksums_the_header() {
+    let materials = VoxelMaterials::default();
+    let (_, bytes) = encoded_chunk(&materials);
+
+    // a different representation tag would otherwise decode as garbage
+    let mut tagged = bytes.clone();
+    tagged[6] = TAG_PALETTE;
+    assert!(matches!(
+      decode_chunk(&tagged, &materials),
+      Err(MnkError::ChecksumMismatch { .. })
+    ));
+
+    let mut lengthened = bytes;
+    lengthened[8] ^= 1;
+    assert!(matches!(
+      decode_chunk(&lengthened, &materials),
+      Err(MnkError::ChecksumMismatch { .. })
+    ));
+  }
+
+  #[test]
+  fn rejects_unknown_versions() {
+    let materials = VoxelMaterials::default();
+    let (_, bytes) = encoded_chunk(&materials);
+    for version in [0, MNK_VERSION - 1, MNK_VERSION + 1] {
+      let mut versioned = bytes.clone();
+      versioned[4..6].copy_from_slice(&version.to_le_bytes());
+      assert!(matches!(
+        decode_chunk(&versioned, &materials),
+        Err(MnkError::UnsupportedVersion(v)) if v == version
+      ));
+    }
+  }
+
+  #[test]
+  fn rejects_shared_octree_blocks() {
+    let materials = VoxelMaterials::default();
+    let leaf = OctreeNode::Leaf(FullVoxel::new(Vec3::Y, 0));
+    let mut aliased = vec![OctreeNode::Empty; 8];
+    aliased[0] = OctreeNode::Branch(8);
+    aliased[1] = OctreeNode::Branch(8);
+    aliased.extend(std::iter::repeat_n(leaf, 8));
+    let mut cyclic = vec![OctreeNode::Empty; 8];
+    cyclic[0] = OctreeNode::Branch(0);
+
+    for nodes in [aliased, cyclic] {
+      let chunk = Chunk::new(ChunkData::Octree {
+        data: OctreeData {
+          root: OctreeNode::Branch(0),
+          nodes,
+          free: Vec::new(),
+        },
+      });
+      assert!(matches!(
+        decode_chunk(&encode_chunk(&chunk, &materials), &materials),
+        Err(MnkError::Corrupt("invalid octree branch"))
+      ));
+    }
+  }
+
+  #[test]
+  fn rejects_oversized_payload_lengths() {
+    let materials = VoxelMaterials::default();
+    let (_, mut bytes) = encoded_chunk(&materials);
+    bytes[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(
+      decode_chunk(&bytes, &materials),
+      Err(MnkError::Corrupt("payload longer than any chunk"))
+    ));
+  }
+
+  #[test]
+  fn rejects_other_chunk_sizes() {
+    let materials = VoxelMaterials::default();
+    let (_, bytes) = encoded_chunk(&materials);
+    assert_eq!(bytes[7] as u32, CHUNK_SIZE.trailing_zeros());
+    for size in [16_usize, 32, 64, 128] {
+      if size == CHUNK_SIZE {
+        continue;
+      }
+      let mut resized = bytes.clone();
+      resized[7] = size.trailing_zeros() as u8;
+      assert!(matches!(
+        decode_chunk(&resized, &materials),
+        Err(MnkError::ChunkSizeMismatch(s)) if s == size
+      ));
+    }
+  }
+}
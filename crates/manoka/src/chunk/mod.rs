@@ -1,139 +1,283 @@
+pub mod brickmap;
+mod dag;
+pub mod edit;
 mod inspector;
+pub mod material;
+pub mod mnk;
+pub mod normals;
+pub mod occupancy;
+mod octree;
+mod palette;
+pub mod region;
+pub mod render;
+#[cfg(test)]
+pub mod test_utils;
+pub mod vox;
+
 use bevy::{
-  asset::ReflectAsset,
-  ecs::system::{lifetimeless::SRes, SystemParamItem},
+  asset::{processor::LoadAndSave, ReflectAsset},
   prelude::*,
-  render::{
-    render_asset::{
-      PrepareAssetError, RenderAsset, RenderAssetPlugin, RenderAssetUsages,
-    },
-    render_resource::{ShaderType, StorageBuffer},
-    renderer::{RenderDevice, RenderQueue},
-    Extract, Render, RenderApp, RenderSet,
-  },
+  render::{render_resource::ShaderType, Extract, RenderApp},
 };
 use bevy_inspector_egui::inspector_egui_impls::InspectorEguiImpl;
+use zerocopy::FromZeroes;
 
-use crate::CHUNK_VOXEL_COUNT;
+pub use self::{
+  brickmap::BrickmapData, dag::VoxelDag, edit::BrickRevisions,
+  octree::OctreeData, palette::PaletteData,
+};
+use self::{
+  material::{MaterialId, VoxelMaterial, VoxelMaterials},
+  mnk::{MnkLoader, MnkSaver},
+  normals::NormalMode,
+  occupancy::OCCUPANCY_MIP_WORDS,
+  render::ChunkRenderPlugin,
+  vox::{VoxLoader, VoxScene},
+};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
 
-#[derive(Clone, Debug, Reflect, ShaderType)]
+#[derive(Clone, Debug, PartialEq, Reflect)]
 pub struct FullVoxel {
-  normal: Vec3,
-  color:  Vec3,
+  /// The voxel's stored normal, or `None` if shaders derive it, see
+  /// [`NormalMode`].
+  pub normal:   Option<Vec3>,
+  /// The voxel's entry in [`VoxelMaterials`].
+  pub material: MaterialId,
+}
+
+/// Bitwise identity of a voxel, see [`FullVoxel::bit_key`].
+type VoxelKey = (Option<[u32; 3]>, MaterialId);
+
+impl FullVoxel {
+  pub fn new(normal: Vec3, material: MaterialId) -> Self {
+    Self {
+      normal: Some(normal),
+      material,
+    }
+  }
+
+  /// A voxel without a stored normal.
+  pub fn without_normal(material: MaterialId) -> Self {
+    Self {
+      normal: None,
+      material,
+    }
+  }
+
+  /// Bitwise identity of the voxel, for hashing and deduplication.
+  fn bit_key(&self) -> VoxelKey {
+    let normal = self.normal.map(|n| n.to_array().map(f32::to_bits));
+    (normal, self.material)
+  }
 }
 
 #[derive(Clone, Debug, Asset, Reflect)]
 #[reflect(Asset)]
-pub enum Chunk {
+pub struct Chunk {
+  /// Private, so every write either records its bricks in `revisions` or
+  /// goes through [`Chunk::data_mut`].
+  data:      ChunkData,
+  /// When each brick was last edited, see [`BrickRevisions`].
+  #[reflect(ignore)]
+  revisions: BrickRevisions,
+}
+
+/// The voxels of a chunk, in one of several representations.
+#[derive(Clone, Debug, Reflect)]
+pub enum ChunkData {
   Full { data: Vec<Option<FullVoxel>> },
+  Palette { data: PaletteData },
+  Octree { data: OctreeData },
+  Brickmap { data: BrickmapData },
 }
 
-#[derive(Clone, Debug, ShaderType)]
-pub struct GpuChunkOccupancy {
-  pub occupancy: [u32; CHUNK_VOXEL_COUNT / 32],
+/// The index of the voxel at `pos` in dense chunk data.
+pub fn voxel_index(pos: UVec3) -> usize {
+  let (x, y, z) = (pos.x as usize, pos.y as usize, pos.z as usize);
+  z * CHUNK_SIZE * CHUNK_SIZE + y * CHUNK_SIZE + x
 }
 
-#[derive(Clone, Debug, ShaderType)]
-pub struct GpuChunkAttributes {
-  #[size(runtime)]
-  attributes: Vec<FullVoxel>,
+/// The position of the voxel at `index` in dense chunk data.
+pub fn voxel_pos(index: usize) -> UVec3 {
+  UVec3::new(
+    (index % CHUNK_SIZE) as u32,
+    (index / CHUNK_SIZE % CHUNK_SIZE) as u32,
+    (index / (CHUNK_SIZE * CHUNK_SIZE)) as u32,
+  )
+}
+
+#[derive(Clone, Debug, PartialEq, ShaderType, FromZeroes)]
+pub struct GpuChunkOccupancy {
+  pub occupancy: [u32; CHUNK_VOXEL_COUNT / 32],
+  /// The coarser levels of the occupancy, see [`occupancy`].
+  pub mips:      [u32; OCCUPANCY_MIP_WORDS],
 }
 
 impl Chunk {
+  pub fn new(data: ChunkData) -> Self {
+    Self {
+      data,
+      revisions: BrickRevisions::default(),
+    }
+  }
+
+  pub fn revisions(&self) -> &BrickRevisions { &self.revisions }
+
+  pub fn data(&self) -> &ChunkData { &self.data }
+
+  /// The chunk's voxels, for changes [`Chunk::set`] can't express. As the
+  /// edited bricks are unknown, the chunk starts a new lineage of
+  /// [`BrickRevisions`], and the render world uploads it whole.
+  pub fn data_mut(&mut self) -> &mut ChunkData {
+    self.revisions = BrickRevisions::default();
+    &mut self.data
+  }
+
   fn into_full(&self) -> Vec<Option<FullVoxel>> {
-    match self {
-      Self::Full { data } => data.clone(),
+    match &self.data {
+      ChunkData::Full { data } => data.clone(),
+      ChunkData::Palette { data } => data.to_full(),
+      ChunkData::Octree { data } => data.to_full(),
+      ChunkData::Brickmap { data } => data.to_full(),
     }
   }
 
-  fn prepare_occupancy(&self) -> GpuChunkOccupancy {
-    let data = self.into_full();
-    let occupancy_map = data.iter().map(|v| v.is_some()).collect::<Vec<_>>();
+  /// Converts the chunk to the dense representation.
+  pub fn to_full(&self) -> Self {
+    Self::new(ChunkData::Full {
+      data: self.into_full(),
+    })
+  }
 
-    // transform array of bools into u32s
-    let mut u32_values: [u32; CHUNK_VOXEL_COUNT / 32] =
-      [0; CHUNK_VOXEL_COUNT / 32];
-    for (i, chunk) in occupancy_map.chunks(32).enumerate() {
-      let mut u32_value = 0;
-      for (j, &bit) in chunk.iter().enumerate() {
-        if bit {
-          u32_value |= 1 << j;
-        }
+  /// Converts the chunk to the palette-compressed representation.
+  pub fn to_palette(&self) -> Self {
+    match &self.data {
+      ChunkData::Full { data } => Self::new(ChunkData::Palette {
+        data: PaletteData::from_full(data),
+      }),
+      ChunkData::Palette { .. } => self.clone(),
+      ChunkData::Octree { .. } | ChunkData::Brickmap { .. } => {
+        Self::new(ChunkData::Palette {
+          data: PaletteData::from_full(&self.into_full()),
+        })
       }
-      u32_values[i] = u32_value;
     }
+  }
 
-    GpuChunkOccupancy {
-      occupancy: u32_values,
+  /// Converts the chunk to the sparse voxel octree representation.
+  pub fn to_octree(&self) -> Self {
+    match &self.data {
+      ChunkData::Full { data } => Self::new(ChunkData::Octree {
+        data: OctreeData::from_full(data),
+      }),
+      ChunkData::Palette { .. } | ChunkData::Brickmap { .. } => {
+        Self::new(ChunkData::Octree {
+          data: OctreeData::from_full(&self.into_full()),
+        })
+      }
+      ChunkData::Octree { .. } => self.clone(),
     }
   }
 
-  fn prepare_attributes(&self) -> GpuChunkAttributes {
-    let data = self.into_full();
-    GpuChunkAttributes {
-      attributes: data.into_iter().flatten().collect(),
+  /// Converts the chunk to the two-level brickmap representation.
+  pub fn to_brickmap(&self) -> Self {
+    match &self.data {
+      ChunkData::Full { data } => Self::new(ChunkData::Brickmap {
+        data: BrickmapData::from_full(data),
+      }),
+      ChunkData::Palette { .. } | ChunkData::Octree { .. } => {
+        Self::new(ChunkData::Brickmap {
+          data: BrickmapData::from_full(&self.into_full()),
+        })
+      }
+      ChunkData::Brickmap { .. } => self.clone(),
     }
   }
 
-  pub fn debug_red_sphere_chunk() -> Self {
-    let mut buffer = Vec::with_capacity(crate::CHUNK_VOXEL_COUNT);
+  /// Packs which voxels are occupied into one bit per voxel, reading the
+  /// chunk's own representation directly.
+  pub fn prepare_occupancy(&self) -> Box<GpuChunkOccupancy> {
+    let occupancy = match &self.data {
+      ChunkData::Full { data } => {
+        let mut mask = [0; CHUNK_VOXEL_COUNT / 32];
+        for (word, voxels) in mask.iter_mut().zip(data.chunks_exact(32)) {
+          *word = voxels
+            .iter()
+            .enumerate()
+            .fold(0, |word, (j, v)| word | (v.is_some() as u32) << j);
+        }
+        mask
+      }
+      ChunkData::Palette { data } => data.occupancy_mask(),
+      ChunkData::Octree { data } => data.occupancy_mask(),
+      ChunkData::Brickmap { data } => data.occupancy_mask(),
+    };
+    GpuChunkOccupancy::from_mask(occupancy)
+  }
 
-    for z in 0..64 {
-      for y in 0..64 {
-        for x in 0..64 {
+  /// Borrows every voxel in dense order, without cloning them.
+  fn dense_refs(&self) -> Vec<Option<&FullVoxel>> {
+    match &self.data {
+      ChunkData::Full { data } => data.iter().map(Option::as_ref).collect(),
+      ChunkData::Palette { data } => data.iter().collect(),
+      ChunkData::Octree { data } => data.dense_refs(),
+      ChunkData::Brickmap { data } => data.dense_refs(),
+    }
+  }
+
+  /// The bytes the chunk's voxel data occupies on the heap.
+  pub fn memory_usage(&self) -> usize {
+    use std::mem::size_of;
+
+    match &self.data {
+      ChunkData::Full { data } => {
+        data.capacity() * size_of::<Option<FullVoxel>>()
+      }
+      ChunkData::Palette { data } => {
+        data.palette.capacity() * (size_of::<FullVoxel>() + size_of::<u32>())
+          + data.indices.capacity() * size_of::<u32>()
+      }
+      ChunkData::Octree { data } => {
+        data.nodes.capacity() * size_of::<octree::OctreeNode>()
+      }
+      ChunkData::Brickmap { data } => {
+        data.grid.capacity() * size_of::<u32>()
+          + data.bricks.len()
+            * brickmap::BRICK_VOXEL_COUNT
+            * size_of::<Option<FullVoxel>>()
+      }
+    }
+  }
+
+  pub fn debug_red_sphere_chunk(materials: &VoxelMaterials) -> Self {
+    let red = materials.intern(&VoxelMaterial::from_color(Vec3::X));
+    let mut buffer = Vec::with_capacity(CHUNK_VOXEL_COUNT);
+
+    let size = CHUNK_SIZE as i32;
+    for z in 0..size {
+      for y in 0..size {
+        for x in 0..size {
           let pos = IVec3::new(x, y, z);
-          let frac_pos =
-            Vec3::new(pos.x as _, pos.y as _, pos.z as _) / 32.0 - 1.0;
+          let frac_pos = pos.as_vec3() / (size / 2) as f32 - 1.0;
           let occupied = frac_pos.length() <= 1.0;
           let normal = frac_pos.normalize();
-          let color = normal / 2.0 + 1.0;
 
-          buffer.push(occupied.then_some(FullVoxel { normal, color }));
+          buffer.push(occupied.then(|| FullVoxel::new(normal, red)));
         }
       }
     }
 
-    Chunk::Full { data: buffer }
+    Chunk::new(ChunkData::Full { data: buffer })
   }
 
   #[allow(dead_code)]
   pub fn new_empty() -> Self {
-    Self::Full {
+    Self::new(ChunkData::Full {
       data: vec![None; CHUNK_VOXEL_COUNT],
-    }
-  }
-}
-
-impl RenderAsset for Chunk {
-  type PreparedAsset = GpuChunk;
-
-  type Param = (SRes<RenderDevice>, SRes<RenderQueue>);
-
-  fn asset_usage(&self) -> RenderAssetUsages {
-    RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD
-  }
-
-  fn prepare_asset(
-    self,
-    (render_device, render_queue): &mut SystemParamItem<Self::Param>,
-  ) -> Result<Self::PreparedAsset, PrepareAssetError<Self>> {
-    debug!("creating `GpuChunk`");
-
-    let mut attribute_buffer = StorageBuffer::from(self.prepare_attributes());
-    attribute_buffer.write_buffer(render_device, render_queue);
-
-    Ok(GpuChunk {
-      occupancy: self.prepare_occupancy(),
-      attribute_buffer,
     })
   }
 }
 
-pub struct GpuChunk {
-  pub occupancy:        GpuChunkOccupancy,
-  pub attribute_buffer: StorageBuffer<GpuChunkAttributes>,
-}
-
 #[allow(clippy::type_complexity)]
 fn extract_chunk_entities(
   mut commands: Commands,
@@ -157,8 +301,21 @@ impl Plugin for ChunkPlugin {
     app
       .register_asset_reflect::<Chunk>()
       .init_asset::<Chunk>()
-      .add_plugins(RenderAssetPlugin::<Chunk>::default())
-      .register_type_data::<Chunk, InspectorEguiImpl>();
+      .init_resource::<VoxelMaterials>()
+      .init_resource::<NormalMode>()
+      .add_plugins(ChunkRenderPlugin)
+      .init_asset::<VoxScene>()
+      .init_asset_loader::<VoxLoader>()
+      .init_asset_loader::<MnkLoader>()
+      .register_type_data::<Chunk, InspectorEguiImpl>()
+      .init_resource::<VoxelDag>()
+      .add_systems(Update, dag::update_voxel_dag);
+
+    // processing rewrites `.mnk` assets in the current format
+    let saver = MnkSaver::from_world(&mut app.world);
+    app
+      .register_asset_processor(LoadAndSave::<MnkLoader, _>::from(saver))
+      .set_default_asset_processor::<LoadAndSave<MnkLoader, MnkSaver>>("mnk");
   }
 
   fn finish(&self, app: &mut App) {
@@ -169,3 +326,22 @@ impl Plugin for ChunkPlugin {
     render_app.add_systems(ExtractSchedule, extract_chunk_entities);
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn indexes_voxels_x_first() {
+    let size = CHUNK_SIZE as u32;
+    assert_eq!(voxel_index(UVec3::X), 1);
+    assert_eq!(voxel_index(UVec3::Y), CHUNK_SIZE);
+    assert_eq!(voxel_index(UVec3::Z), CHUNK_SIZE * CHUNK_SIZE);
+    assert_eq!(voxel_index(UVec3::splat(size - 1)), CHUNK_VOXEL_COUNT - 1);
+    for index in 0..CHUNK_VOXEL_COUNT {
+      let pos = voxel_pos(index);
+      assert!(pos.cmplt(UVec3::splat(size)).all());
+      assert_eq!(voxel_index(pos), index);
+    }
+  }
+}
@@ -0,0 +1,251 @@
+//! Voxel normals derived from the occupancy field.
+//!
+//! A voxel's normal points from the occupied voxels around it towards the
+//! empty ones: the offsets to every empty voxel within the kernel are summed
+//! and normalized. Larger kernels give smoother normals at the cost of
+//! rounding off sharp edges.
+
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+
+use super::{voxel_index, voxel_pos, Chunk, ChunkData};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+/// The neighborhood sampled to estimate a normal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Reflect)]
+pub struct NormalKernel {
+  /// Voxels closer than `radius + 1` contribute, so a radius of 1 is the
+  /// classic 26-neighborhood gradient.
+  pub radius: u32,
+}
+
+impl Default for NormalKernel {
+  fn default() -> Self { Self { radius: 2 } }
+}
+
+impl NormalKernel {
+  /// The offsets of the kernel, excluding the center.
+  fn offsets(&self) -> impl Iterator<Item = IVec3> {
+    let r = self.radius as i32;
+    (-r..=r).flat_map(move |z| {
+      (-r..=r).flat_map(move |y| {
+        (-r..=r).filter_map(move |x| {
+          let offset = IVec3::new(x, y, z);
+          (offset != IVec3::ZERO && offset.length_squared() < (r + 1) * (r + 1))
+            .then_some(offset)
+        })
+      })
+    })
+  }
+}
+
+/// How voxel normals reach the GPU. Changing it at runtime switches the
+/// direct pass to the matching pipeline.
+#[derive(
+  Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq, Eq, Hash,
+)]
+pub enum NormalMode {
+  /// Each voxel's stored normal is uploaded alongside its material. Voxels
+  /// without one are uploaded with a zero normal, which only lights their
+  /// emission.
+  #[default]
+  Stored,
+  /// Shaders derive normals from the occupancy with `kernel`. Stored normals
+  /// aren't uploaded, so chunks can be stripped of them with
+  /// [`Chunk::strip_normals`].
+  Derived { kernel: NormalKernel },
+}
+
+/// Estimates the normal of the voxel at `pos` from the occupancy around it.
+pub fn estimate_normal(
+  pos: IVec3,
+  kernel: NormalKernel,
+  occupied: impl Fn(IVec3) -> bool,
+) -> Vec3 {
+  kernel
+    .offsets()
+    .filter(|offset| !occupied(pos + *offset))
+    .map(|offset| offset.as_vec3())
+    .sum::<Vec3>()
+    .try_normalize()
+    .unwrap_or(Vec3::Y)
+}
+
+/// Occupancy of a chunk plus a border of `padding` voxels from its
+/// neighbors, so kernels near the edge can be evaluated without lookups into
+/// other chunks.
+struct PaddedOccupancy {
+  padding: i32,
+  size:    i32,
+  bits:    Vec<bool>,
+}
+
+impl PaddedOccupancy {
+  fn new(chunk: &Chunk, padding: u32, outside: impl Fn(IVec3) -> bool) -> Self {
+    let padding = padding as i32;
+    let size = CHUNK_SIZE as i32 + 2 * padding;
+    let inside = chunk.occupancy();
+    let mut bits = Vec::with_capacity((size * size * size) as usize);
+    for z in 0..size {
+      for y in 0..size {
+        for x in 0..size {
+          let pos = IVec3::new(x, y, z) - padding;
+          bits.push(
+            match pos.cmpge(IVec3::ZERO).all()
+              && pos.cmplt(IVec3::splat(CHUNK_SIZE as i32)).all()
+            {
+              true => inside[voxel_index(pos.as_uvec3())],
+              false => outside(pos),
+            },
+          );
+        }
+      }
+    }
+    Self {
+      padding,
+      size,
+      bits,
+    }
+  }
+
+  /// Whether the voxel at chunk-local `pos` is occupied. `pos` may lie up to
+  /// `padding` voxels outside the chunk.
+  fn get(&self, pos: IVec3) -> bool {
+    let p = pos + self.padding;
+    self.bits[(p.z * self.size * self.size + p.y * self.size + p.x) as usize]
+  }
+}
+
+impl Chunk {
+  /// Whether each voxel is occupied, in dense order.
+  pub fn occupancy(&self) -> Vec<bool> {
+    match &self.data {
+      ChunkData::Palette { data } => data.indices().map(|i| i != 0).collect(),
+      _ => self.into_full().iter().map(Option::is_some).collect(),
+    }
+  }
+
+  /// Replaces every stored normal with one estimated from the occupancy.
+  ///
+  /// `outside` reports the occupancy of chunk-local positions beyond the
+  /// chunk's bounds, so normals stay continuous across chunk borders. Voxels
+  /// buried on all six sides aren't visible and get `Vec3::Y`.
+  pub fn derive_normals(
+    &mut self,
+    kernel: NormalKernel,
+    outside: impl Fn(IVec3) -> bool,
+  ) {
+    let occupancy = PaddedOccupancy::new(self, kernel.radius.max(1), outside);
+    let faces = [IVec3::X, IVec3::Y, IVec3::Z];
+    self.map_voxels(|index, voxel| {
+      let pos = voxel_pos(index).as_ivec3();
+      let exposed = faces
+        .iter()
+        .any(|f| !occupancy.get(pos + *f) || !occupancy.get(pos - *f));
+      voxel.normal = Some(match exposed {
+        true => estimate_normal(pos, kernel, |p| occupancy.get(p)),
+        false => Vec3::Y,
+      });
+    });
+  }
+
+  /// Drops every stored normal, for use with [`NormalMode::Derived`].
+  ///
+  /// Palette and octree chunks compress much better without normals, since
+  /// voxels then differ only by material.
+  pub fn strip_normals(&mut self) {
+    self.map_voxels(|_, voxel| voxel.normal = None);
+  }
+
+  /// Edits every occupied voxel in dense order, keeping the chunk's
+  /// representation.
+  fn map_voxels(&mut self, mut f: impl FnMut(usize, &mut super::FullVoxel)) {
+    let mut data = self.into_full();
+    debug_assert_eq!(data.len(), CHUNK_VOXEL_COUNT);
+    for (index, voxel) in data.iter_mut().enumerate() {
+      if let Some(voxel) = voxel {
+        f(index, voxel);
+      }
+    }
+
+    let full = Chunk::new(ChunkData::Full { data });
+    *self = match &self.data {
+      ChunkData::Full { .. } => full,
+      ChunkData::Palette { .. } => full.to_palette(),
+      ChunkData::Octree { .. } => full.to_octree(),
+      ChunkData::Brickmap { .. } => full.to_brickmap(),
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::FullVoxel;
+
+  fn solid_chunk(occupied: impl Fn(IVec3) -> bool) -> Chunk {
+    let data = (0..CHUNK_VOXEL_COUNT)
+      .map(|i| {
+        occupied(voxel_pos(i).as_ivec3()).then(|| FullVoxel::new(Vec3::ZERO, 0))
+      })
+      .collect();
+    Chunk::new(ChunkData::Full { data })
+  }
+
+  fn normal_at(chunk: &Chunk, pos: IVec3) -> Vec3 {
+    chunk.get(pos.as_uvec3()).unwrap().unwrap().normal.unwrap()
+  }
+
+  #[test]
+  fn sphere_normals_point_away_from_the_center() {
+    let center = Vec3::splat(CHUNK_SIZE as f32 / 2.0);
+    let radius = CHUNK_SIZE as f32 / 2.0 - 3.0;
+    let inside = |p: IVec3| (p.as_vec3() + 0.5).distance(center) <= radius;
+    let mut chunk = solid_chunk(inside).to_palette();
+    chunk.derive_normals(NormalKernel::default(), |_| false);
+
+    let mut surface = 0;
+    for index in 0..CHUNK_VOXEL_COUNT {
+      let pos = voxel_pos(index).as_ivec3();
+      let buried = [IVec3::X, IVec3::Y, IVec3::Z]
+        .iter()
+        .all(|f| inside(pos + *f) && inside(pos - *f));
+      if !inside(pos) || buried {
+        continue;
+      }
+      let analytic = (pos.as_vec3() + 0.5 - center).normalize();
+      let normal = normal_at(&chunk, pos);
+      assert!(normal.dot(analytic) > 0.9, "{normal} at {pos}");
+      surface += 1;
+    }
+    assert!(surface > 0);
+  }
+
+  fn assert_flat(normal: Vec3, face: Vec3, pos: IVec3) {
+    assert!(normal.abs_diff_eq(face, 1e-6), "{normal} at {pos}");
+  }
+
+  #[test]
+  fn cube_faces_are_flat_across_chunk_borders() {
+    // a box from the middle of the chunk into its +x neighbor
+    let size = CHUNK_SIZE as i32;
+    let (min, max) = (IVec3::new(size / 2, 4, 4), IVec3::new(size * 2, 12, 12));
+    let inside = |p: IVec3| p.cmpge(min).all() && p.cmplt(max).all();
+    let mut chunk = solid_chunk(inside);
+    let kernel = NormalKernel::default();
+    chunk.derive_normals(kernel, inside);
+
+    // face voxels at least a kernel away from the cube's edges, including
+    // those on the chunk border whose kernels reach into the neighbor
+    let margin = kernel.radius as i32;
+    for x in min.x + margin..size {
+      for z in min.z + margin..max.z - margin {
+        let top = IVec3::new(x, max.y - 1, z);
+        assert_flat(normal_at(&chunk, top), Vec3::Y, top);
+        let bottom = IVec3::new(x, min.y, z);
+        assert_flat(normal_at(&chunk, bottom), Vec3::NEG_Y, bottom);
+      }
+    }
+    let face = IVec3::new(min.x, 8, 8);
+    assert_flat(normal_at(&chunk, face), Vec3::NEG_X, face);
+  }
+}
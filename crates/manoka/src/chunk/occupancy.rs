@@ -0,0 +1,359 @@
+//! The occupancy mip pyramid of a chunk.
+//!
+//! Level 0 is the chunk's full resolution occupancy bitmask. Each level above
+//! halves the resolution, with a bit set wherever any of the eight voxels
+//! below it is occupied, up to a single bit for the whole chunk. A ray can
+//! skip an empty node of level `l` in one step instead of `2^l` voxel steps.
+
+use std::ops::Range;
+
+use bevy::prelude::*;
+use zerocopy::FromZeroes;
+
+use super::{voxel_index, GpuChunkOccupancy};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+/// The number of levels above the base mask, down to 1³.
+pub const OCCUPANCY_MIP_LEVELS: usize = CHUNK_SIZE.trailing_zeros() as usize;
+
+/// The word offset of each level in [`GpuChunkOccupancy::mips`], level 1
+/// first. The last entry is the total length.
+const MIP_OFFSETS: [usize; OCCUPANCY_MIP_LEVELS + 1] = {
+  let mut offsets = [0; OCCUPANCY_MIP_LEVELS + 1];
+  let mut level = 1;
+  while level <= OCCUPANCY_MIP_LEVELS {
+    let size = CHUNK_SIZE >> level;
+    offsets[level] = offsets[level - 1] + (size * size * size).div_ceil(32);
+    level += 1;
+  }
+  offsets
+};
+
+/// The number of words taken by every mip level together.
+pub const OCCUPANCY_MIP_WORDS: usize = MIP_OFFSETS[OCCUPANCY_MIP_LEVELS];
+
+/// The index of the node at `pos` within a level of `size`³ nodes.
+fn node_index(pos: UVec3, size: usize) -> usize {
+  let (x, y, z) = (pos.x as usize, pos.y as usize, pos.z as usize);
+  z * size * size + y * size + x
+}
+
+/// The axis of the smallest component of `v`.
+fn min_axis(v: Vec3) -> usize {
+  match (v.x <= v.y, v.x <= v.z, v.y <= v.z) {
+    (true, true, _) => 0,
+    (false, _, true) => 1,
+    _ => 2,
+  }
+}
+
+/// The axis of the largest component of `v`.
+fn max_axis(v: Vec3) -> usize { min_axis(-v) }
+
+/// Where a ray first hits an occupied voxel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OccupancyHit {
+  /// The voxel that was hit, in chunk coordinates.
+  pub voxel:  UVec3,
+  /// The ray parameter at which the ray enters the voxel.
+  pub t:      f32,
+  /// The normal of the face the ray entered through.
+  pub normal: IVec3,
+}
+
+/// The result of a traversal, with the number of nodes it stepped through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OccupancyRaycast {
+  pub hit:   Option<OccupancyHit>,
+  pub steps: u32,
+}
+
+impl GpuChunkOccupancy {
+  /// Wraps a base occupancy mask, building its mip pyramid. It is boxed, as
+  /// with large chunk sizes it takes too much of the stack.
+  pub fn from_mask(occupancy: [u32; CHUNK_VOXEL_COUNT / 32]) -> Box<Self> {
+    let mut this = Self::new_box_zeroed();
+    this.occupancy = occupancy;
+    this.update_mips(UVec3::ZERO, UVec3::splat(CHUNK_SIZE as u32 - 1));
+    this
+  }
+
+  /// Sets whether the voxel at `pos` is occupied, leaving the mips stale
+  /// until [`Self::update_mips`].
+  pub fn set_voxel(&mut self, pos: UVec3, occupied: bool) {
+    let index = voxel_index(pos);
+    let bit = 1 << (index % 32);
+    match occupied {
+      true => self.occupancy[index / 32] |= bit,
+      false => self.occupancy[index / 32] &= !bit,
+    }
+  }
+
+  /// Rebuilds every mip node above the voxels in `min..=max`, returning the
+  /// range of words of `mips` they lie in.
+  pub fn update_mips(&mut self, min: UVec3, max: UVec3) -> Range<usize> {
+    let (mut first, mut last) = (usize::MAX, 0);
+    for level in 1..=OCCUPANCY_MIP_LEVELS {
+      let size = CHUNK_SIZE >> level;
+      let (lo, hi) = (min >> level as u32, max >> level as u32);
+      for z in lo.z..=hi.z {
+        for y in lo.y..=hi.y {
+          for x in lo.x..=hi.x {
+            let pos = UVec3::new(x, y, z);
+            let occupied = (0..8).any(|i| {
+              let child = pos * 2 + UVec3::new(i & 1, (i >> 1) & 1, i >> 2);
+              self.is_occupied(level - 1, child)
+            });
+            let i = MIP_OFFSETS[level - 1] * 32 + node_index(pos, size);
+            match occupied {
+              true => self.mips[i / 32] |= 1 << (i % 32),
+              false => self.mips[i / 32] &= !(1 << (i % 32)),
+            }
+            (first, last) = (first.min(i / 32), last.max(i / 32));
+          }
+        }
+      }
+    }
+    first..last + 1
+  }
+
+  /// Whether anything is occupied within the node at `pos` of `level`, where
+  /// level 0 is single voxels. `pos` is in units of that level's nodes.
+  pub fn is_occupied(&self, level: usize, pos: UVec3) -> bool {
+    let size = CHUNK_SIZE >> level;
+    let index = node_index(pos, size);
+    match level {
+      0 => self.occupancy[index / 32] & (1 << (index % 32)) != 0,
+      _ => {
+        let i = MIP_OFFSETS[level - 1] * 32 + index;
+        self.mips[i / 32] & (1 << (i % 32)) != 0
+      }
+    }
+  }
+
+  /// Finds the first occupied voxel along a ray in chunk coordinates,
+  /// skipping empty space a whole mip node at a time.
+  pub fn raycast(&self, origin: Vec3, dir: Vec3) -> OccupancyRaycast {
+    self.traverse(origin, dir, OCCUPANCY_MIP_LEVELS)
+  }
+
+  /// Like [`Self::raycast`], but steps voxel by voxel without the pyramid.
+  pub fn raycast_flat(&self, origin: Vec3, dir: Vec3) -> OccupancyRaycast {
+    self.traverse(origin, dir, 0)
+  }
+
+  fn traverse(&self, origin: Vec3, dir: Vec3, top: usize) -> OccupancyRaycast {
+    raycast_levels(origin, dir, top, |level, pos| self.is_occupied(level, pos))
+  }
+}
+
+/// Finds the first occupied voxel along a ray in chunk coordinates.
+///
+/// `is_occupied(level, pos)` reports whether anything is occupied in the node
+/// at `pos` of `level`, in units of that level's `2^level`-sized nodes, for
+/// every level up to `top_level`. Levels without data of their own can just
+/// report `true`, and are then stepped through at the next finer level.
+pub fn raycast_levels(
+  origin: Vec3,
+  dir: Vec3,
+  top_level: usize,
+  is_occupied: impl Fn(usize, UVec3) -> bool,
+) -> OccupancyRaycast {
+  // nudge axes the ray runs parallel to, so they are just never crossed,
+  // rather than dividing zero by zero for origins on a face
+  let dir = Vec3::select(dir.cmpeq(Vec3::ZERO), Vec3::splat(1e-8), dir);
+  let inv_dir = dir.recip();
+  let size = Vec3::splat(CHUNK_SIZE as f32);
+  let mut steps = 0;
+  let miss = |steps| OccupancyRaycast { hit: None, steps };
+
+  // clip the ray to the chunk
+  let t0 = -origin * inv_dir;
+  let t1 = (size - origin) * inv_dir;
+  let (near, far) = (t0.min(t1), t0.max(t1));
+  let mut t = near.max_element().max(0.0);
+  let t_exit = far.min_element();
+  if t > t_exit || !t.is_finite() {
+    return miss(steps);
+  }
+  let mut axis = max_axis(near);
+  let mut pos = origin + dir * t;
+  if t > 0.0 {
+    pos[axis] = if dir[axis] > 0.0 { 0.0 } else { size[axis] };
+  }
+
+  // the previous cell, so the ray can't step back into it when it passes
+  // close to an edge and rounding puts it on the wrong side of a face
+  let mut last =
+    Vec3::select(dir.cmpgt(Vec3::ZERO), Vec3::NEG_INFINITY, Vec3::INFINITY);
+
+  loop {
+    // the cell the ray is in, resolving faces towards the direction of
+    // travel. The ray is inside the chunk until `t_exit`, so clamping only
+    // corrects rounding.
+    let mut cell =
+      Vec3::select(dir.cmplt(Vec3::ZERO), pos.ceil() - 1.0, pos.floor());
+    cell = Vec3::select(
+      dir.cmpgt(Vec3::ZERO),
+      cell.max(last),
+      Vec3::select(dir.cmplt(Vec3::ZERO), cell.min(last), cell),
+    );
+    last = cell;
+    let voxel = cell.clamp(Vec3::ZERO, size - 1.0).as_uvec3();
+    steps += 1;
+
+    // the coarsest empty node containing the voxel, if any
+    let empty = (0..=top_level)
+      .rev()
+      .find(|&level| !is_occupied(level, voxel >> level as u32));
+    let Some(level) = empty else {
+      let mut normal = IVec3::ZERO;
+      normal[axis] = -dir[axis].signum() as i32;
+      return OccupancyRaycast {
+        hit: Some(OccupancyHit { voxel, t, normal }),
+        steps,
+      };
+    };
+
+    // jump to where the ray leaves that node
+    let level = level as u32;
+    let node_size = (1 << level) as f32;
+    let node_min = ((voxel >> level) << level).as_vec3();
+    let bound =
+      Vec3::select(dir.cmpgt(Vec3::ZERO), node_min + node_size, node_min);
+    let t_bound = (bound - origin) * inv_dir;
+    axis = min_axis(t_bound);
+    t = t_bound[axis].max(t);
+    if t >= t_exit {
+      return miss(steps);
+    }
+    pos = origin + dir * t;
+    // snap to the face we crossed so rounding can't leave us inside
+    pos[axis] = bound[axis];
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::{
+    test_utils::{dense_mask, random_blocks, random_ray},
+    voxel_pos,
+  };
+
+  /// The first occupied voxel a ray along `axis` from the center of `start`
+  /// passes through, found by walking the mask voxel by voxel.
+  fn first_along_axis(
+    occupancy: &GpuChunkOccupancy,
+    start: UVec3,
+    axis: IVec3,
+  ) -> Option<UVec3> {
+    let size = IVec3::splat(CHUNK_SIZE as i32);
+    let mut pos = start.as_ivec3();
+    while pos.cmpge(IVec3::ZERO).all() && pos.cmplt(size).all() {
+      if occupancy.is_occupied(0, pos.as_uvec3()) {
+        return Some(pos.as_uvec3());
+      }
+      pos += axis;
+    }
+    None
+  }
+
+  #[test]
+  fn lays_out_a_level_per_halving() {
+    assert_eq!(CHUNK_SIZE >> OCCUPANCY_MIP_LEVELS, 1);
+    for level in 1..=OCCUPANCY_MIP_LEVELS {
+      let size = CHUNK_SIZE >> level;
+      let words = MIP_OFFSETS[level] - MIP_OFFSETS[level - 1];
+      assert_eq!(words, (size * size * size).div_ceil(32), "level {level}");
+    }
+    let expected = match CHUNK_SIZE {
+      16 => 20,
+      32 => 148,
+      64 => 1172,
+      128 => 9364,
+      _ => unreachable!(),
+    };
+    assert_eq!(OCCUPANCY_MIP_WORDS, expected);
+  }
+
+  #[test]
+  fn mips_mark_nodes_with_any_occupied_voxel() {
+    let mut rng = fastrand::Rng::with_seed(2);
+    let data = random_blocks(&mut rng, 2, 0.02, 1);
+    let occupancy = GpuChunkOccupancy::from_mask(dense_mask(&data));
+
+    let mut expected = (0..=OCCUPANCY_MIP_LEVELS)
+      .map(|level| vec![false; (CHUNK_SIZE >> level).pow(3)])
+      .collect::<Vec<_>>();
+    for (i, voxel) in data.iter().enumerate() {
+      for (level, nodes) in expected.iter_mut().enumerate() {
+        let node = voxel_pos(i) >> level as u32;
+        nodes[node_index(node, CHUNK_SIZE >> level)] |= voxel.is_some();
+      }
+    }
+    for (level, nodes) in expected.iter().enumerate() {
+      let size = CHUNK_SIZE >> level;
+      for (index, occupied) in nodes.iter().enumerate() {
+        let pos = UVec3::new(
+          (index % size) as u32,
+          (index / size % size) as u32,
+          (index / (size * size)) as u32,
+        );
+        assert_eq!(occupancy.is_occupied(level, pos), *occupied);
+      }
+    }
+  }
+
+  #[test]
+  fn hits_what_the_flat_traversal_hits_in_fewer_steps() {
+    let mut rng = fastrand::Rng::with_seed(0);
+    let (mut steps, mut flat_steps) = (0, 0);
+    for density in [0.02, 0.1, 0.3] {
+      let data = random_blocks(&mut rng, 4, density, 1);
+      let occupancy = GpuChunkOccupancy::from_mask(dense_mask(&data));
+      for _ in 0..500 {
+        let (origin, dir) = random_ray(&mut rng);
+        let levels = occupancy.raycast(origin, dir);
+        let flat = occupancy.raycast_flat(origin, dir);
+        assert_eq!(levels.hit, flat.hit, "ray {origin} {dir}");
+        steps += levels.steps;
+        flat_steps += flat.steps;
+      }
+    }
+    assert!(steps < flat_steps, "{steps} steps, {flat_steps} flat");
+  }
+
+  #[test]
+  fn traces_axis_parallel_rays() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let data = random_blocks(&mut rng, 2, 0.05, 1);
+    let occupancy = GpuChunkOccupancy::from_mask(dense_mask(&data));
+    let size = CHUNK_SIZE as f32;
+    for _ in 0..500 {
+      let start = UVec3::new(
+        rng.u32(0..CHUNK_SIZE as u32),
+        rng.u32(0..CHUNK_SIZE as u32),
+        rng.u32(0..CHUNK_SIZE as u32),
+      );
+      let (i, forward) = (rng.usize(0..3), rng.bool());
+      let mut dir = Vec3::ZERO;
+      dir[i] = if forward { 1.0 } else { -1.0 };
+      let axis = dir.as_ivec3();
+
+      // from the center of `start`, and from outside the chunk along a line
+      // on the voxels' faces, where the other axes divide zero by zero
+      let center = start.as_vec3() + 0.5;
+      let mut outside = start.as_vec3();
+      outside[i] = if forward { -1.0 } else { size + 1.0 };
+      let mut entry = start;
+      entry[i] = if forward { 0 } else { CHUNK_SIZE as u32 - 1 };
+      for (origin, first) in [(center, start), (outside, entry)] {
+        let expected = first_along_axis(&occupancy, first, axis);
+        let levels = occupancy.raycast(origin, dir);
+        assert_eq!(levels.hit.map(|hit| hit.voxel), expected, "{origin} {dir}");
+        assert_eq!(levels.hit, occupancy.raycast_flat(origin, dir).hit);
+      }
+    }
+  }
+}
@@ -0,0 +1,486 @@
+use bevy::prelude::*;
+
+use super::{voxel_index, FullVoxel};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+/// A node of a sparse voxel octree.
+///
+/// `Empty` and `Leaf` cover their whole region uniformly, so a `Leaf` high in
+/// the tree stands for a solid cube of identical voxels. `Branch` holds the
+/// index of its first child in [`OctreeData::nodes`]; the eight children are
+/// stored contiguously, ordered by `x | y << 1 | z << 2`.
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub enum OctreeNode {
+  Empty,
+  Leaf(FullVoxel),
+  Branch(u32),
+}
+
+/// Sparse voxel octree storage for a chunk.
+#[derive(Clone, Debug, Reflect)]
+pub struct OctreeData {
+  pub(super) root:  OctreeNode,
+  pub(super) nodes: Vec<OctreeNode>,
+  /// The first index of each block of eight nodes in `nodes` that edits have
+  /// orphaned, for later splits to reuse.
+  pub(super) free:  Vec<u32>,
+}
+
+/// Offset of child `i` within a node of half-size `half`.
+fn child_offset(i: usize, half: u32) -> UVec3 {
+  UVec3::new(i as u32 & 1, (i as u32 >> 1) & 1, (i as u32 >> 2) & 1) * half
+}
+
+fn boxes_intersect(origin: UVec3, size: u32, min: UVec3, max: UVec3) -> bool {
+  origin.cmple(max).all() && (origin + size).cmpgt(min).all()
+}
+
+impl OctreeData {
+  /// Builds an octree from dense voxel data, collapsing uniform regions.
+  pub fn from_full(data: &[Option<FullVoxel>]) -> Self {
+    let mut nodes = Vec::new();
+    let root = Self::build(data, &mut nodes, UVec3::ZERO, CHUNK_SIZE as u32);
+    Self {
+      root,
+      nodes,
+      free: Vec::new(),
+    }
+  }
+
+  fn build(
+    data: &[Option<FullVoxel>],
+    nodes: &mut Vec<OctreeNode>,
+    origin: UVec3,
+    size: u32,
+  ) -> OctreeNode {
+    if size == 1 {
+      return match &data[voxel_index(origin)] {
+        Some(voxel) => OctreeNode::Leaf(voxel.clone()),
+        None => OctreeNode::Empty,
+      };
+    }
+
+    let half = size / 2;
+    let children: [OctreeNode; 8] = std::array::from_fn(|i| {
+      Self::build(data, nodes, origin + child_offset(i, half), half)
+    });
+
+    // collapse children that are all the same uniform node
+    if !matches!(children[0], OctreeNode::Branch(_))
+      && children.iter().all(|c| *c == children[0])
+    {
+      return children[0].clone();
+    }
+
+    let first_child = nodes.len() as u32;
+    nodes.extend(children);
+    OctreeNode::Branch(first_child)
+  }
+
+  /// Expands the octree back into dense voxel data.
+  pub fn to_full(&self) -> Vec<Option<FullVoxel>> {
+    let mut data = vec![None; CHUNK_VOXEL_COUNT];
+    self.visit(
+      &self.root,
+      UVec3::ZERO,
+      CHUNK_SIZE as u32,
+      &mut |origin, size, voxel| {
+        for z in origin.z..origin.z + size {
+          for y in origin.y..origin.y + size {
+            for x in origin.x..origin.x + size {
+              data[voxel_index(UVec3::new(x, y, z))] = Some(voxel.clone());
+            }
+          }
+        }
+      },
+    );
+    data
+  }
+
+  /// Borrows every voxel in dense order, without cloning them.
+  pub fn dense_refs(&self) -> Vec<Option<&FullVoxel>> {
+    let mut data = vec![None; CHUNK_VOXEL_COUNT];
+    self.visit(
+      &self.root,
+      UVec3::ZERO,
+      CHUNK_SIZE as u32,
+      &mut |origin, size, voxel| {
+        for z in origin.z..origin.z + size {
+          for y in origin.y..origin.y + size {
+            for x in origin.x..origin.x + size {
+              data[voxel_index(UVec3::new(x, y, z))] = Some(voxel);
+            }
+          }
+        }
+      },
+    );
+    data
+  }
+
+  /// Calls `f` with the origin, size and voxel of every occupied leaf.
+  fn visit<'a>(
+    &'a self,
+    node: &'a OctreeNode,
+    origin: UVec3,
+    size: u32,
+    f: &mut impl FnMut(UVec3, u32, &'a FullVoxel),
+  ) {
+    match node {
+      OctreeNode::Empty => {}
+      OctreeNode::Leaf(voxel) => f(origin, size, voxel),
+      OctreeNode::Branch(first_child) => {
+        let half = size / 2;
+        for i in 0..8 {
+          self.visit(
+            &self.nodes[*first_child as usize + i],
+            origin + child_offset(i, half),
+            half,
+            f,
+          );
+        }
+      }
+    }
+  }
+
+  /// Returns the voxel at `pos`, or `None` if it is empty or out of bounds.
+  pub fn get(&self, pos: UVec3) -> Option<&FullVoxel> {
+    if pos.cmpge(UVec3::splat(CHUNK_SIZE as u32)).any() {
+      return None;
+    }
+
+    let mut node = &self.root;
+    let mut size = CHUNK_SIZE as u32;
+    loop {
+      match node {
+        OctreeNode::Empty => return None,
+        OctreeNode::Leaf(voxel) => return Some(voxel),
+        OctreeNode::Branch(first_child) => {
+          size /= 2;
+          let child = pos / size % 2;
+          let i = (child.x | child.y << 1 | child.z << 2) as usize;
+          node = &self.nodes[*first_child as usize + i];
+        }
+      }
+    }
+  }
+
+  /// Writes the voxel at `pos`, splitting uniform nodes on the way down and
+  /// collapsing them again on the way up.
+  ///
+  /// Collapsing frees the children's block of eight nodes, which the next
+  /// split reuses, so repeated edits don't grow the arena.
+  pub fn set(&mut self, pos: UVec3, voxel: Option<FullVoxel>) {
+    let new = match voxel {
+      Some(voxel) => OctreeNode::Leaf(voxel),
+      None => OctreeNode::Empty,
+    };
+    let root = self.root.clone();
+    self.root = self.set_in(root, pos, CHUNK_SIZE as u32, new);
+  }
+
+  fn set_in(
+    &mut self,
+    node: OctreeNode,
+    pos: UVec3,
+    size: u32,
+    new: OctreeNode,
+  ) -> OctreeNode {
+    if size == 1 || node == new {
+      return new;
+    }
+
+    let first_child = match node {
+      OctreeNode::Branch(first_child) => first_child as usize,
+      uniform => match self.free.pop() {
+        Some(first_child) => {
+          let first_child = first_child as usize;
+          self.nodes[first_child..first_child + 8].fill(uniform);
+          first_child
+        }
+        None => {
+          let first_child = self.nodes.len();
+          self.nodes.extend(std::iter::repeat_n(uniform, 8));
+          first_child
+        }
+      },
+    };
+
+    let half = size / 2;
+    let child = pos / half % 2;
+    let i = first_child + (child.x | child.y << 1 | child.z << 2) as usize;
+    let updated = self.set_in(self.nodes[i].clone(), pos, half, new);
+    self.nodes[i] = updated;
+
+    let children = &self.nodes[first_child..first_child + 8];
+    if !matches!(children[0], OctreeNode::Branch(_))
+      && children.iter().all(|c| *c == children[0])
+    {
+      // uniform children hold no branches, so the block is the only thing
+      // orphaned
+      let uniform = children[0].clone();
+      self.free.push(first_child as u32);
+      return uniform;
+    }
+    OctreeNode::Branch(first_child as u32)
+  }
+
+  /// A copy holding only the nodes reachable from the root, without the
+  /// blocks edits have freed.
+  pub fn compacted(&self) -> Self {
+    let mut nodes = Vec::with_capacity(self.nodes.len() - 8 * self.free.len());
+    let root = self.copy_reachable(&self.root, &mut nodes);
+    Self {
+      root,
+      nodes,
+      free: Vec::new(),
+    }
+  }
+
+  fn copy_reachable(
+    &self,
+    node: &OctreeNode,
+    nodes: &mut Vec<OctreeNode>,
+  ) -> OctreeNode {
+    let OctreeNode::Branch(first_child) = node else {
+      return node.clone();
+    };
+    let copy = nodes.len();
+    nodes.extend(std::iter::repeat_n(OctreeNode::Empty, 8));
+    for i in 0..8 {
+      let child = &self.nodes[*first_child as usize + i];
+      nodes[copy + i] = self.copy_reachable(child, nodes);
+    }
+    OctreeNode::Branch(copy as u32)
+  }
+
+  /// Returns every occupied voxel within the inclusive box `min..=max`.
+  ///
+  /// Subtrees outside the box or known to be empty are skipped entirely.
+  pub fn query_region(
+    &self,
+    min: UVec3,
+    max: UVec3,
+  ) -> Vec<(UVec3, &FullVoxel)> {
+    let mut voxels = Vec::new();
+    self.visit_region(
+      &self.root,
+      UVec3::ZERO,
+      CHUNK_SIZE as u32,
+      min,
+      max,
+      &mut voxels,
+    );
+    voxels
+  }
+
+  fn visit_region<'a>(
+    &'a self,
+    node: &'a OctreeNode,
+    origin: UVec3,
+    size: u32,
+    min: UVec3,
+    max: UVec3,
+    voxels: &mut Vec<(UVec3, &'a FullVoxel)>,
+  ) {
+    if !boxes_intersect(origin, size, min, max) {
+      return;
+    }
+
+    match node {
+      OctreeNode::Empty => {}
+      OctreeNode::Leaf(voxel) => {
+        let lo = origin.max(min);
+        let hi = (origin + size - 1).min(max);
+        for z in lo.z..=hi.z {
+          for y in lo.y..=hi.y {
+            for x in lo.x..=hi.x {
+              voxels.push((UVec3::new(x, y, z), voxel));
+            }
+          }
+        }
+      }
+      OctreeNode::Branch(first_child) => {
+        let half = size / 2;
+        for i in 0..8 {
+          self.visit_region(
+            &self.nodes[*first_child as usize + i],
+            origin + child_offset(i, half),
+            half,
+            min,
+            max,
+            voxels,
+          );
+        }
+      }
+    }
+  }
+
+  /// Exports the occupancy of the octree as a packed bitmask, in the same
+  /// layout as `GpuChunkOccupancy`.
+  pub fn occupancy_mask(&self) -> [u32; CHUNK_VOXEL_COUNT / 32] {
+    let mut mask = [0; CHUNK_VOXEL_COUNT / 32];
+    self.visit(
+      &self.root,
+      UVec3::ZERO,
+      CHUNK_SIZE as u32,
+      &mut |origin, size, _| {
+        // leaves are aligned to their size, so each row of a leaf either
+        // covers whole words or lies within a single word
+        for z in origin.z..origin.z + size {
+          for y in origin.y..origin.y + size {
+            let first = voxel_index(UVec3::new(origin.x, y, z));
+            let (words, row) = match size >= 32 {
+              true => (size as usize / 32, u32::MAX),
+              false => (1, ((1 << size) - 1) << (first % 32)),
+            };
+            let start = first / 32;
+            for word in &mut mask[start..start + words] {
+              *word |= row;
+            }
+          }
+        }
+      },
+    );
+    mask
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::{
+    test_utils::{dense_mask, random_blocks, random_voxels},
+    voxel_pos,
+  };
+
+  fn test_chunks() -> Vec<Vec<Option<FullVoxel>>> {
+    let mut rng = fastrand::Rng::with_seed(1);
+    vec![
+      vec![None; CHUNK_VOXEL_COUNT],
+      random_voxels(&mut rng, 1.0, 1),
+      random_voxels(&mut rng, 0.3, 5),
+      random_blocks(&mut rng, 8, 0.5, 3),
+      random_blocks(&mut rng, 2, 0.3, 2),
+    ]
+  }
+
+  #[test]
+  fn round_trips_dense_data() {
+    for data in test_chunks() {
+      let octree = OctreeData::from_full(&data);
+      assert_eq!(octree.to_full(), data);
+      let refs = octree.dense_refs();
+      assert!(refs.iter().zip(&data).all(|(a, b)| *a == b.as_ref()));
+    }
+  }
+
+  #[test]
+  fn collapses_uniform_regions() {
+    let empty = OctreeData::from_full(&vec![None; CHUNK_VOXEL_COUNT]);
+    assert_eq!(empty.root, OctreeNode::Empty);
+    assert!(empty.nodes.is_empty());
+
+    let mut rng = fastrand::Rng::with_seed(2);
+    let solid = OctreeData::from_full(&random_voxels(&mut rng, 1.0, 1));
+    assert!(matches!(solid.root, OctreeNode::Leaf(_)));
+    assert!(solid.nodes.is_empty());
+  }
+
+  #[test]
+  fn occupancy_mask_matches_dense_data() {
+    for data in test_chunks() {
+      let octree = OctreeData::from_full(&data);
+      assert_eq!(octree.occupancy_mask(), dense_mask(&data));
+    }
+  }
+
+  #[test]
+  fn point_queries_match_dense_data() {
+    for data in test_chunks() {
+      let octree = OctreeData::from_full(&data);
+      for (i, voxel) in data.iter().enumerate() {
+        assert_eq!(octree.get(voxel_pos(i)), voxel.as_ref());
+      }
+      assert_eq!(octree.get(UVec3::splat(CHUNK_SIZE as u32)), None);
+    }
+  }
+
+  #[test]
+  fn region_queries_match_dense_data() {
+    let mut rng = fastrand::Rng::with_seed(3);
+    let size = CHUNK_SIZE as u32;
+    for data in test_chunks() {
+      let octree = OctreeData::from_full(&data);
+      for _ in 0..8 {
+        let a =
+          UVec3::new(rng.u32(0..size), rng.u32(0..size), rng.u32(0..size));
+        let b =
+          UVec3::new(rng.u32(0..size), rng.u32(0..size), rng.u32(0..size));
+        let (min, max) = (a.min(b), a.max(b));
+
+        let mut found = octree.query_region(min, max);
+        found.sort_by_key(|(pos, _)| voxel_index(*pos));
+        let expected = data
+          .iter()
+          .enumerate()
+          .map(|(i, voxel)| (voxel_pos(i), voxel))
+          .filter(|(pos, _)| pos.cmpge(min).all() && pos.cmple(max).all())
+          .filter_map(|(pos, voxel)| Some((pos, voxel.as_ref()?)))
+          .collect::<Vec<_>>();
+        assert_eq!(found, expected);
+      }
+    }
+  }
+
+  #[test]
+  fn set_matches_dense_writes() {
+    let mut rng = fastrand::Rng::with_seed(4);
+    let mut data = random_blocks(&mut rng, 4, 0.5, 2);
+    let mut octree = OctreeData::from_full(&data);
+    for _ in 0..2000 {
+      let i = rng.usize(0..CHUNK_VOXEL_COUNT);
+      let voxel = rng.bool().then(|| FullVoxel::new(Vec3::Y, rng.u32(0..2)));
+      octree.set(voxel_pos(i), voxel.clone());
+      data[i] = voxel;
+      assert_eq!(octree.get(voxel_pos(i)), data[i].as_ref());
+    }
+    assert_eq!(octree.to_full(), data);
+  }
+
+  #[test]
+  fn repeated_edits_reuse_freed_nodes() {
+    let mut octree = OctreeData::from_full(&vec![None; CHUNK_VOXEL_COUNT]);
+    let voxel = FullVoxel::new(Vec3::Y, 0);
+    let mut rng = fastrand::Rng::with_seed(5);
+    for _ in 0..100 {
+      let pos = voxel_pos(rng.usize(0..CHUNK_VOXEL_COUNT));
+      octree.set(pos, Some(voxel.clone()));
+      octree.set(pos, None);
+    }
+
+    assert_eq!(octree.root, OctreeNode::Empty);
+    // a single voxel splits one node per level
+    let levels = CHUNK_SIZE.trailing_zeros() as usize;
+    assert_eq!(octree.nodes.len(), 8 * levels);
+    assert_eq!(octree.free.len(), levels);
+  }
+
+  #[test]
+  fn compacting_drops_freed_nodes() {
+    let mut rng = fastrand::Rng::with_seed(6);
+    let mut data = random_blocks(&mut rng, 4, 0.5, 2);
+    let mut octree = OctreeData::from_full(&data);
+    for _ in 0..500 {
+      let i = rng.usize(0..CHUNK_VOXEL_COUNT);
+      octree.set(voxel_pos(i), None);
+      data[i] = None;
+    }
+
+    let compacted = octree.compacted();
+    assert_eq!(compacted.to_full(), data);
+    assert_eq!(
+      compacted.nodes.len(),
+      octree.nodes.len() - 8 * octree.free.len()
+    );
+  }
+}
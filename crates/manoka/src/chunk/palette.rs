@@ -0,0 +1,375 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{FullVoxel, VoxelKey};
+use crate::CHUNK_VOXEL_COUNT;
+
+/// Palette-compressed voxel storage.
+///
+/// Stores each unique voxel once in `palette`, and a bit-packed index per
+/// voxel into it. Index `0` is reserved for empty voxels, so index `i`
+/// refers to `palette[i - 1]`. The index width is the smallest of 1, 2, 4,
+/// 8, 16 or 32 bits that fits the palette, so indices never straddle words.
+///
+/// Entries no voxel refers to any more are reused by the next new voxel, and
+/// the palette is compacted once the rest fit a narrower index width, so
+/// repeated edits don't grow it.
+#[derive(Clone, Debug, Reflect)]
+pub struct PaletteData {
+  pub(super) palette: Vec<FullVoxel>,
+  pub(super) bits:    u32,
+  pub(super) indices: Vec<u32>,
+  /// The raw index of each referenced palette entry, by its voxel's bits.
+  #[reflect(ignore)]
+  lookup:             HashMap<VoxelKey, u32>,
+  /// How many voxels refer to each palette entry.
+  #[reflect(ignore)]
+  counts:             Vec<u32>,
+  /// The raw indices of the entries no voxel refers to.
+  #[reflect(ignore)]
+  free:               Vec<u32>,
+}
+
+impl PaletteData {
+  /// Compresses dense voxel data into palette form.
+  pub fn from_full(data: &[Option<FullVoxel>]) -> Self {
+    let mut palette = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut counts = Vec::new();
+    let raw_indices = data
+      .iter()
+      .map(|v| match v {
+        Some(voxel) => {
+          let raw = *lookup.entry(voxel.bit_key()).or_insert_with(|| {
+            palette.push(voxel.clone());
+            counts.push(0);
+            palette.len() as u32
+          });
+          counts[raw as usize - 1] += 1;
+          raw
+        }
+        None => 0,
+      })
+      .collect::<Vec<_>>();
+
+    let bits = Self::bits_for_len(palette.len());
+    let per_word = 32 / bits as usize;
+    let mut indices = vec![0; data.len().div_ceil(per_word)];
+    for (i, index) in raw_indices.into_iter().enumerate() {
+      indices[i / per_word] |= index << ((i % per_word) as u32 * bits);
+    }
+
+    Self {
+      palette,
+      bits,
+      indices,
+      lookup,
+      counts,
+      free: Vec::new(),
+    }
+  }
+
+  /// Wraps packed indices into `palette`, as stored in a file, or `None` if
+  /// any index lies beyond the palette. Entries no voxel refers to are free.
+  pub(super) fn from_packed(
+    palette: Vec<FullVoxel>,
+    bits: u32,
+    indices: Vec<u32>,
+  ) -> Option<Self> {
+    let mut this = Self {
+      counts: vec![0; palette.len()],
+      palette,
+      bits,
+      indices,
+      lookup: HashMap::new(),
+      free: Vec::new(),
+    };
+    for raw in this.indices().collect::<Vec<_>>() {
+      if raw != 0 {
+        *this.counts.get_mut(raw as usize - 1)? += 1;
+      }
+    }
+    for (i, voxel) in this.palette.iter().enumerate() {
+      let raw = i as u32 + 1;
+      match this.counts[i] {
+        0 => this.free.push(raw),
+        _ => {
+          this.lookup.entry(voxel.bit_key()).or_insert(raw);
+        }
+      }
+    }
+    Some(this)
+  }
+
+  /// Expands the palette back into dense voxel data.
+  pub fn to_full(&self) -> Vec<Option<FullVoxel>> {
+    self.iter().map(|v| v.cloned()).collect()
+  }
+
+  /// The smallest index width that can address `len` entries plus empty.
+  fn bits_for_len(len: usize) -> u32 {
+    [1, 2, 4, 8, 16]
+      .into_iter()
+      .find(|bits| len < (1 << bits))
+      .unwrap_or(32)
+  }
+
+  fn mask(&self) -> u32 { u32::MAX >> (32 - self.bits) }
+
+  /// Iterates the raw palette index of every voxel, `0` being empty.
+  pub fn indices(&self) -> impl Iterator<Item = u32> + '_ {
+    let per_word = 32 / self.bits as usize;
+    let mask = self.mask();
+    self
+      .indices
+      .iter()
+      .flat_map(move |word| {
+        (0..per_word).map(move |j| (word >> (j as u32 * self.bits)) & mask)
+      })
+      .take(CHUNK_VOXEL_COUNT)
+  }
+
+  /// One bit per voxel, set where the voxel is occupied.
+  pub fn occupancy_mask(&self) -> [u32; CHUNK_VOXEL_COUNT / 32] {
+    let mut mask = [0; CHUNK_VOXEL_COUNT / 32];
+    // with 1-bit indices, the indices already are the mask
+    if self.bits == 1 {
+      mask.copy_from_slice(&self.indices[..CHUNK_VOXEL_COUNT / 32]);
+      return mask;
+    }
+
+    let per_word = 32 / self.bits as usize;
+    let index_mask = self.mask();
+    for (i, &word) in self.indices.iter().enumerate() {
+      if word == 0 {
+        continue;
+      }
+      for j in 0..per_word {
+        if (word >> (j as u32 * self.bits)) & index_mask != 0 {
+          let voxel = i * per_word + j;
+          mask[voxel / 32] |= 1 << (voxel % 32);
+        }
+      }
+    }
+    mask
+  }
+
+  fn raw_index(&self, index: usize) -> u32 {
+    let per_word = 32 / self.bits as usize;
+    let shift = (index % per_word) as u32 * self.bits;
+    (self.indices[index / per_word] >> shift) & self.mask()
+  }
+
+  fn set_raw_index(&mut self, index: usize, raw: u32) {
+    let per_word = 32 / self.bits as usize;
+    let shift = (index % per_word) as u32 * self.bits;
+    let mask = self.mask() << shift;
+    let word = &mut self.indices[index / per_word];
+    *word = (*word & !mask) | (raw << shift);
+  }
+
+  /// Returns the voxel at dense index `index`.
+  pub fn get(&self, index: usize) -> Option<&FullVoxel> {
+    let raw = self.raw_index(index);
+    raw.checked_sub(1).map(|i| &self.palette[i as usize])
+  }
+
+  /// Writes the voxel at dense index `index`, reusing or growing the palette
+  /// and widening the packed indices if needed.
+  pub fn set(&mut self, index: usize, voxel: Option<FullVoxel>) {
+    let raw = match voxel {
+      None => 0,
+      Some(voxel) => self.acquire(voxel),
+    };
+    let old = self.raw_index(index);
+    self.set_raw_index(index, raw);
+    self.release(old);
+  }
+
+  /// The raw index of `voxel`, adding it to the palette if no voxel refers
+  /// to it yet, counting one more reference.
+  fn acquire(&mut self, voxel: FullVoxel) -> u32 {
+    let key = voxel.bit_key();
+    let raw = match self.lookup.get(&key) {
+      Some(&raw) => raw,
+      None => {
+        let raw = match self.free.pop() {
+          Some(raw) => {
+            self.palette[raw as usize - 1] = voxel;
+            raw
+          }
+          None => {
+            self.palette.push(voxel);
+            self.counts.push(0);
+            let bits = Self::bits_for_len(self.palette.len());
+            if bits != self.bits {
+              self.repack(bits);
+            }
+            self.palette.len() as u32
+          }
+        };
+        self.lookup.insert(key, raw);
+        raw
+      }
+    };
+    self.counts[raw as usize - 1] += 1;
+    raw
+  }
+
+  /// Counts one reference fewer to raw index `raw`, freeing its entry once
+  /// no voxel refers to it.
+  fn release(&mut self, raw: u32) {
+    let Some(i) = raw.checked_sub(1).map(|i| i as usize) else {
+      return;
+    };
+    self.counts[i] -= 1;
+    if self.counts[i] > 0 {
+      return;
+    }
+    self.lookup.remove(&self.palette[i].bit_key());
+    self.free.push(raw);
+
+    // waiting for twice the headroom keeps a voxel that comes and goes at a
+    // width's limit from repacking the chunk on every write
+    let live = self.palette.len() - self.free.len();
+    if Self::bits_for_len(2 * live) < self.bits {
+      self.compact();
+    }
+  }
+
+  /// Drops the free entries, renumbering the rest, and narrows the indices
+  /// to fit.
+  fn compact(&mut self) {
+    let mut remap = vec![0; self.palette.len() + 1];
+    let mut palette = Vec::new();
+    let mut counts = Vec::new();
+    for (i, voxel) in self.palette.drain(..).enumerate() {
+      if self.counts[i] > 0 {
+        palette.push(voxel);
+        counts.push(self.counts[i]);
+        remap[i + 1] = palette.len() as u32;
+      }
+    }
+    let raw_indices = self.indices().map(|raw| remap[raw as usize]).collect();
+
+    self.lookup = palette
+      .iter()
+      .enumerate()
+      .map(|(i, voxel)| (voxel.bit_key(), i as u32 + 1))
+      .collect();
+    self.free.clear();
+    self.pack(Self::bits_for_len(palette.len()), raw_indices);
+    self.palette = palette;
+    self.counts = counts;
+  }
+
+  /// A copy without free entries, for saving.
+  pub(super) fn compacted(&self) -> Self {
+    let mut data = self.clone();
+    data.compact();
+    data
+  }
+
+  pub(super) fn has_free_entries(&self) -> bool { !self.free.is_empty() }
+
+  fn repack(&mut self, bits: u32) {
+    let raw_indices = self.indices().collect();
+    self.pack(bits, raw_indices);
+  }
+
+  /// Replaces the indices with `raw_indices` packed at `bits` wide.
+  fn pack(&mut self, bits: u32, raw_indices: Vec<u32>) {
+    let per_word = 32 / bits as usize;
+    self.bits = bits;
+    self.indices = vec![0; raw_indices.len().div_ceil(per_word)];
+    for (i, raw) in raw_indices.into_iter().enumerate() {
+      self.set_raw_index(i, raw);
+    }
+  }
+
+  /// Iterates every voxel, resolved through the palette.
+  pub fn iter(&self) -> impl Iterator<Item = Option<&FullVoxel>> + '_ {
+    self
+      .indices()
+      .map(|i| i.checked_sub(1).map(|i| &self.palette[i as usize]))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::test_utils::{dense_mask, random_voxel, random_voxels};
+
+  #[test]
+  fn round_trips_every_index_width() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    for variety in [1, 2, 3, 15, 16, 255, 256, 4000] {
+      let data = random_voxels(&mut rng, 0.5, variety);
+      let palette = PaletteData::from_full(&data);
+      assert_eq!(palette.to_full(), data, "{variety} distinct voxels");
+      assert_eq!(palette.occupancy_mask(), dense_mask(&data));
+    }
+  }
+
+  #[test]
+  fn round_trips_empty_and_full_chunks() {
+    let empty = vec![None; CHUNK_VOXEL_COUNT];
+    assert_eq!(PaletteData::from_full(&empty).to_full(), empty);
+
+    let mut rng = fastrand::Rng::with_seed(2);
+    let full = random_voxels(&mut rng, 1.0, 7);
+    assert_eq!(PaletteData::from_full(&full).to_full(), full);
+  }
+
+  #[test]
+  fn set_matches_dense_writes() {
+    let mut rng = fastrand::Rng::with_seed(3);
+    let mut data = random_voxels(&mut rng, 0.2, 2);
+    let mut palette = PaletteData::from_full(&data);
+
+    // enough new voxels to widen the indices several times
+    for _ in 0..2000 {
+      let index = rng.usize(0..CHUNK_VOXEL_COUNT);
+      let voxel = rng.bool().then(|| random_voxel(&mut rng, 300));
+      palette.set(index, voxel.clone());
+      data[index] = voxel;
+      assert_eq!(palette.get(index), data[index].as_ref());
+    }
+    assert_eq!(palette.to_full(), data);
+  }
+
+  #[test]
+  fn rewriting_keeps_the_palette_bounded() {
+    let mut rng = fastrand::Rng::with_seed(4);
+    let mut palette = PaletteData::from_full(&random_voxels(&mut rng, 0.5, 4));
+
+    // each pass paints the chunk with four voxels it hasn't held before, so
+    // at most eight are ever in use at once
+    for pass in 0..20 {
+      let voxel =
+        |index: usize| FullVoxel::new(Vec3::Y, pass * 4 + (index % 4) as u32);
+      for index in 0..CHUNK_VOXEL_COUNT {
+        palette.set(index, Some(voxel(index)));
+      }
+      assert!(
+        palette.palette.len() <= 8,
+        "{} entries",
+        palette.palette.len()
+      );
+      assert_eq!(palette.bits, 4);
+      assert_eq!(
+        palette.to_full(),
+        (0..CHUNK_VOXEL_COUNT)
+          .map(|i| Some(voxel(i)))
+          .collect::<Vec<_>>()
+      );
+    }
+
+    for index in 0..CHUNK_VOXEL_COUNT {
+      palette.set(index, None);
+    }
+    // one-bit indices have room for an entry, so that one isn't compacted
+    assert!(palette.palette.len() <= 1);
+    assert_eq!(palette.bits, 1);
+    assert_eq!(palette.to_full(), vec![None; CHUNK_VOXEL_COUNT]);
+  }
+}
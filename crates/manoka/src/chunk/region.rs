@@ -0,0 +1,458 @@
+//! Region files, which bundle a cube of chunks into a single file.
+//!
+//! A region file starts with a header and an offset table with one entry per
+//! chunk slot, followed by `.mnk` encoded chunks. Rewriting a chunk appends
+//! the new encoding to the end of the file and repoints its table entry, so
+//! a crash mid-write never corrupts the previous copy. The space left behind
+//! is reclaimed by [`RegionFile::compact`].
+//!
+//! | bytes | contents                                           |
+//! |-------|----------------------------------------------------|
+//! | 4     | magic, `MNKR`                                      |
+//! | 2     | format version                                     |
+//! | 2     | reserved, zero                                     |
+//! | 16 *  | per slot: offset (u64), length (u32), reserved     |
+//! |       | (u32); a length of zero marks an empty slot        |
+
+use std::{
+  fmt,
+  fs::{self, File, OpenOptions},
+  io::{Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{
+  material::VoxelMaterials,
+  mnk::{decode_chunk, encode_chunk, MnkError},
+  Chunk,
+};
+
+/// The number of chunks along each edge of a region.
+pub const REGION_SIZE: usize = 8;
+const REGION_SLOT_COUNT: usize = REGION_SIZE * REGION_SIZE * REGION_SIZE;
+
+const REGION_MAGIC: &[u8; 4] = b"MNKR";
+pub const REGION_VERSION: u16 = 1;
+const ENTRY_LEN: usize = 16;
+const HEADER_LEN: usize = 8 + REGION_SLOT_COUNT * ENTRY_LEN;
+
+#[derive(Debug)]
+pub enum RegionError {
+  Io(std::io::Error),
+  /// The file does not start with the region magic.
+  InvalidMagic,
+  /// The file was written by a newer version of the format.
+  UnsupportedVersion(u16),
+  /// A slot coordinate lies outside the region.
+  InvalidSlot(UVec3),
+  /// The offset table points outside the file's chunk data, or two of its
+  /// entries overlap.
+  Corrupt,
+  /// A chunk stored in the region failed to decode.
+  Chunk(MnkError),
+}
+
+impl fmt::Display for RegionError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RegionError::Io(e) => write!(f, "region file I/O failed: {e}"),
+      RegionError::InvalidMagic => write!(f, "not a region file"),
+      RegionError::UnsupportedVersion(v) => write!(
+        f,
+        "region file has version {v}, but only up to {REGION_VERSION} is \
+         supported"
+      ),
+      RegionError::InvalidSlot(slot) => {
+        write!(f, "chunk slot {slot} is outside the region")
+      }
+      RegionError::Corrupt => write!(f, "region offset table is corrupt"),
+      RegionError::Chunk(e) => write!(f, "region chunk is invalid: {e}"),
+    }
+  }
+}
+
+impl std::error::Error for RegionError {}
+
+impl From<std::io::Error> for RegionError {
+  fn from(value: std::io::Error) -> Self { RegionError::Io(value) }
+}
+
+impl From<MnkError> for RegionError {
+  fn from(value: MnkError) -> Self { RegionError::Chunk(value) }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct RegionEntry {
+  offset: u64,
+  len:    u32,
+}
+
+/// Splits a chunk grid position into its region and its slot in that region.
+pub fn region_of(chunk_pos: IVec3) -> (IVec3, UVec3) {
+  let size = IVec3::splat(REGION_SIZE as i32);
+  (
+    chunk_pos.div_euclid(size),
+    chunk_pos.rem_euclid(size).as_uvec3(),
+  )
+}
+
+fn slot_index(slot: UVec3) -> Result<usize, RegionError> {
+  if slot.cmpge(UVec3::splat(REGION_SIZE as u32)).any() {
+    return Err(RegionError::InvalidSlot(slot));
+  }
+  let (x, y, z) = (slot.x as usize, slot.y as usize, slot.z as usize);
+  Ok(z * REGION_SIZE * REGION_SIZE + y * REGION_SIZE + x)
+}
+
+/// An open region file.
+pub struct RegionFile {
+  path:  PathBuf,
+  file:  File,
+  table: Vec<RegionEntry>,
+}
+
+impl RegionFile {
+  /// Opens the region file at `path`, creating an empty one if it doesn't
+  /// exist.
+  pub fn open(path: impl Into<PathBuf>) -> Result<Self, RegionError> {
+    let path = path.into();
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)?;
+
+    let table = match file.metadata()?.len() {
+      0 => {
+        let table = vec![RegionEntry::default(); REGION_SLOT_COUNT];
+        Self::write_header(&mut file, &table)?;
+        table
+      }
+      _ => Self::read_header(&mut file)?,
+    };
+
+    Ok(Self { path, file, table })
+  }
+
+  fn read_header(file: &mut File) -> Result<Vec<RegionEntry>, RegionError> {
+    let mut header = vec![0; HEADER_LEN];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+
+    if &header[0..4] != REGION_MAGIC {
+      return Err(RegionError::InvalidMagic);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > REGION_VERSION {
+      return Err(RegionError::UnsupportedVersion(version));
+    }
+
+    let file_len = file.metadata()?.len();
+    let table = header[8..]
+      .chunks_exact(ENTRY_LEN)
+      .map(|entry| RegionEntry {
+        offset: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+        len:    u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+      })
+      .collect::<Vec<_>>();
+
+    // every chunk must lie between the header and the end of the file,
+    // without sharing bytes with another
+    let mut ranges = table
+      .iter()
+      .filter(|entry| entry.len != 0)
+      .map(|entry| match entry.offset.checked_add(entry.len as u64) {
+        Some(end) if entry.offset >= HEADER_LEN as u64 && end <= file_len => {
+          Ok(entry.offset..end)
+        }
+        _ => Err(RegionError::Corrupt),
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    ranges.sort_unstable_by_key(|range| range.start);
+    if ranges.windows(2).any(|pair| pair[1].start < pair[0].end) {
+      return Err(RegionError::Corrupt);
+    }
+    Ok(table)
+  }
+
+  fn write_header(
+    file: &mut File,
+    table: &[RegionEntry],
+  ) -> Result<(), RegionError> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(REGION_MAGIC);
+    header.extend_from_slice(&REGION_VERSION.to_le_bytes());
+    header.extend_from_slice(&[0; 2]);
+    for entry in table {
+      header.extend_from_slice(&Self::encode_entry(entry));
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    Ok(())
+  }
+
+  fn encode_entry(entry: &RegionEntry) -> [u8; ENTRY_LEN] {
+    let mut bytes = [0; ENTRY_LEN];
+    bytes[0..8].copy_from_slice(&entry.offset.to_le_bytes());
+    bytes[8..12].copy_from_slice(&entry.len.to_le_bytes());
+    bytes
+  }
+
+  /// Rewrites the table entry of a single slot.
+  fn write_entry(&mut self, index: usize) -> Result<(), RegionError> {
+    let entry = Self::encode_entry(&self.table[index]);
+    self
+      .file
+      .seek(SeekFrom::Start((8 + index * ENTRY_LEN) as u64))?;
+    self.file.write_all(&entry)?;
+    Ok(())
+  }
+
+  /// Reads the chunk in `slot`, or `None` if the slot is empty.
+  pub fn read_chunk(
+    &mut self,
+    slot: UVec3,
+    materials: &VoxelMaterials,
+  ) -> Result<Option<Chunk>, RegionError> {
+    let entry = self.table[slot_index(slot)?];
+    if entry.len == 0 {
+      return Ok(None);
+    }
+
+    let mut bytes = vec![0; entry.len as usize];
+    self.file.seek(SeekFrom::Start(entry.offset))?;
+    self.file.read_exact(&mut bytes)?;
+    Ok(Some(decode_chunk(&bytes, materials)?))
+  }
+
+  /// Writes `chunk` to `slot`, appending it to the end of the file.
+  pub fn write_chunk(
+    &mut self,
+    slot: UVec3,
+    chunk: &Chunk,
+    materials: &VoxelMaterials,
+  ) -> Result<(), RegionError> {
+    let index = slot_index(slot)?;
+    let bytes = encode_chunk(chunk, materials);
+
+    // the data must be on disk before the table points at it
+    let offset = self.file.seek(SeekFrom::End(0))?;
+    self.file.write_all(&bytes)?;
+    self.file.sync_data()?;
+
+    self.table[index] = RegionEntry {
+      offset,
+      len: bytes.len() as u32,
+    };
+    self.write_entry(index)
+  }
+
+  /// Empties `slot`. The chunk's bytes remain until the next compaction.
+  pub fn remove_chunk(&mut self, slot: UVec3) -> Result<(), RegionError> {
+    let index = slot_index(slot)?;
+    self.table[index] = RegionEntry::default();
+    self.write_entry(index)
+  }
+
+  /// The number of bytes taken up by superseded or removed chunks.
+  pub fn wasted_bytes(&self) -> Result<u64, RegionError> {
+    let live = self.table.iter().map(|e| e.len as u64).sum::<u64>();
+    Ok(self.file.metadata()?.len() - HEADER_LEN as u64 - live)
+  }
+
+  /// Rewrites the file with only its live chunks, reclaiming the space left
+  /// by appends and removals.
+  ///
+  /// The compacted copy is written next to the original and renamed over
+  /// it, so the region stays intact if compaction is interrupted.
+  pub fn compact(&mut self) -> Result<(), RegionError> {
+    let temp_path = self.path.with_extension("mnkr.tmp");
+    let mut temp = File::create(&temp_path)?;
+
+    let mut table = vec![RegionEntry::default(); REGION_SLOT_COUNT];
+    temp.write_all(&vec![0; HEADER_LEN])?;
+    let mut offset = HEADER_LEN as u64;
+    for (index, entry) in self.table.iter().enumerate() {
+      if entry.len == 0 {
+        continue;
+      }
+      let mut bytes = vec![0; entry.len as usize];
+      self.file.seek(SeekFrom::Start(entry.offset))?;
+      self.file.read_exact(&mut bytes)?;
+      temp.write_all(&bytes)?;
+
+      table[index] = RegionEntry {
+        offset,
+        len: entry.len,
+      };
+      offset += entry.len as u64;
+    }
+    Self::write_header(&mut temp, &table)?;
+    temp.sync_all()?;
+    drop(temp);
+
+    fs::rename(&temp_path, &self.path)?;
+    *self = Self::open(self.path.clone())?;
+    Ok(())
+  }
+}
+
+/// A directory of region files making up a saved world, addressed by chunk
+/// grid position.
+pub struct RegionStore {
+  dir:       PathBuf,
+  regions:   HashMap<IVec3, RegionFile>,
+  materials: VoxelMaterials,
+}
+
+impl RegionStore {
+  pub fn new(dir: impl Into<PathBuf>, materials: VoxelMaterials) -> Self {
+    Self {
+      dir: dir.into(),
+      regions: HashMap::new(),
+      materials,
+    }
+  }
+
+  /// The path of the file holding `region`.
+  pub fn region_path(dir: &Path, region: IVec3) -> PathBuf {
+    dir.join(format!("r.{}.{}.{}.mnkr", region.x, region.y, region.z))
+  }
+
+  fn region(&mut self, region: IVec3) -> Result<&mut RegionFile, RegionError> {
+    if !self.regions.contains_key(&region) {
+      fs::create_dir_all(&self.dir)?;
+      let file = RegionFile::open(Self::region_path(&self.dir, region))?;
+      self.regions.insert(region, file);
+    }
+    Ok(self.regions.get_mut(&region).unwrap())
+  }
+
+  /// Loads the chunk at `chunk_pos`, or `None` if it has never been saved.
+  pub fn load_chunk(
+    &mut self,
+    chunk_pos: IVec3,
+  ) -> Result<Option<Chunk>, RegionError> {
+    let (region, slot) = region_of(chunk_pos);
+    if !Self::region_path(&self.dir, region).exists() {
+      return Ok(None);
+    }
+    let materials = self.materials.clone();
+    self.region(region)?.read_chunk(slot, &materials)
+  }
+
+  /// Saves `chunk` at `chunk_pos`.
+  pub fn save_chunk(
+    &mut self,
+    chunk_pos: IVec3,
+    chunk: &Chunk,
+  ) -> Result<(), RegionError> {
+    let (region, slot) = region_of(chunk_pos);
+    let materials = self.materials.clone();
+    self.region(region)?.write_chunk(slot, chunk, &materials)
+  }
+
+  /// Compacts every open region file.
+  pub fn compact(&mut self) -> Result<(), RegionError> {
+    for region in self.regions.values_mut() {
+      region.compact()?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A fresh path in the temporary directory, removed when dropped.
+  struct TempPath(PathBuf);
+
+  impl TempPath {
+    fn new(name: &str) -> Self {
+      let file = format!("manoka-{name}-{}.mnkr", std::process::id());
+      let path = std::env::temp_dir().join(file);
+      let _ = fs::remove_file(&path);
+      Self(path)
+    }
+  }
+
+  impl Drop for TempPath {
+    fn drop(&mut self) { let _ = fs::remove_file(&self.0); }
+  }
+
+  /// Points the table entry of `index` at `offset..offset + len`.
+  fn corrupt_entry(path: &Path, index: usize, offset: u64, len: u32) {
+    let mut file = OpenOptions::new().write(true).open(path).unwrap();
+    let entry = RegionFile::encode_entry(&RegionEntry { offset, len });
+    file
+      .seek(SeekFrom::Start((8 + index * ENTRY_LEN) as u64))
+      .unwrap();
+    file.write_all(&entry).unwrap();
+  }
+
+  /// A region file with chunks in slots 0 and 1, returning their entries.
+  fn write_two_chunks(path: &Path) -> [RegionEntry; 2] {
+    let materials = VoxelMaterials::default();
+    let mut region = RegionFile::open(path).unwrap();
+    let chunk = Chunk::new_empty();
+    region.write_chunk(UVec3::ZERO, &chunk, &materials).unwrap();
+    region.write_chunk(UVec3::X, &chunk, &materials).unwrap();
+    [region.table[0], region.table[1]]
+  }
+
+  #[test]
+  fn rewrites_and_compacts_chunks() {
+    let path = TempPath::new("compact");
+    let materials = VoxelMaterials::default();
+    write_two_chunks(&path.0);
+
+    let mut region = RegionFile::open(&path.0).unwrap();
+    let chunk = Chunk::new_empty();
+    region.write_chunk(UVec3::ZERO, &chunk, &materials).unwrap();
+    region.remove_chunk(UVec3::X).unwrap();
+    assert!(region.wasted_bytes().unwrap() > 0);
+
+    region.compact().unwrap();
+    assert_eq!(region.wasted_bytes().unwrap(), 0);
+    let read = region.read_chunk(UVec3::ZERO, &materials).unwrap();
+    assert_eq!(read.unwrap().into_full(), chunk.into_full());
+    assert!(region.read_chunk(UVec3::X, &materials).unwrap().is_none());
+  }
+
+  #[test]
+  fn rejects_entries_inside_the_header() {
+    let path = TempPath::new("header");
+    let [first, _] = write_two_chunks(&path.0);
+    corrupt_entry(&path.0, 0, 8, first.len);
+    assert!(matches!(
+      RegionFile::open(&path.0),
+      Err(RegionError::Corrupt)
+    ));
+  }
+
+  #[test]
+  fn rejects_overlapping_entries() {
+    let path = TempPath::new("overlap");
+    let [first, _] = write_two_chunks(&path.0);
+    corrupt_entry(&path.0, 1, first.offset + 1, first.len);
+    assert!(matches!(
+      RegionFile::open(&path.0),
+      Err(RegionError::Corrupt)
+    ));
+  }
+
+  #[test]
+  fn rejects_entries_past_the_end() {
+    let path = TempPath::new("end");
+    let [_, second] = write_two_chunks(&path.0);
+    corrupt_entry(&path.0, 1, second.offset, second.len + 1);
+    assert!(matches!(
+      RegionFile::open(&path.0),
+      Err(RegionError::Corrupt)
+    ));
+  }
+}
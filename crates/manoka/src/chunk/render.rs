@@ -0,0 +1,968 @@
+use std::{mem::size_of, ops::Range};
+
+use bevy::{
+  prelude::*,
+  render::{
+    render_resource::{
+      encase, Buffer, BufferDescriptor, ShaderDefVal, ShaderSize, ShaderType,
+      StorageBuffer,
+    },
+    renderer::{RenderDevice, RenderQueue},
+    Extract, Render, RenderApp, RenderSet,
+  },
+  utils::{HashMap, HashSet},
+};
+use wgpu::BufferUsages;
+use zerocopy::FromZeroes;
+
+use super::{
+  brickmap::{
+    BRICK_GRID_COUNT, BRICK_GRID_SIZE, BRICK_SIZE, BRICK_VOXEL_COUNT,
+    EMPTY_BRICK,
+  },
+  material::{GpuVoxelMaterials, MaterialId, VoxelMaterial, VoxelMaterials},
+  normals::NormalMode,
+  occupancy::{OCCUPANCY_MIP_LEVELS, OCCUPANCY_MIP_WORDS},
+  voxel_index, Chunk, FullVoxel, GpuChunkOccupancy,
+};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+/// Shader defs describing the chunk layout, for every shader that reads
+/// chunk buffers.
+pub fn chunk_shader_defs() -> Vec<ShaderDefVal> {
+  vec![
+    ShaderDefVal::UInt("CHUNK_SIZE".into(), CHUNK_SIZE as u32),
+    ShaderDefVal::UInt(
+      "OCCUPANCY_MIP_LEVELS".into(),
+      OCCUPANCY_MIP_LEVELS as u32,
+    ),
+    ShaderDefVal::UInt(
+      "OCCUPANCY_MIP_WORDS".into(),
+      OCCUPANCY_MIP_WORDS as u32,
+    ),
+    ShaderDefVal::UInt("BRICK_SIZE".into(), BRICK_SIZE as u32),
+  ]
+}
+
+/// Chunk assets that changed in the main world this frame.
+#[derive(Resource, Default)]
+pub struct ExtractedChunks {
+  changed: Vec<(AssetId<Chunk>, ChunkUpdate)>,
+  removed: Vec<AssetId<Chunk>>,
+}
+
+fn extract_chunk_assets(
+  mut commands: Commands,
+  mut events: Extract<EventReader<AssetEvent<Chunk>>>,
+  chunks: Extract<Res<Assets<Chunk>>>,
+  gpu_chunks: Res<GpuChunks>,
+) {
+  let mut changed = HashSet::new();
+  let mut removed = Vec::new();
+  for event in events.read() {
+    match event {
+      AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+        changed.insert(*id);
+      }
+      AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+        changed.remove(id);
+        removed.push(*id);
+      }
+      AssetEvent::LoadedWithDependencies { .. } => {}
+    }
+  }
+
+  // a chunk removed and added again starts from an empty mirror
+  let changed = changed
+    .into_iter()
+    .filter_map(|id| {
+      let mirror = gpu_chunks
+        .chunks
+        .get(&id)
+        .filter(|_| !removed.contains(&id))
+        .map(|gpu_chunk| &gpu_chunk.mirror);
+      Some((id, ChunkUpdate::new(chunks.get(id)?, mirror)?))
+    })
+    .collect();
+  commands.insert_resource(ExtractedChunks { changed, removed });
+}
+
+/// The voxels of one brick in brick-local dense order, or `None` if the
+/// brick is empty.
+type BrickVoxels = Option<Vec<Option<FullVoxel>>>;
+
+/// What the render world needs to bring its mirror of a chunk up to date.
+pub struct ChunkUpdate {
+  lineage:   u64,
+  revision:  u64,
+  /// The whole occupancy, when the mirror is of another lineage.
+  occupancy: Option<Box<GpuChunkOccupancy>>,
+  /// The bricks edited since the mirror's revision, by grid cell.
+  bricks:    Vec<(usize, BrickVoxels)>,
+}
+
+impl ChunkUpdate {
+  /// The update from `mirror` to `chunk`, or `None` if `mirror` is already
+  /// up to date. Without a mirror of the same lineage, every brick is sent.
+  fn new(chunk: &Chunk, mirror: Option<&ChunkMirror>) -> Option<Self> {
+    let revisions = chunk.revisions();
+    let (occupancy, bricks) = match mirror {
+      Some(mirror) if mirror.lineage == revisions.lineage() => {
+        if mirror.revision == revisions.latest() {
+          return None;
+        }
+        let bricks = revisions
+          .edited_since(mirror.revision)
+          .map(|cell| {
+            let voxel = |pos| chunk.get(pos).expect("bricks lie in the chunk");
+            (cell, gather_brick(cell, voxel))
+          })
+          .collect();
+        (None, bricks)
+      }
+      _ => {
+        let voxels = chunk.dense_refs();
+        let bricks = (0..BRICK_GRID_COUNT)
+          .map(|cell| {
+            (cell, gather_brick(cell, |pos| voxels[voxel_index(pos)]))
+          })
+          .collect();
+        (Some(chunk.prepare_occupancy()), bricks)
+      }
+    };
+
+    Some(Self {
+      lineage: revisions.lineage(),
+      revision: revisions.latest(),
+      occupancy,
+      bricks,
+    })
+  }
+}
+
+/// The render world's copy of a chunk, patched by each [`ChunkUpdate`].
+pub struct ChunkMirror {
+  pub occupancy: Box<GpuChunkOccupancy>,
+  pub grid:      BrickGrid,
+  /// The lineage and revision of [`BrickRevisions`] the mirror matches. No
+  /// chunk has lineage 0.
+  ///
+  /// [`BrickRevisions`]: super::BrickRevisions
+  lineage:       u64,
+  revision:      u64,
+  /// The occupancy words changed since the last upload, counting the base
+  /// mask's words first and then the mips'.
+  dirty:         Vec<Range<usize>>,
+}
+
+impl Default for ChunkMirror {
+  fn default() -> Self {
+    Self {
+      occupancy: GpuChunkOccupancy::new_box_zeroed(),
+      grid:      BrickGrid::default(),
+      lineage:   0,
+      revision:  0,
+      dirty:     Vec::new(),
+    }
+  }
+}
+
+impl ChunkMirror {
+  fn apply(&mut self, update: ChunkUpdate, pool: &mut BrickPool) {
+    match update.occupancy {
+      Some(occupancy) => {
+        self.occupancy = occupancy;
+        self.mark_all();
+      }
+      None => {
+        for (cell, voxels) in update.bricks.iter() {
+          self.patch_occupancy(*cell, voxels.as_deref());
+        }
+      }
+    }
+
+    // bricks whose voxels didn't change aren't written again
+    for (cell, voxels) in update.bricks {
+      pool.write_brick(&mut self.grid, cell, voxels);
+    }
+    self.lineage = update.lineage;
+    self.revision = update.revision;
+  }
+
+  /// Writes the occupancy of the brick in grid cell `cell`, and the mip
+  /// nodes above it.
+  fn patch_occupancy(
+    &mut self,
+    cell: usize,
+    voxels: Option<&[Option<FullVoxel>]>,
+  ) {
+    let origin = brick_origin(cell);
+    let size = BRICK_SIZE as u32;
+    let mut local = 0;
+    for z in 0..size {
+      for y in 0..size {
+        for x in 0..size {
+          let occupied = voxels.is_some_and(|voxels| voxels[local].is_some());
+          self
+            .occupancy
+            .set_voxel(origin + UVec3::new(x, y, z), occupied);
+          local += 1;
+        }
+      }
+    }
+
+    let max = origin + UVec3::splat(size - 1);
+    let mips = self.occupancy.update_mips(origin, max);
+    let mask_words = CHUNK_VOXEL_COUNT / 32;
+    self
+      .dirty
+      .push(voxel_index(origin) / 32..voxel_index(max) / 32 + 1);
+    self
+      .dirty
+      .push(mask_words + mips.start..mask_words + mips.end);
+  }
+
+  /// Marks the whole occupancy as changed.
+  fn mark_all(&mut self) {
+    self.dirty.clear();
+    self.dirty.push(0..OCCUPANCY_WORDS);
+  }
+
+  /// The occupancy words changed since the last call, merged into
+  /// ascending, disjoint ranges.
+  fn take_dirty(&mut self) -> Vec<Range<usize>> {
+    let mut dirty = std::mem::take(&mut self.dirty);
+    dirty.sort_unstable_by_key(|words| words.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(dirty.len());
+    for words in dirty {
+      match merged.last_mut() {
+        Some(last) if words.start <= last.end => {
+          last.end = last.end.max(words.end);
+        }
+        _ => merged.push(words),
+      }
+    }
+    merged
+  }
+}
+
+/// The number of words of a [`GpuChunkOccupancy`], mask and mips together.
+const OCCUPANCY_WORDS: usize = CHUNK_VOXEL_COUNT / 32 + OCCUPANCY_MIP_WORDS;
+
+/// The bytes of `occupancy`'s words in `words`, counting the base mask's
+/// words first and then the mips'.
+fn occupancy_bytes(
+  occupancy: &GpuChunkOccupancy,
+  words: Range<usize>,
+) -> Vec<u8> {
+  occupancy
+    .occupancy
+    .iter()
+    .chain(occupancy.mips.iter())
+    .skip(words.start)
+    .take(words.len())
+    .flat_map(|word| word.to_le_bytes())
+    .collect()
+}
+
+/// The render world copy of a chunk: its mirror, with its occupancy in
+/// [`GpuChunks`]' occupancy buffer and its voxels in [`BrickPool`].
+pub struct GpuChunk {
+  pub mirror:      ChunkMirror,
+  /// `mirror.grid` on the GPU.
+  pub grid_buffer: Buffer,
+  /// The chunk's entry in the occupancy buffer.
+  pub slot:        u32,
+}
+
+impl GpuChunk {
+  /// Writes the range of grid cells changed since the last upload.
+  fn upload_grid(&mut self, render_queue: &RenderQueue) {
+    let grid = &mut self.mirror.grid;
+    let Some(dirty) = grid.dirty.take() else {
+      return;
+    };
+    let bytes = grid.cells[dirty.clone()]
+      .iter()
+      .flat_map(|brick| brick.to_le_bytes())
+      .collect::<Vec<_>>();
+    render_queue.write_buffer(
+      &self.grid_buffer,
+      (dirty.start * size_of::<u32>()) as u64,
+      &bytes,
+    );
+  }
+
+  /// The bytes of GPU memory held for the chunk's occupancy, grid and
+  /// bricks.
+  pub fn memory_usage(&self, pool: &BrickPool) -> u64 {
+    GpuChunkOccupancy::min_size().get()
+      + self.grid_buffer.size()
+      + self.mirror.grid.brick_count() as u64 * pool.brick_bytes()
+  }
+}
+
+/// Serializes `values` with the layout of a storage array.
+fn storage_bytes<T>(values: Vec<T>) -> Vec<u8>
+where
+  Vec<T>: ShaderType + encase::internal::WriteInto,
+{
+  let mut buffer = encase::StorageBuffer::new(Vec::new());
+  buffer
+    .write(&values)
+    .expect("failed to serialize chunk voxels");
+  buffer.into_inner()
+}
+
+/// Every chunk asset that has been uploaded to the GPU, with the occupancy
+/// of all of them in one buffer.
+#[derive(Resource, Default)]
+pub struct GpuChunks {
+  chunks:           HashMap<AssetId<Chunk>, GpuChunk>,
+  /// The occupancy of each chunk at its slot. Slot 0 is always empty, to
+  /// stand in when no chunks are rendered.
+  occupancy_buffer: Option<Buffer>,
+  /// The number of slots `occupancy_buffer` has room for.
+  capacity:         usize,
+  /// The highest slot allocated.
+  last_slot:        u32,
+  free_slots:       Vec<u32>,
+}
+
+impl GpuChunks {
+  pub fn get(&self, id: AssetId<Chunk>) -> Option<&GpuChunk> {
+    self.chunks.get(&id)
+  }
+
+  /// The occupancy buffer, once it has been uploaded.
+  pub fn occupancy_buffer(&self) -> Option<&Buffer> {
+    self.occupancy_buffer.as_ref()
+  }
+
+  /// The GPU memory held by every uploaded chunk. Entities sharing a chunk
+  /// asset share its upload, so each asset is counted once.
+  pub fn memory_usage(&self, pool: &BrickPool) -> u64 {
+    self.chunks.values().map(|c| c.memory_usage(pool)).sum()
+  }
+
+  fn alloc_slot(&mut self) -> u32 {
+    self.free_slots.pop().unwrap_or_else(|| {
+      self.last_slot += 1;
+      self.last_slot
+    })
+  }
+
+  /// Writes the occupancy words changed since the last upload, or every
+  /// chunk's occupancy if the chunks outgrew the buffer.
+  fn upload_occupancy(
+    &mut self,
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+  ) {
+    let slot_size = GpuChunkOccupancy::min_size().get();
+    let needed = self.last_slot as usize + 1;
+    if self.occupancy_buffer.is_none() || needed > self.capacity {
+      self.capacity = needed.next_power_of_two();
+      debug!(
+        "allocating chunk occupancy buffer: {} slots, {:.1} KiB",
+        self.capacity,
+        (self.capacity as u64 * slot_size) as f32 / 1024.0
+      );
+      // new buffers are zeroed, which leaves slot 0 empty
+      self.occupancy_buffer =
+        Some(render_device.create_buffer(&BufferDescriptor {
+          label:              Some("chunk_occupancy_buffer"),
+          size:               self.capacity as u64 * slot_size,
+          usage:              BufferUsages::STORAGE | BufferUsages::COPY_DST,
+          mapped_at_creation: false,
+        }));
+      for gpu_chunk in self.chunks.values_mut() {
+        gpu_chunk.mirror.mark_all();
+      }
+    }
+    let Some(buffer) = &self.occupancy_buffer else {
+      return;
+    };
+
+    for gpu_chunk in self.chunks.values_mut() {
+      let slot_offset = gpu_chunk.slot as u64 * slot_size;
+      for words in gpu_chunk.mirror.take_dirty() {
+        render_queue.write_buffer(
+          buffer,
+          slot_offset + (words.start * size_of::<u32>()) as u64,
+          &occupancy_bytes(&gpu_chunk.mirror.occupancy, words),
+        );
+      }
+    }
+  }
+}
+
+fn prepare_chunk_assets(
+  mut extracted: ResMut<ExtractedChunks>,
+  mut gpu_chunks: ResMut<GpuChunks>,
+  mut brick_pool: ResMut<BrickPool>,
+  render_device: Res<RenderDevice>,
+  render_queue: Res<RenderQueue>,
+) {
+  for id in extracted.removed.drain(..) {
+    if let Some(mut gpu_chunk) = gpu_chunks.chunks.remove(&id) {
+      brick_pool.free_grid(&mut gpu_chunk.mirror.grid);
+      gpu_chunks.free_slots.push(gpu_chunk.slot);
+    }
+  }
+
+  for (id, update) in extracted.changed.drain(..) {
+    if !gpu_chunks.chunks.contains_key(&id) {
+      let slot = gpu_chunks.alloc_slot();
+      gpu_chunks.chunks.insert(id, GpuChunk {
+        mirror: ChunkMirror::default(),
+        grid_buffer: render_device.create_buffer(&BufferDescriptor {
+          label:              Some("chunk_brick_grid_buffer"),
+          size:               (BRICK_GRID_COUNT * size_of::<u32>()) as u64,
+          usage:              BufferUsages::STORAGE | BufferUsages::COPY_DST,
+          mapped_at_creation: false,
+        }),
+        slot,
+      });
+    }
+    let gpu_chunk = gpu_chunks.chunks.get_mut(&id).unwrap();
+
+    let bricks = update.bricks.len();
+    gpu_chunk.mirror.apply(update, &mut brick_pool);
+    gpu_chunk.upload_grid(&render_queue);
+
+    debug!(
+      "updated `GpuChunk` for {id:?}: {bricks} bricks sent, {:.1} KiB on the \
+       GPU",
+      gpu_chunk.memory_usage(&brick_pool) as f32 / 1024.0,
+    );
+  }
+
+  gpu_chunks.upload_occupancy(&render_device, &render_queue);
+}
+
+/// The pool index of each brick of a chunk, indexed by grid cell, with
+/// [`EMPTY_BRICK`] for bricks without voxels.
+pub struct BrickGrid {
+  pub cells: Vec<u32>,
+  /// The cells changed since the grid was last uploaded.
+  dirty:     Option<Range<usize>>,
+}
+
+impl Default for BrickGrid {
+  fn default() -> Self {
+    Self {
+      cells: vec![EMPTY_BRICK; BRICK_GRID_COUNT],
+      dirty: Some(0..BRICK_GRID_COUNT),
+    }
+  }
+}
+
+impl BrickGrid {
+  /// The number of occupied bricks.
+  pub fn brick_count(&self) -> usize {
+    self
+      .cells
+      .iter()
+      .filter(|brick| **brick != EMPTY_BRICK)
+      .count()
+  }
+
+  fn set(&mut self, cell: usize, brick: u32) {
+    self.cells[cell] = brick;
+    self.dirty = Some(match self.dirty.take() {
+      Some(dirty) => dirty.start.min(cell)..dirty.end.max(cell + 1),
+      None => cell..cell + 1,
+    });
+  }
+}
+
+/// The voxels of every occupied brick of every uploaded chunk, allocated
+/// from one shared pool.
+///
+/// Each chunk's [`BrickGrid`] points into the pool, so empty space takes no
+/// pool memory. Each brick holds [`BRICK_VOXEL_COUNT`] voxels in brick-local
+/// dense order, with empty voxels zeroed. Freed bricks are reused by the
+/// next brick allocated, and only bricks written since the last upload are
+/// uploaded again.
+///
+/// Materials and normals are uploaded to separate buffers, and normals only
+/// under [`NormalMode::Stored`], since shaders derive them otherwise.
+#[derive(Resource, Default)]
+pub struct BrickPool {
+  /// The voxels of every brick, as uploaded.
+  voxels:          Vec<FullVoxel>,
+  material_buffer: Option<Buffer>,
+  normal_buffer:   Option<Buffer>,
+  /// The number of bricks the buffers have room for.
+  capacity:        usize,
+  free:            Vec<u32>,
+  /// Bricks written since the last upload.
+  dirty:           Vec<u32>,
+}
+
+impl BrickPool {
+  const MATERIAL_BRICK_BYTES: u64 =
+    BRICK_VOXEL_COUNT as u64 * MaterialId::SHADER_SIZE.get();
+  /// Elements of a storage array of `vec3` are padded to a `vec4`.
+  const NORMAL_BRICK_BYTES: u64 =
+    BRICK_VOXEL_COUNT as u64 * Vec4::SHADER_SIZE.get();
+
+  /// The material of every voxel, once the pool has been uploaded.
+  pub fn material_buffer(&self) -> Option<&Buffer> {
+    self.material_buffer.as_ref()
+  }
+
+  /// The stored normal of every voxel, zero where a voxel has none. Only
+  /// uploaded under [`NormalMode::Stored`].
+  pub fn normal_buffer(&self) -> Option<&Buffer> { self.normal_buffer.as_ref() }
+
+  /// The GPU memory each brick takes.
+  fn brick_bytes(&self) -> u64 {
+    match self.normal_buffer {
+      Some(_) => Self::MATERIAL_BRICK_BYTES + Self::NORMAL_BRICK_BYTES,
+      None => Self::MATERIAL_BRICK_BYTES,
+    }
+  }
+
+  /// The voxels of brick `brick`, as uploaded.
+  pub fn brick(&self, brick: u32) -> &[FullVoxel] {
+    let start = brick as usize * BRICK_VOXEL_COUNT;
+    &self.voxels[start..start + BRICK_VOXEL_COUNT]
+  }
+
+  /// The number of bricks allocated, including free ones.
+  fn len(&self) -> usize { self.voxels.len() / BRICK_VOXEL_COUNT }
+
+  /// The GPU memory held by the pool, including free bricks.
+  pub fn memory_usage(&self) -> u64 {
+    self.capacity as u64 * self.brick_bytes()
+  }
+
+  /// Stores the voxels of `grid`'s cell `cell`, allocating its brick if
+  /// needed and freeing it if `voxels` is `None`. Unchanged bricks aren't
+  /// written.
+  fn write_brick(
+    &mut self,
+    grid: &mut BrickGrid,
+    cell: usize,
+    voxels: BrickVoxels,
+  ) {
+    let voxels = voxels.map(|voxels| {
+      let voxels = voxels.into_iter();
+      voxels
+        .map(|v| v.unwrap_or_else(empty_voxel))
+        .collect::<Vec<_>>()
+    });
+    let (brick, voxels) = match (grid.cells[cell], voxels) {
+      (EMPTY_BRICK, None) => return,
+      (brick, None) => {
+        self.free.push(brick);
+        grid.set(cell, EMPTY_BRICK);
+        return;
+      }
+      (EMPTY_BRICK, Some(voxels)) => {
+        let brick = self.alloc();
+        grid.set(cell, brick);
+        (brick, voxels)
+      }
+      (brick, Some(voxels)) if self.brick(brick) == voxels => return,
+      (brick, Some(voxels)) => (brick, voxels),
+    };
+
+    let start = brick as usize * BRICK_VOXEL_COUNT;
+    self.voxels[start..start + BRICK_VOXEL_COUNT].clone_from_slice(&voxels);
+    self.dirty.push(brick);
+  }
+
+  fn alloc(&mut self) -> u32 {
+    self.free.pop().unwrap_or_else(|| {
+      self
+        .voxels
+        .resize(self.voxels.len() + BRICK_VOXEL_COUNT, empty_voxel());
+      self.len() as u32 - 1
+    })
+  }
+
+  /// Returns every brick of `grid` to the pool.
+  fn free_grid(&mut self, grid: &mut BrickGrid) {
+    for cell in 0..BRICK_GRID_COUNT {
+      self.write_brick(grid, cell, None);
+    }
+  }
+
+  /// Writes the bricks changed since the last upload, or the whole pool if
+  /// it outgrew its buffers or normals start being uploaded.
+  fn upload(
+    &mut self,
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+    normal_mode: NormalMode,
+  ) {
+    let create_buffer = |label, size| {
+      render_device.create_buffer(&BufferDescriptor {
+        label: Some(label),
+        size,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+      })
+    };
+
+    // storage bindings can't be empty, so the buffers always have a brick
+    let needed = self.len().max(1);
+    let grown = self.material_buffer.is_none() || needed > self.capacity;
+    if grown {
+      self.capacity = needed.next_power_of_two();
+      self.material_buffer = Some(create_buffer(
+        "brick_pool_material_buffer",
+        self.capacity as u64 * Self::MATERIAL_BRICK_BYTES,
+      ));
+    }
+    let stored_normals = normal_mode == NormalMode::Stored;
+    let normals_added =
+      stored_normals && (grown || self.normal_buffer.is_none());
+    self.normal_buffer = match stored_normals {
+      true if normals_added => Some(create_buffer(
+        "brick_pool_normal_buffer",
+        self.capacity as u64 * Self::NORMAL_BRICK_BYTES,
+      )),
+      true => self.normal_buffer.take(),
+      false => None,
+    };
+    if grown || normals_added {
+      debug!(
+        "allocating brick pool: {} bricks, {:.1} KiB",
+        self.capacity,
+        self.memory_usage() as f32 / 1024.0
+      );
+      self.dirty = (0..self.len() as u32).collect();
+    }
+    let Some(material_buffer) = &self.material_buffer else {
+      return;
+    };
+
+    // contiguous runs of dirty bricks are written together
+    self.dirty.sort_unstable();
+    self.dirty.dedup();
+    let mut bricks = self.dirty.iter().copied().peekable();
+    while let Some(first) = bricks.next() {
+      let mut end = first + 1;
+      while bricks.next_if_eq(&end).is_some() {
+        end += 1;
+      }
+      let voxels = &self.voxels
+        [first as usize * BRICK_VOXEL_COUNT..end as usize * BRICK_VOXEL_COUNT];
+      let materials = voxels.iter().map(|v| v.material).collect();
+      render_queue.write_buffer(
+        material_buffer,
+        first as u64 * Self::MATERIAL_BRICK_BYTES,
+        &storage_bytes::<MaterialId>(materials),
+      );
+      if let Some(normal_buffer) = &self.normal_buffer {
+        let normals = voxels.iter().map(|v| v.normal.unwrap_or(Vec3::ZERO));
+        render_queue.write_buffer(
+          normal_buffer,
+          first as u64 * Self::NORMAL_BRICK_BYTES,
+          &storage_bytes::<Vec3>(normals.collect()),
+        );
+      }
+    }
+    self.dirty.clear();
+  }
+}
+
+/// What the pool holds for empty voxels of occupied bricks.
+fn empty_voxel() -> FullVoxel { FullVoxel::new(Vec3::ZERO, 0) }
+
+/// The chunk position of the minimum corner of grid cell `cell`.
+fn brick_origin(cell: usize) -> UVec3 {
+  UVec3::new(
+    (cell % BRICK_GRID_SIZE) as u32,
+    (cell / BRICK_GRID_SIZE % BRICK_GRID_SIZE) as u32,
+    (cell / (BRICK_GRID_SIZE * BRICK_GRID_SIZE)) as u32,
+  ) * BRICK_SIZE as u32
+}
+
+/// The voxels of grid cell `cell`, looking each one up with `voxel`.
+fn gather_brick<'a>(
+  cell: usize,
+  voxel: impl Fn(UVec3) -> Option<&'a FullVoxel>,
+) -> BrickVoxels {
+  let origin = brick_origin(cell);
+  let size = BRICK_SIZE as u32;
+  let mut occupied = false;
+  let mut brick = Vec::with_capacity(BRICK_VOXEL_COUNT);
+  for z in 0..size {
+    for y in 0..size {
+      for x in 0..size {
+        let voxel = voxel(origin + UVec3::new(x, y, z));
+        occupied |= voxel.is_some();
+        brick.push(voxel.cloned());
+      }
+    }
+  }
+  occupied.then_some(brick)
+}
+
+fn prepare_brick_pool(
+  mut pool: ResMut<BrickPool>,
+  render_device: Res<RenderDevice>,
+  render_queue: Res<RenderQueue>,
+  normal_mode: Res<NormalMode>,
+) {
+  pool.upload(&render_device, &render_queue, *normal_mode);
+}
+
+/// The material table, if it changed since it was last uploaded.
+#[derive(Resource, Default)]
+pub struct ExtractedMaterials(Option<(u64, Vec<VoxelMaterial>)>);
+
+fn extract_materials(
+  materials: Extract<Res<VoxelMaterials>>,
+  gpu_materials: Res<GpuMaterials>,
+  mut extracted: ResMut<ExtractedMaterials>,
+) {
+  let revision = materials.revision();
+  extracted.0 = (gpu_materials.revision != Some(revision))
+    .then(|| (revision, materials.snapshot()));
+}
+
+/// The material table on the GPU, shared by every chunk.
+#[derive(Resource, Default)]
+pub struct GpuMaterials {
+  pub buffer: StorageBuffer<GpuVoxelMaterials>,
+  revision:   Option<u64>,
+}
+
+fn prepare_materials(
+  mut extracted: ResMut<ExtractedMaterials>,
+  mut gpu_materials: ResMut<GpuMaterials>,
+  render_device: Res<RenderDevice>,
+  render_queue: Res<RenderQueue>,
+) {
+  let Some((revision, mut materials)) = extracted.0.take() else {
+    return;
+  };
+  // storage bindings can't be empty
+  if materials.is_empty() {
+    materials.push(VoxelMaterial::default());
+  }
+
+  debug!("uploading {} voxel materials", materials.len());
+  gpu_materials.buffer.set(GpuVoxelMaterials { materials });
+  gpu_materials
+    .buffer
+    .write_buffer(&render_device, &render_queue);
+  gpu_materials.revision = Some(revision);
+}
+
+pub struct ChunkRenderPlugin;
+
+impl Plugin for ChunkRenderPlugin {
+  fn build(&self, app: &mut App) {
+    let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+      panic!("render_app not found");
+    };
+
+    render_app
+      .init_resource::<ExtractedChunks>()
+      .init_resource::<GpuChunks>()
+      .init_resource::<ExtractedMaterials>()
+      .init_resource::<GpuMaterials>()
+      .init_resource::<BrickPool>()
+      .init_resource::<NormalMode>()
+      .add_systems(ExtractSchedule, (extract_chunk_assets, extract_materials))
+      .add_systems(
+        Render,
+        (
+          prepare_chunk_assets,
+          prepare_brick_pool.after(prepare_chunk_assets),
+          prepare_materials,
+        )
+          .in_set(RenderSet::PrepareAssets),
+      );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::{
+    test_utils::{random_blocks, random_voxel},
+    voxel_pos, ChunkData,
+  };
+
+  /// The voxel at `pos` as the direct pass reads it through the grid.
+  fn pool_voxel(pool: &BrickPool, grid: &BrickGrid, pos: UVec3) -> FullVoxel {
+    let (cell, local) = (pos / BRICK_SIZE as u32, pos % BRICK_SIZE as u32);
+    let (grid_size, size) = (BRICK_GRID_SIZE as u32, BRICK_SIZE as u32);
+    let cell = (cell.z * grid_size + cell.y) * grid_size + cell.x;
+    match grid.cells[cell as usize] {
+      EMPTY_BRICK => empty_voxel(),
+      brick => {
+        let local = (local.z * size + local.y) * size + local.x;
+        pool.brick(brick)[local as usize].clone()
+      }
+    }
+  }
+
+  fn write_chunk(
+    pool: &mut BrickPool,
+    grid: &mut BrickGrid,
+    data: &[Option<FullVoxel>],
+  ) {
+    for cell in 0..BRICK_GRID_COUNT {
+      let brick = gather_brick(cell, |pos| data[voxel_index(pos)].as_ref());
+      pool.write_brick(grid, cell, brick);
+    }
+  }
+
+  fn assert_pool_matches(
+    pool: &BrickPool,
+    grid: &BrickGrid,
+    data: &[Option<FullVoxel>],
+  ) {
+    for (i, voxel) in data.iter().enumerate() {
+      let expected = voxel.clone().unwrap_or_else(empty_voxel);
+      assert_eq!(pool_voxel(pool, grid, voxel_pos(i)), expected);
+    }
+  }
+
+  #[test]
+  fn pool_holds_every_occupied_brick() {
+    let mut rng = fastrand::Rng::with_seed(1);
+    let data = random_blocks(&mut rng, 4, 0.2, 3);
+    let (mut pool, mut grid) = (BrickPool::default(), BrickGrid::default());
+    write_chunk(&mut pool, &mut grid, &data);
+
+    assert_pool_matches(&pool, &grid, &data);
+    assert_eq!(pool.len(), grid.brick_count());
+    assert_eq!(pool.dirty.len(), grid.brick_count());
+  }
+
+  #[test]
+  fn rewrites_only_changed_bricks() {
+    let mut rng = fastrand::Rng::with_seed(2);
+    let mut data = random_blocks(&mut rng, 2, 0.5, 3);
+    let (mut pool, mut grid) = (BrickPool::default(), BrickGrid::default());
+    write_chunk(&mut pool, &mut grid, &data);
+    pool.dirty.clear();
+    grid.dirty = None;
+
+    // one voxel changes in an occupied brick
+    let (i, _) = data.iter().enumerate().find(|(_, v)| v.is_some()).unwrap();
+    data[i] = Some(FullVoxel::new(Vec3::X, 7));
+    write_chunk(&mut pool, &mut grid, &data);
+
+    assert_pool_matches(&pool, &grid, &data);
+    assert_eq!(pool.dirty.len(), 1);
+    assert_eq!(grid.dirty, None);
+  }
+
+  #[test]
+  fn freed_bricks_are_reused() {
+    let mut rng = fastrand::Rng::with_seed(3);
+    let data = random_blocks(&mut rng, 4, 0.3, 3);
+    let (mut pool, mut first) = (BrickPool::default(), BrickGrid::default());
+    write_chunk(&mut pool, &mut first, &data);
+    let allocated = pool.len();
+
+    pool.free_grid(&mut first);
+    assert_eq!(first.brick_count(), 0);
+    assert_eq!(pool.free.len(), allocated);
+
+    let mut second = BrickGrid::default();
+    write_chunk(&mut pool, &mut second, &data);
+    assert_eq!(pool.len(), allocated);
+    assert!(pool.free.is_empty());
+    assert_pool_matches(&pool, &second, &data);
+  }
+
+  /// Copies the mirror's dirty occupancy words into `uploaded`, like
+  /// [`GpuChunks::upload_occupancy`] does into the chunk's slot.
+  fn upload(mirror: &mut ChunkMirror, uploaded: &mut [u8]) {
+    for words in mirror.take_dirty() {
+      let bytes = words.start * 4..words.end * 4;
+      uploaded[bytes]
+        .copy_from_slice(&occupancy_bytes(&mirror.occupancy, words));
+    }
+  }
+
+  #[test]
+  fn patched_mirror_matches_a_rebuild() {
+    let mut rng = fastrand::Rng::with_seed(4);
+    let full = Chunk::new(ChunkData::Full {
+      data: random_blocks(&mut rng, 4, 0.3, 3),
+    });
+    let size = CHUNK_SIZE as u32;
+
+    for mut chunk in [
+      full.to_full(),
+      full.to_palette(),
+      full.to_octree(),
+      full.to_brickmap(),
+    ] {
+      let (mut pool, mut mirror) =
+        (BrickPool::default(), ChunkMirror::default());
+      let mut uploaded = vec![0; OCCUPANCY_WORDS * 4];
+      mirror.apply(ChunkUpdate::new(&chunk, Some(&mirror)).unwrap(), &mut pool);
+      upload(&mut mirror, &mut uploaded);
+
+      for _ in 0..4 {
+        for _ in 0..16 {
+          let pos =
+            UVec3::new(rng.u32(0..size), rng.u32(0..size), rng.u32(0..size));
+          match rng.bool() {
+            true => chunk.set(pos, random_voxel(&mut rng, 3)).unwrap(),
+            false => chunk.clear(pos).unwrap(),
+          }
+        }
+        let update = ChunkUpdate::new(&chunk, Some(&mirror)).unwrap();
+        assert!(update.occupancy.is_none());
+        assert!(update.bricks.len() <= 16);
+        mirror.apply(update, &mut pool);
+        upload(&mut mirror, &mut uploaded);
+      }
+      assert!(ChunkUpdate::new(&chunk, Some(&mirror)).is_none());
+
+      let (mut rebuilt_pool, mut rebuilt) =
+        (BrickPool::default(), ChunkMirror::default());
+      rebuilt.apply(ChunkUpdate::new(&chunk, None).unwrap(), &mut rebuilt_pool);
+      assert_eq!(mirror.occupancy, rebuilt.occupancy);
+      assert_eq!(
+        uploaded,
+        occupancy_bytes(&rebuilt.occupancy, 0..OCCUPANCY_WORDS)
+      );
+      assert_eq!(mirror.grid.brick_count(), rebuilt.grid.brick_count());
+      assert_pool_matches(&pool, &mirror.grid, &chunk.into_full());
+    }
+  }
+
+  #[test]
+  fn clones_are_sent_whole() {
+    let chunk = Chunk::new_empty();
+    let (mut pool, mut mirror) = (BrickPool::default(), ChunkMirror::default());
+    mirror.apply(ChunkUpdate::new(&chunk, None).unwrap(), &mut pool);
+    assert!(ChunkUpdate::new(&chunk, Some(&mirror)).is_none());
+
+    let update = ChunkUpdate::new(&chunk.clone(), Some(&mirror)).unwrap();
+    assert!(update.occupancy.is_some());
+    assert_eq!(update.bricks.len(), BRICK_GRID_COUNT);
+  }
+
+  #[test]
+  fn raw_writes_are_sent_whole() {
+    let mut chunk = Chunk::new_empty();
+    let (mut pool, mut mirror) = (BrickPool::default(), ChunkMirror::default());
+    mirror.apply(ChunkUpdate::new(&chunk, None).unwrap(), &mut pool);
+
+    let ChunkData::Full { data } = chunk.data_mut() else {
+      unreachable!();
+    };
+    data[0] = Some(FullVoxel::new(Vec3::Y, 0));
+    let update = ChunkUpdate::new(&chunk, Some(&mirror)).unwrap();
+    assert!(update.occupancy.is_some());
+    mirror.apply(update, &mut pool);
+    assert_pool_matches(&pool, &mirror.grid, &chunk.into_full());
+  }
+}
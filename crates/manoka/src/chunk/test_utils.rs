@@ -0,0 +1,67 @@
+//! Chunk data for tests.
+
+use bevy::prelude::*;
+
+use super::{voxel_pos, FullVoxel};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+/// Dense chunk data with about `density` of the voxels occupied, each by one
+/// of `variety` distinct voxels.
+pub fn random_voxels(
+  rng: &mut fastrand::Rng,
+  density: f32,
+  variety: u32,
+) -> Vec<Option<FullVoxel>> {
+  (0..CHUNK_VOXEL_COUNT)
+    .map(|_| (rng.f32() < density).then(|| random_voxel(rng, variety)))
+    .collect()
+}
+
+/// Dense chunk data made of aligned `block`³ cubes, each either empty or
+/// filled with one of `variety` distinct voxels, so that sparse
+/// representations have uniform regions to collapse.
+pub fn random_blocks(
+  rng: &mut fastrand::Rng,
+  block: u32,
+  density: f32,
+  variety: u32,
+) -> Vec<Option<FullVoxel>> {
+  let blocks = CHUNK_SIZE as u32 / block;
+  let cubes = (0..blocks * blocks * blocks)
+    .map(|_| (rng.f32() < density).then(|| random_voxel(rng, variety)))
+    .collect::<Vec<_>>();
+  (0..CHUNK_VOXEL_COUNT)
+    .map(|i| {
+      let cube = voxel_pos(i) / block;
+      cubes[((cube.z * blocks + cube.y) * blocks + cube.x) as usize].clone()
+    })
+    .collect()
+}
+
+/// One of `variety` distinct voxels.
+pub fn random_voxel(rng: &mut fastrand::Rng, variety: u32) -> FullVoxel {
+  FullVoxel::new(Vec3::Y, rng.u32(0..variety))
+}
+
+/// The occupancy mask of dense chunk data.
+pub fn dense_mask(data: &[Option<FullVoxel>]) -> [u32; CHUNK_VOXEL_COUNT / 32] {
+  let mut mask = [0; CHUNK_VOXEL_COUNT / 32];
+  for (i, voxel) in data.iter().enumerate() {
+    if voxel.is_some() {
+      mask[i / 32] |= 1 << (i % 32);
+    }
+  }
+  mask
+}
+
+/// A ray from somewhere around the chunk towards a point inside it, in
+/// chunk coordinates.
+pub fn random_ray(rng: &mut fastrand::Rng) -> (Vec3, Vec3) {
+  let size = CHUNK_SIZE as f32;
+  let mut point = |lo: f32, hi: f32| {
+    Vec3::new(rng.f32(), rng.f32(), rng.f32()) * (hi - lo) + lo
+  };
+  let origin = point(-size / 2.0, size * 1.5);
+  let target = point(0.0, size);
+  (origin, (target - origin).normalize())
+}
@@ -0,0 +1,784 @@
+//! Loading and exporting MagicaVoxel `.vox` files.
+//!
+//! See <https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt>
+//! and the `-extension` document next to it for the scene graph chunks.
+
+use std::{fmt, io::Write};
+
+use bevy::{
+  asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+  prelude::*,
+  utils::{BoxedFuture, HashMap},
+};
+
+use super::{
+  material::{MaterialId, VoxelMaterial, VoxelMaterials},
+  normals::{estimate_normal, NormalKernel},
+  voxel_index, Chunk, ChunkData, FullVoxel,
+};
+use crate::{CHUNK_SIZE, CHUNK_VOXEL_COUNT};
+
+#[derive(Debug)]
+pub enum VoxError {
+  Io(std::io::Error),
+  /// The file does not start with the `VOX ` magic.
+  InvalidMagic,
+  /// The file ended in the middle of a chunk.
+  UnexpectedEof,
+  /// A chunk's contents did not match its declared layout.
+  Malformed(&'static str),
+}
+
+impl fmt::Display for VoxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      VoxError::Io(e) => write!(f, "failed to read vox file: {e}"),
+      VoxError::InvalidMagic => write!(f, "not a vox file"),
+      VoxError::UnexpectedEof => write!(f, "unexpected end of vox file"),
+      VoxError::Malformed(what) => write!(f, "malformed vox file: {what}"),
+    }
+  }
+}
+
+impl std::error::Error for VoxError {}
+
+impl From<std::io::Error> for VoxError {
+  fn from(value: std::io::Error) -> Self { VoxError::Io(value) }
+}
+
+/// A single model in a `.vox` file: its size and `(x, y, z, color index)`
+/// voxels, in MagicaVoxel's z-up coordinates.
+pub(super) struct VoxModel {
+  pub size:   IVec3,
+  pub voxels: Vec<[u8; 4]>,
+}
+
+/// A node of the `.vox` scene graph.
+enum VoxNode {
+  Transform {
+    child:       i32,
+    translation: IVec3,
+    rotation:    Mat3,
+  },
+  Group {
+    children: Vec<i32>,
+  },
+  Shape {
+    models: Vec<i32>,
+  },
+}
+
+/// The parsed contents of a `.vox` file.
+pub(super) struct VoxFile {
+  pub models:  Vec<VoxModel>,
+  pub palette: [[u8; 4]; 256],
+  nodes:       HashMap<i32, VoxNode>,
+}
+
+struct VoxReader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> VoxReader<'a> {
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+    if self.bytes.len() < len {
+      return Err(VoxError::UnexpectedEof);
+    }
+    let (head, tail) = self.bytes.split_at(len);
+    self.bytes = tail;
+    Ok(head)
+  }
+
+  fn u32(&mut self) -> Result<u32, VoxError> {
+    Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  fn i32(&mut self) -> Result<i32, VoxError> { Ok(self.u32()? as i32) }
+
+  fn string(&mut self) -> Result<String, VoxError> {
+    let len = self.u32()? as usize;
+    Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+  }
+
+  fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+    let len = self.u32()?;
+    (0..len)
+      .map(|_| Ok((self.string()?, self.string()?)))
+      .collect()
+  }
+}
+
+/// Decodes MagicaVoxel's packed rotation byte into a matrix.
+///
+/// Bits 0-1 and 2-3 are the column of the non-zero entry in the first and
+/// second rows, and bits 4-6 are the signs of the three rows.
+fn decode_rotation(bits: u8) -> Result<Mat3, VoxError> {
+  let first = (bits & 3) as usize;
+  let second = ((bits >> 2) & 3) as usize;
+  if first > 2 || second > 2 || first == second {
+    return Err(VoxError::Malformed("invalid rotation"));
+  }
+  let third = 3 - first - second;
+
+  let mut rows = [Vec3::ZERO; 3];
+  for (row, (column, sign_bit)) in [(first, 4), (second, 5), (third, 6)]
+    .into_iter()
+    .enumerate()
+  {
+    rows[row][column] = if bits & (1 << sign_bit) != 0 {
+      -1.0
+    } else {
+      1.0
+    };
+  }
+  Ok(Mat3::from_cols(rows[0], rows[1], rows[2]).transpose())
+}
+
+/// MagicaVoxel's palette for files without an `RGBA` chunk, indexed by
+/// color index like [`VoxFile::palette`].
+fn default_palette() -> [[u8; 4]; 256] {
+  let mut palette = [[0; 4]; 256];
+  let mut i = 1;
+
+  // a 6³ color cube from white down, leaving out black
+  let steps = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+  for r in steps {
+    for g in steps {
+      for b in steps {
+        if i < 216 {
+          palette[i] = [r, g, b, 0xff];
+          i += 1;
+        }
+      }
+    }
+  }
+
+  // then ramps of red, green, blue and gray between the cube's steps
+  let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+  for [r, g, b] in [[1, 0, 0], [0, 1, 0], [0, 0, 1], [1, 1, 1]] {
+    for v in ramp {
+      palette[i] = [v * r, v * g, v * b, 0xff];
+      i += 1;
+    }
+  }
+  palette
+}
+
+impl VoxFile {
+  pub fn parse(bytes: &[u8]) -> Result<Self, VoxError> {
+    let mut reader = VoxReader { bytes };
+    if reader.bytes(4)? != b"VOX " {
+      return Err(VoxError::InvalidMagic);
+    }
+    let _version = reader.u32()?;
+
+    // MAIN only has children, which hold everything else
+    if reader.bytes(4)? != b"MAIN" {
+      return Err(VoxError::Malformed("missing MAIN chunk"));
+    }
+    let main_content_len = reader.u32()? as usize;
+    let _main_children_len = reader.u32()?;
+    reader.bytes(main_content_len)?;
+
+    let mut file = VoxFile {
+      models:  Vec::new(),
+      palette: default_palette(),
+      nodes:   HashMap::new(),
+    };
+    let mut pending_size = None;
+
+    while !reader.bytes.is_empty() {
+      let id = reader.bytes(4)?;
+      let content_len = reader.u32()? as usize;
+      let children_len = reader.u32()? as usize;
+      let mut content = VoxReader {
+        bytes: reader.bytes(content_len)?,
+      };
+      reader.bytes(children_len)?;
+
+      match id {
+        b"SIZE" => {
+          pending_size =
+            Some(IVec3::new(content.i32()?, content.i32()?, content.i32()?));
+        }
+        b"XYZI" => {
+          let size = pending_size
+            .take()
+            .ok_or(VoxError::Malformed("XYZI without SIZE"))?;
+          let count = content.u32()? as usize;
+          let voxels = content
+            .bytes(count * 4)?
+            .chunks_exact(4)
+            .map(|v| v.try_into().unwrap())
+            .collect();
+          file.models.push(VoxModel { size, voxels });
+        }
+        b"RGBA" => {
+          // palette entry `i` is used by color index `i + 1`
+          for i in 1..256 {
+            file.palette[i] = content.bytes(4)?.try_into().unwrap();
+          }
+        }
+        b"nTRN" => {
+          let node = content.i32()?;
+          let _attributes = content.dict()?;
+          let child = content.i32()?;
+          let _reserved = content.i32()?;
+          let _layer = content.i32()?;
+          let frame_count = content.u32()?;
+          let mut translation = IVec3::ZERO;
+          let mut rotation = Mat3::IDENTITY;
+          // only the first frame matters outside of animations
+          if frame_count > 0 {
+            let frame = content.dict()?;
+            if let Some(t) = frame.get("_t") {
+              let t = t
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<Vec<i32>, _>>()
+                .map_err(|_| VoxError::Malformed("invalid translation"))?;
+              let [x, y, z] = t[..] else {
+                return Err(VoxError::Malformed("invalid translation"));
+              };
+              translation = IVec3::new(x, y, z);
+            }
+            if let Some(r) = frame.get("_r") {
+              let bits = r
+                .parse()
+                .map_err(|_| VoxError::Malformed("invalid rotation"))?;
+              rotation = decode_rotation(bits)?;
+            }
+          }
+          file.nodes.insert(node, VoxNode::Transform {
+            child,
+            translation,
+            rotation,
+          });
+        }
+        b"nGRP" => {
+          let node = content.i32()?;
+          let _attributes = content.dict()?;
+          let count = content.u32()?;
+          let children = (0..count)
+            .map(|_| content.i32())
+            .collect::<Result<_, _>>()?;
+          file.nodes.insert(node, VoxNode::Group { children });
+        }
+        b"nSHP" => {
+          let node = content.i32()?;
+          let _attributes = content.dict()?;
+          let count = content.u32()?;
+          let models = (0..count)
+            .map(|_| {
+              let model = content.i32()?;
+              let _attributes = content.dict()?;
+              Ok(model)
+            })
+            .collect::<Result<_, VoxError>>()?;
+          file.nodes.insert(node, VoxNode::Shape { models });
+        }
+        // materials, layers, cameras etc. don't affect the voxels
+        _ => {}
+      }
+    }
+
+    Ok(file)
+  }
+
+  /// Every placed model as `(model, translation, rotation)`, found by walking
+  /// the scene graph from its root. Files without a scene graph place every
+  /// model at the origin.
+  fn instances(&self) -> Result<Vec<(usize, IVec3, Mat3)>, VoxError> {
+    if self.nodes.is_empty() {
+      return Ok(
+        (0..self.models.len())
+          .map(|i| (i, IVec3::ZERO, Mat3::IDENTITY))
+          .collect(),
+      );
+    }
+
+    let mut instances = Vec::new();
+    let mut stack = vec![(0, IVec3::ZERO, Mat3::IDENTITY)];
+    let mut visited = 0;
+    while let Some((node, translation, rotation)) = stack.pop() {
+      // the graph is a tree, so visiting more nodes than exist means a cycle
+      visited += 1;
+      if visited > self.nodes.len() {
+        return Err(VoxError::Malformed("cyclic scene graph"));
+      }
+
+      match self.nodes.get(&node) {
+        Some(VoxNode::Transform {
+          child,
+          translation: t,
+          rotation: r,
+        }) => {
+          let t = translation + (rotation * t.as_vec3()).round().as_ivec3();
+          stack.push((*child, t, rotation * *r));
+        }
+        Some(VoxNode::Group { children }) => {
+          stack.extend(children.iter().map(|c| (*c, translation, rotation)));
+        }
+        Some(VoxNode::Shape { models }) => {
+          for model in models {
+            if *model < 0 || *model as usize >= self.models.len() {
+              return Err(VoxError::Malformed("shape references no model"));
+            }
+            instances.push((*model as usize, translation, rotation));
+          }
+        }
+        None => return Err(VoxError::Malformed("missing scene graph node")),
+      }
+    }
+    Ok(instances)
+  }
+
+  /// Resolves the scene into world-space voxels, keyed by position in
+  /// manoka's y-up coordinates, with their color index.
+  fn world_voxels(&self) -> Result<HashMap<IVec3, u8>, VoxError> {
+    let mut voxels = HashMap::new();
+    for (model, translation, rotation) in self.instances()? {
+      let model = &self.models[model];
+      // models are placed by their center
+      let half = model.size / 2;
+      for &[x, y, z, color] in model.voxels.iter() {
+        let local = IVec3::new(x as i32, y as i32, z as i32) - half;
+        let pos = translation + (rotation * local.as_vec3()).round().as_ivec3();
+        voxels.insert(IVec3::new(pos.x, pos.z, -pos.y), color);
+      }
+    }
+    Ok(voxels)
+  }
+}
+
+/// A chunk produced from a `.vox` file, and the world-space voxel position of
+/// its minimum corner.
+#[derive(Clone, Debug, Reflect)]
+pub struct VoxChunk {
+  pub chunk:  Handle<Chunk>,
+  pub offset: IVec3,
+}
+
+/// The chunks making up a `.vox` file, split on the chunk grid.
+#[derive(Clone, Debug, Asset, Reflect)]
+pub struct VoxScene {
+  pub chunks: Vec<VoxChunk>,
+}
+
+impl VoxFile {
+  /// Splits the scene along the world's chunk grid, as `(offset, chunk)`
+  /// sorted by offset. Each palette entry in use becomes a material in
+  /// `materials`.
+  pub fn to_chunks(
+    &self,
+    materials: &VoxelMaterials,
+  ) -> Result<Vec<(IVec3, Chunk)>, VoxError> {
+    let voxels = self.world_voxels()?;
+
+    let mut palette = [None::<MaterialId>; 256];
+    let mut material = |color: u8| {
+      *palette[color as usize].get_or_insert_with(|| {
+        let [r, g, b, a] = self.palette[color as usize];
+        let [r, g, b, _] = Color::rgb_u8(r, g, b).as_linear_rgba_f32();
+        materials.intern(&VoxelMaterial {
+          opacity: a as f32 / 255.0,
+          ..VoxelMaterial::from_color(Vec3::new(r, g, b))
+        })
+      })
+    };
+
+    let mut chunks = HashMap::<IVec3, Vec<Option<FullVoxel>>>::new();
+    for (&pos, &color) in voxels.iter() {
+      let cell = pos.div_euclid(IVec3::splat(CHUNK_SIZE as i32));
+      let local = pos.rem_euclid(IVec3::splat(CHUNK_SIZE as i32));
+      let normal = estimate_normal(pos, NormalKernel { radius: 1 }, |p| {
+        voxels.contains_key(&p)
+      });
+      chunks
+        .entry(cell)
+        .or_insert_with(|| vec![None; CHUNK_VOXEL_COUNT])
+        [voxel_index(local.as_uvec3())] =
+        Some(FullVoxel::new(normal, material(color)));
+    }
+
+    // sorted, so chunk labels are stable between loads
+    let mut chunks = chunks.into_iter().collect::<Vec<_>>();
+    chunks.sort_unstable_by_key(|(cell, _)| cell.to_array());
+    Ok(
+      chunks
+        .into_iter()
+        .map(|(cell, data)| {
+          (
+            cell * CHUNK_SIZE as i32,
+            Chunk::new(ChunkData::Full { data }),
+          )
+        })
+        .collect(),
+    )
+  }
+}
+
+pub struct VoxLoader {
+  materials: VoxelMaterials,
+}
+
+impl FromWorld for VoxLoader {
+  fn from_world(world: &mut World) -> Self {
+    Self {
+      materials: world.resource::<VoxelMaterials>().clone(),
+    }
+  }
+}
+
+impl AssetLoader for VoxLoader {
+  type Asset = VoxScene;
+  type Settings = ();
+  type Error = VoxError;
+
+  fn load<'a>(
+    &'a self,
+    reader: &'a mut Reader,
+    _settings: &'a (),
+    load_context: &'a mut LoadContext,
+  ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+    Box::pin(async move {
+      let mut bytes = Vec::new();
+      reader.read_to_end(&mut bytes).await?;
+
+      let chunks = VoxFile::parse(&bytes)?
+        .to_chunks(&self.materials)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, (offset, chunk))| VoxChunk {
+          chunk: load_context.add_labeled_asset(format!("chunk{i}"), chunk),
+          offset,
+        })
+        .collect();
+
+      Ok(VoxScene { chunks })
+    })
+  }
+
+  fn extensions(&self) -> &[&str] { &["vox"] }
+}
+
+/// Quantizes colors down to at most `max` palette entries with median cut,
+/// returning the palette and the palette index of each input color.
+fn quantize_colors(colors: &[[u8; 3]], max: usize) -> (Vec<[u8; 3]>, Vec<u8>) {
+  let mut unique = colors.to_vec();
+  unique.sort_unstable();
+  unique.dedup();
+  if unique.is_empty() {
+    return (Vec::new(), Vec::new());
+  }
+
+  let mut boxes = vec![unique];
+  while boxes.len() < max {
+    // split the box with the widest channel range at its median
+    let widest = boxes
+      .iter()
+      .enumerate()
+      .filter(|(_, b)| b.len() > 1)
+      .map(|(i, b)| {
+        let (channel, range) = (0..3)
+          .map(|c| {
+            let min = b.iter().map(|color| color[c]).min().unwrap();
+            let max = b.iter().map(|color| color[c]).max().unwrap();
+            (c, max - min)
+          })
+          .max_by_key(|(_, range)| *range)
+          .unwrap();
+        (i, channel, range)
+      })
+      .max_by_key(|(_, _, range)| *range);
+    let Some((i, channel, _)) = widest else {
+      break;
+    };
+
+    let mut split = boxes.swap_remove(i);
+    split.sort_unstable_by_key(|color| color[channel]);
+    let upper = split.split_off(split.len() / 2);
+    boxes.push(split);
+    boxes.push(upper);
+  }
+
+  let mut lookup = HashMap::new();
+  let palette = boxes
+    .iter()
+    .filter(|b| !b.is_empty())
+    .enumerate()
+    .map(|(i, b)| {
+      let mut sum = [0u32; 3];
+      for color in b {
+        lookup.insert(*color, i as u8);
+        for c in 0..3 {
+          sum[c] += color[c] as u32;
+        }
+      }
+      sum.map(|s| (s / b.len() as u32) as u8)
+    })
+    .collect();
+  (palette, colors.iter().map(|c| lookup[c]).collect())
+}
+
+fn write_chunk(
+  out: &mut Vec<u8>,
+  id: &[u8; 4],
+  content: &[u8],
+  children: &[u8],
+) {
+  out.extend_from_slice(id);
+  out.extend_from_slice(&(content.len() as u32).to_le_bytes());
+  out.extend_from_slice(&(children.len() as u32).to_le_bytes());
+  out.extend_from_slice(content);
+  out.extend_from_slice(children);
+}
+
+fn write_dict(out: &mut Vec<u8>, entries: &[(&str, String)]) {
+  out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+  for (key, value) in entries {
+    for s in [key.as_bytes(), value.as_bytes()] {
+      out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+      out.extend_from_slice(s);
+    }
+  }
+}
+
+fn write_i32s(out: &mut Vec<u8>, values: &[i32]) {
+  for value in values {
+    out.extend_from_slice(&value.to_le_bytes());
+  }
+}
+
+/// Writes chunks to a `.vox` file, each as its own model placed at its
+/// world-space voxel `offset` (the same convention [`VoxScene`] uses).
+///
+/// Material albedos are quantized to the 255 usable palette entries, but
+/// occupancy is preserved exactly, so loading the file back yields the same
+/// voxels.
+pub fn export_vox(
+  writer: &mut impl Write,
+  chunks: &[(IVec3, &Chunk)],
+  materials: &VoxelMaterials,
+) -> std::io::Result<()> {
+  let materials = materials.snapshot();
+  let size = CHUNK_SIZE as i32;
+
+  // manoka is y-up and MagicaVoxel is z-up, so (x, y, z) becomes (x, -z, y)
+  let models = chunks
+    .iter()
+    .map(|(_, chunk)| {
+      chunk
+        .iter_occupied()
+        .map(|(pos, voxel)| {
+          let albedo = materials
+            .get(voxel.material as usize)
+            .map_or(Vec3::ONE, |material| material.albedo);
+          let [r, g, b, _] =
+            Color::rgb_linear(albedo.x, albedo.y, albedo.z).as_rgba_u8();
+          (
+            [pos.x as u8, (size - 1) as u8 - pos.z as u8, pos.y as u8],
+            [r, g, b],
+          )
+        })
+        .collect::<Vec<_>>()
+    })
+    .collect::<Vec<_>>();
+
+  let colors = models
+    .iter()
+    .flatten()
+    .map(|(_, color)| *color)
+    .collect::<Vec<_>>();
+  let (palette, mut indices) = quantize_colors(&colors, 255);
+  let mut indices = indices.drain(..);
+
+  let mut children = Vec::new();
+  for model in models.iter() {
+    let mut content = Vec::new();
+    write_i32s(&mut content, &[size, size, size]);
+    write_chunk(&mut children, b"SIZE", &content, &[]);
+
+    let mut content = (model.len() as u32).to_le_bytes().to_vec();
+    for ([x, y, z], _) in model {
+      // color index `i + 1` refers to palette entry `i`
+      let index = indices.next().unwrap() + 1;
+      content.extend_from_slice(&[*x, *y, *z, index]);
+    }
+    write_chunk(&mut children, b"XYZI", &content, &[]);
+  }
+
+  // root transform -> group -> a transform and shape per model
+  let model_count = chunks.len() as i32;
+  let mut content = Vec::new();
+  write_i32s(&mut content, &[0]);
+  write_dict(&mut content, &[]);
+  write_i32s(&mut content, &[1, -1, -1, 1]);
+  write_dict(&mut content, &[]);
+  write_chunk(&mut children, b"nTRN", &content, &[]);
+
+  let mut content = Vec::new();
+  write_i32s(&mut content, &[1]);
+  write_dict(&mut content, &[]);
+  write_i32s(&mut content, &[model_count]);
+  write_i32s(
+    &mut content,
+    &(0..model_count).map(|i| 2 + 2 * i).collect::<Vec<_>>(),
+  );
+  write_chunk(&mut children, b"nGRP", &content, &[]);
+
+  for (i, (offset, _)) in chunks.iter().enumerate() {
+    let i = i as i32;
+    // models are placed by their center, see `VoxFile::world_voxels`
+    let half = size / 2;
+    let translation = IVec3::new(
+      offset.x + half,
+      -offset.z - (size - 1) + half,
+      offset.y + half,
+    );
+
+    let mut content = Vec::new();
+    write_i32s(&mut content, &[2 + 2 * i]);
+    write_dict(&mut content, &[]);
+    write_i32s(&mut content, &[3 + 2 * i, -1, 0, 1]);
+    write_dict(&mut content, &[(
+      "_t",
+      format!("{} {} {}", translation.x, translation.y, translation.z),
+    )]);
+    write_chunk(&mut children, b"nTRN", &content, &[]);
+
+    let mut content = Vec::new();
+    write_i32s(&mut content, &[3 + 2 * i]);
+    write_dict(&mut content, &[]);
+    write_i32s(&mut content, &[1, i]);
+    write_dict(&mut content, &[]);
+    write_chunk(&mut children, b"nSHP", &content, &[]);
+  }
+
+  let mut content = Vec::with_capacity(256 * 4);
+  for i in 0..256 {
+    let [r, g, b] = palette.get(i).copied().unwrap_or_default();
+    content.extend_from_slice(&[r, g, b, 255]);
+  }
+  write_chunk(&mut children, b"RGBA", &content, &[]);
+
+  let mut file = b"VOX ".to_vec();
+  file.extend_from_slice(&150u32.to_le_bytes());
+  write_chunk(&mut file, b"MAIN", &[], &children);
+  writer.write_all(&file)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A chunk with a few voxels, far from the chunk's minimum corner.
+  fn sparse_chunk(materials: &VoxelMaterials) -> Chunk {
+    let material = materials.intern(&VoxelMaterial::from_color(Vec3::ONE));
+    let mut chunk = Chunk::new_empty();
+    let far = CHUNK_SIZE as u32 - 2;
+    for pos in [UVec3::splat(far), UVec3::new(far, 1, far)] {
+      chunk.set(pos, FullVoxel::new(Vec3::Y, material)).unwrap();
+    }
+    chunk
+  }
+
+  #[test]
+  fn quantizes_to_the_palette_size() {
+    assert_eq!(quantize_colors(&[], 255), (Vec::new(), Vec::new()));
+
+    let few = [[10, 20, 30], [200, 0, 0], [10, 20, 30]];
+    let (palette, indices) = quantize_colors(&few, 255);
+    assert_eq!(palette.len(), 2);
+    let resolved = indices
+      .iter()
+      .map(|i| palette[*i as usize])
+      .collect::<Vec<_>>();
+    assert_eq!(resolved, few);
+
+    let mut rng = fastrand::Rng::with_seed(5);
+    let many = (0..4096)
+      .map(|_| [rng.u8(..), rng.u8(..), rng.u8(..)])
+      .collect::<Vec<_>>();
+    let (palette, indices) = quantize_colors(&many, 16);
+    assert_eq!(palette.len(), 16);
+    assert_eq!(indices.len(), many.len());
+    assert!(indices.iter().all(|i| (*i as usize) < palette.len()));
+  }
+
+  #[test]
+  fn exports_empty_chunks() {
+    let materials = VoxelMaterials::default();
+    let chunk = Chunk::new_empty();
+    let mut bytes = Vec::new();
+    export_vox(&mut bytes, &[(IVec3::ZERO, &chunk)], &materials).unwrap();
+    let loaded = VoxFile::parse(&bytes)
+      .unwrap()
+      .to_chunks(&materials)
+      .unwrap();
+    assert!(loaded.is_empty());
+  }
+
+  #[test]
+  fn splits_along_the_world_chunk_grid() {
+    let materials = VoxelMaterials::default();
+    let chunk = sparse_chunk(&materials);
+    let size = CHUNK_SIZE as i32;
+    let offsets =
+      [IVec3::new(size, 0, -size), IVec3::ZERO, IVec3::splat(-size)];
+    let chunks = offsets.map(|offset| (offset, &chunk));
+
+    let mut bytes = Vec::new();
+    export_vox(&mut bytes, &chunks, &materials).unwrap();
+    let loaded = VoxFile::parse(&bytes)
+      .unwrap()
+      .to_chunks(&materials)
+      .unwrap();
+
+    let mut sorted = offsets;
+    sorted.sort_unstable_by_key(|offset| offset.to_array());
+    assert_eq!(
+      loaded.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(),
+      sorted
+    );
+    for (_, loaded) in loaded.iter() {
+      let occupied = |chunk: &Chunk| {
+        chunk
+          .iter_occupied()
+          .map(|(pos, _)| pos)
+          .collect::<Vec<_>>()
+      };
+      assert_eq!(occupied(loaded), occupied(&chunk));
+    }
+  }
+
+  #[test]
+  fn falls_back_to_the_default_palette() {
+    let palette = default_palette();
+    assert_eq!(palette[1], [0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(palette[2], [0xff, 0xff, 0xcc, 0xff]);
+    assert_eq!(palette[215], [0x00, 0x00, 0x33, 0xff]);
+    assert_eq!(palette[216], [0xee, 0x00, 0x00, 0xff]);
+    assert_eq!(palette[255], [0x11, 0x11, 0x11, 0xff]);
+
+    // one voxel of color index 216 and no RGBA chunk
+    let mut children = Vec::new();
+    let mut content = Vec::new();
+    write_i32s(&mut content, &[1, 1, 1]);
+    write_chunk(&mut children, b"SIZE", &content, &[]);
+    write_chunk(&mut children, b"XYZI", &[1, 0, 0, 0, 0, 0, 0, 216], &[]);
+    let mut bytes = b"VOX ".to_vec();
+    bytes.extend_from_slice(&150u32.to_le_bytes());
+    write_chunk(&mut bytes, b"MAIN", &[], &children);
+
+    let materials = VoxelMaterials::default();
+    let chunks = VoxFile::parse(&bytes)
+      .unwrap()
+      .to_chunks(&materials)
+      .unwrap();
+    let (_, voxel) = chunks[0].1.iter_occupied().next().unwrap();
+    let [r, g, b, _] = Color::rgb_u8(0xee, 0, 0).as_linear_rgba_f32();
+    let albedo = materials.snapshot()[voxel.material as usize].albedo;
+    assert_eq!(albedo, Vec3::new(r, g, b));
+  }
+}
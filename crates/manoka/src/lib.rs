@@ -0,0 +1,28 @@
+pub mod chunk;
+pub mod render;
+pub mod sun;
+pub mod world;
+
+/// The edge length of a chunk, in voxels, chosen with the `chunk-size-*`
+/// features. Everything sized by the chunk, including shaders and dispatch
+/// sizes, follows it.
+pub const CHUNK_SIZE: usize = if cfg!(feature = "chunk-size-16") {
+  16
+} else if cfg!(feature = "chunk-size-32") {
+  32
+} else if cfg!(feature = "chunk-size-128") {
+  128
+} else {
+  64
+};
+const _: () = assert!(
+  cfg!(feature = "chunk-size-16") as u8
+    + cfg!(feature = "chunk-size-32") as u8
+    + cfg!(feature = "chunk-size-64") as u8
+    + cfg!(feature = "chunk-size-128") as u8
+    <= 1,
+  "enable only one `chunk-size-*` feature, with `--no-default-features`"
+);
+pub const CHUNK_VOXEL_COUNT: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+pub const MAX_CHUNKS: usize = 256;
+pub const MAX_SUN_LIGHTS: usize = 16;
@@ -1,7 +1,3 @@
-mod chunk;
-mod render;
-mod sun;
-
 use std::f32::consts::PI;
 
 use bevy::{
@@ -11,23 +7,22 @@ use bevy::{
   render::{
     camera::{CameraMainTextureUsages, CameraRenderGraph, Exposure},
     primitives::Frustum,
+    render_resource::TextureUsages,
     view::{ColorGrading, VisibleEntities},
   },
   window::PresentMode,
 };
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use sun::SunPlugin;
-
-use crate::{
-  chunk::{Chunk, ChunkPlugin},
+use manoka::{
+  chunk::{material::VoxelMaterials, Chunk, ChunkPlugin},
   render::{CoreVoxel, ManokaRenderPlugin},
-  sun::SunLight,
+  sun::{SunLight, SunPlugin},
+  world::{
+    terrain::{Terrain, TerrainSettings},
+    ChunkPos, VoxelWorldPlugin,
+  },
 };
 
-pub const CHUNK_VOXEL_COUNT: usize = 64 * 64 * 64;
-pub const MAX_CHUNKS: usize = 256;
-pub const MAX_SUN_LIGHTS: usize = 16;
-
 fn main() {
   let mut app = App::new();
 
@@ -53,7 +48,7 @@ fn main() {
 
   // first party logic
   app
-    .add_plugins((ManokaRenderPlugin, ChunkPlugin, SunPlugin))
+    .add_plugins((ManokaRenderPlugin, ChunkPlugin, SunPlugin, VoxelWorldPlugin))
     .add_systems(Startup, setup);
 
   // bevy_mod_debugdump::print_render_graph(&mut app);
@@ -61,23 +56,36 @@ fn main() {
   app.run();
 }
 
-fn setup(mut commands: Commands, mut chunks: ResMut<Assets<Chunk>>) {
-  let chunk_handle = chunks.add(Chunk::debug_red_sphere_chunk());
+fn setup(
+  mut commands: Commands,
+  mut chunks: ResMut<Assets<Chunk>>,
+  materials: Res<VoxelMaterials>,
+  asset_server: Res<AssetServer>,
+) {
+  commands.insert_resource(Terrain {
+    settings: TerrainSettings::default(),
+    layers:   asset_server.load("terrain/default.layers.ron"),
+  });
+
+  let chunk_handle = chunks.add(Chunk::debug_red_sphere_chunk(&materials));
   // let chunk_handle = chunks.add(Chunk::new_empty());
 
   // spawn a chunk
   commands.spawn((
     chunk_handle.clone(),
+    ChunkPos(IVec3::new(0, 0, 0)),
     SpatialBundle::default(),
     Name::new("test_chunk"),
   ));
   commands.spawn((
     chunk_handle.clone(),
+    ChunkPos(IVec3::new(1, 0, 0)),
     SpatialBundle::default(),
     Name::new("test_chunk_2"),
   ));
   commands.spawn((
     chunk_handle.clone(),
+    ChunkPos(IVec3::new(2, 0, 0)),
     SpatialBundle::default(),
     Name::new("test_chunk_3"),
   ));
@@ -128,7 +136,10 @@ fn setup(mut commands: Commands, mut chunks: ResMut<Assets<Chunk>>) {
     Tonemapping::default(),
     ColorGrading::default(),
     Exposure::default(),
-    CameraMainTextureUsages::default(),
+    // the primary pass writes color as a storage texture
+    CameraMainTextureUsages(
+      CameraMainTextureUsages::default().0 | TextureUsages::STORAGE_BINDING,
+    ),
     DebandDither::Enabled,
   ));
 }
@@ -0,0 +1,221 @@
+//! A bounding volume hierarchy over chunk instances.
+//!
+//! Chunks can be rotated and scaled arbitrarily, so the top level of ray
+//! traversal bounds each chunk's world space box and builds a binary tree
+//! over those bounds with the surface area heuristic. Every leaf holds one
+//! chunk.
+//!
+//! Nodes are stored depth first, so a node's first child directly follows
+//! it, and each node records the index just past its subtree. Shaders walk
+//! the tree without a stack: a ray that misses a node jumps past it, and one
+//! that hits it moves on to the next node.
+//!
+//! When chunks move without being added or removed, the tree can be refit
+//! instead of rebuilt, keeping its topology and just growing or shrinking
+//! the bounds. [`Bvh::sah_cost`] measures how much that costs in quality.
+
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+/// [`BvhNode::chunk`] of interior nodes.
+pub const BVH_INTERIOR: u32 = u32::MAX;
+
+/// The cost of marching a chunk relative to testing a node's bounds, for
+/// [`Bvh::sah_cost`].
+const CHUNK_COST: f32 = 8.0;
+
+/// A node of [`Bvh`], in the layout of the shader's `BvhNode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ShaderType)]
+pub struct BvhNode {
+  pub min:   Vec3,
+  /// The index of the first node after this node's subtree.
+  pub skip:  u32,
+  pub max:   Vec3,
+  /// The chunk of a leaf, or [`BVH_INTERIOR`].
+  pub chunk: u32,
+}
+
+impl BvhNode {
+  fn leaf_chunk(&self) -> Option<usize> {
+    (self.chunk != BVH_INTERIOR).then_some(self.chunk as usize)
+  }
+
+  /// Whether a ray enters the node's bounds before `t_max`. `inv_dir` is the
+  /// reciprocal of the ray's direction.
+  pub fn intersects(&self, origin: Vec3, inv_dir: Vec3, t_max: f32) -> bool {
+    let t0 = (self.min - origin) * inv_dir;
+    let t1 = (self.max - origin) * inv_dir;
+    let t_enter = t0.min(t1).max_element().max(0.0);
+    let t_exit = t0.max(t1).min_element();
+    t_enter <= t_exit && t_enter <= t_max
+  }
+}
+
+/// The area of the surface of a box.
+fn surface_area(min: Vec3, max: Vec3) -> f32 {
+  let size = (max - min).max(Vec3::ZERO);
+  2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+}
+
+/// The box containing every box of `bounds` selected by `items`.
+fn union(bounds: &[(Vec3, Vec3)], items: &[u32]) -> (Vec3, Vec3) {
+  items.iter().map(|i| bounds[*i as usize]).fold(
+    (Vec3::INFINITY, Vec3::NEG_INFINITY),
+    |(min, max), (item_min, item_max)| (min.min(item_min), max.max(item_max)),
+  )
+}
+
+/// A bounding volume hierarchy over the world space bounds of chunks.
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+  /// Depth first, with the root first.
+  pub nodes: Vec<BvhNode>,
+}
+
+impl Bvh {
+  /// Builds a tree over `bounds`, with one leaf per box. Leaves refer to
+  /// boxes by their index in `bounds`.
+  pub fn build(bounds: &[(Vec3, Vec3)]) -> Self {
+    let mut nodes = Vec::with_capacity((2 * bounds.len()).saturating_sub(1));
+    let mut items = (0..bounds.len() as u32).collect::<Vec<_>>();
+    if !items.is_empty() {
+      build_node(&mut nodes, bounds, &mut items);
+    }
+    Self { nodes }
+  }
+
+  /// Updates the bounds of every node for new `bounds` of the same boxes,
+  /// keeping the tree's topology.
+  pub fn refit(&mut self, bounds: &[(Vec3, Vec3)]) {
+    // children follow their parents, so going backwards updates them first
+    for i in (0..self.nodes.len()).rev() {
+      let (min, max) = match self.nodes[i].leaf_chunk() {
+        Some(chunk) => bounds[chunk],
+        None => {
+          let left = self.nodes[i + 1];
+          let right = self.nodes[left.skip as usize];
+          (left.min.min(right.min), left.max.max(right.max))
+        }
+      };
+      self.nodes[i].min = min;
+      self.nodes[i].max = max;
+    }
+  }
+
+  /// The expected cost of tracing a ray that hits the root through the
+  /// tree, by the surface area heuristic: each node is weighted by the
+  /// chance a ray through the root also passes through it. Lower is better.
+  pub fn sah_cost(&self) -> f32 {
+    let Some(root) = self.nodes.first() else {
+      return 0.0;
+    };
+    let root_area = surface_area(root.min, root.max).max(f32::EPSILON);
+    self
+      .nodes
+      .iter()
+      .map(|node| {
+        let cost = match node.leaf_chunk() {
+          Some(_) => CHUNK_COST,
+          None => 1.0,
+        };
+        surface_area(node.min, node.max) / root_area * cost
+      })
+      .sum()
+  }
+
+  /// Visits the leaves whose bounds a ray enters, in tree order. `visit`
+  /// returns the ray's new `t_max`, so nodes beyond the nearest hit so far
+  /// are skipped, or `None` to stop. Mirrors the loop of `raycast_scene` in
+  /// `chunk.wgsl`.
+  pub fn traverse(
+    &self,
+    origin: Vec3,
+    inv_dir: Vec3,
+    mut visit: impl FnMut(usize) -> Option<f32>,
+  ) {
+    let mut t_max = f32::INFINITY;
+    let mut i = 0;
+    while i < self.nodes.len() {
+      let node = &self.nodes[i];
+      if !node.intersects(origin, inv_dir, t_max) {
+        i = node.skip as usize;
+        continue;
+      }
+      if let Some(chunk) = node.leaf_chunk() {
+        let Some(t) = visit(chunk) else {
+          return;
+        };
+        t_max = t;
+      }
+      i += 1;
+    }
+  }
+}
+
+/// Appends the subtree over `items` to `nodes`, splitting where the surface
+/// area heuristic is lowest.
+fn build_node(
+  nodes: &mut Vec<BvhNode>,
+  bounds: &[(Vec3, Vec3)],
+  items: &mut [u32],
+) {
+  let index = nodes.len();
+  let (min, max) = union(bounds, items);
+  nodes.push(BvhNode {
+    min,
+    skip: 0,
+    max,
+    chunk: BVH_INTERIOR,
+  });
+
+  if let [chunk] = items {
+    nodes[index].chunk = *chunk;
+    nodes[index].skip = index as u32 + 1;
+    return;
+  }
+
+  let (axis, split) = sah_split(bounds, items);
+  sort_by_centroid(bounds, items, axis);
+  let (left, right) = items.split_at_mut(split);
+  build_node(nodes, bounds, left);
+  build_node(nodes, bounds, right);
+  nodes[index].skip = nodes.len() as u32;
+}
+
+fn sort_by_centroid(bounds: &[(Vec3, Vec3)], items: &mut [u32], axis: usize) {
+  items.sort_unstable_by(|a, b| {
+    let (a_min, a_max) = bounds[*a as usize];
+    let (b_min, b_max) = bounds[*b as usize];
+    (a_min[axis] + a_max[axis]).total_cmp(&(b_min[axis] + b_max[axis]))
+  });
+}
+
+/// The axis to sort `items` along by centroid and the number of items to
+/// put in the first child, minimizing the children's combined surface area
+/// weighted by their number of chunks.
+fn sah_split(bounds: &[(Vec3, Vec3)], items: &mut [u32]) -> (usize, usize) {
+  let mut best = (f32::INFINITY, 0, items.len() / 2);
+  for axis in 0..3 {
+    sort_by_centroid(bounds, items, axis);
+
+    // the area of the boxes after each split point, swept from the right
+    let mut right_areas = vec![0.0; items.len()];
+    let (mut min, mut max) = (Vec3::INFINITY, Vec3::NEG_INFINITY);
+    for i in (1..items.len()).rev() {
+      let (item_min, item_max) = bounds[items[i] as usize];
+      (min, max) = (min.min(item_min), max.max(item_max));
+      right_areas[i] = surface_area(min, max);
+    }
+
+    let (mut min, mut max) = (Vec3::INFINITY, Vec3::NEG_INFINITY);
+    for split in 1..items.len() {
+      let (item_min, item_max) = bounds[items[split - 1] as usize];
+      (min, max) = (min.min(item_min), max.max(item_max));
+      let cost = surface_area(min, max) * split as f32
+        + right_areas[split] * (items.len() - split) as f32;
+      if cost < best.0 {
+        best = (cost, axis, split);
+      }
+    }
+  }
+  (best.1, best.2)
+}
@@ -1,49 +1,70 @@
 use std::borrow::Cow;
 
 use bevy::{
-  ecs::entity::EntityHashMap,
+  ecs::{entity::EntityHashMap, query::QueryItem},
   prelude::*,
   render::{
-    render_asset::RenderAssets,
-    render_graph::Node,
+    extract_resource::ExtractResourcePlugin,
+    render_graph::{NodeRunError, RenderGraphContext, ViewNode},
     render_resource::{
       binding_types::{
         storage_buffer, storage_buffer_read_only, uniform_buffer,
       },
       BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries,
       Buffer, BufferDescriptor, CachedComputePipelineId,
-      ComputePipelineDescriptor, PipelineCache, ShaderType, StorageBuffer,
-      UniformBuffer,
+      ComputePipelineDescriptor, PipelineCache, ShaderDefVal, ShaderSize,
+      ShaderType, SpecializedComputePipeline, SpecializedComputePipelines,
+      StorageBuffer, UniformBuffer,
     },
-    renderer::{RenderDevice, RenderQueue},
+    renderer::{RenderContext, RenderDevice, RenderQueue},
     Render, RenderApp, RenderSet,
   },
 };
 use wgpu::{BufferUsages, ComputePassDescriptor, ShaderStages};
 
+use super::{
+  bvh::{Bvh, BvhNode},
+  traversal::chunk_bounds,
+  view::{
+    VoxelViewBindGroup, VoxelViewBindGroupLayout, VoxelViewUniformOffset,
+  },
+};
 use crate::{
-  chunk::{Chunk, GpuChunkAttributes, GpuChunkOccupancy},
+  chunk::{
+    material::{GpuVoxelMaterials, MaterialId},
+    normals::NormalMode,
+    render::{chunk_shader_defs, BrickPool, GpuChunks, GpuMaterials},
+    Chunk, GpuChunkOccupancy,
+  },
   sun::render::{GpuSunLight, SunLightsBuffer},
-  CHUNK_VOXEL_COUNT,
+  CHUNK_SIZE, CHUNK_VOXEL_COUNT,
 };
 
-pub struct DirectPassNode {
-  query_state: QueryState<(&'static Handle<Chunk>, &'static GlobalTransform)>,
-}
+/// The workgroup size of `direct_pass.wgsl` along each axis.
+const DIRECT_PASS_WORKGROUP_SIZE: u32 = 4;
+/// Workgroups along each axis to cover a chunk with one invocation per voxel.
+const DIRECT_PASS_WORKGROUPS: u32 =
+  CHUNK_SIZE as u32 / DIRECT_PASS_WORKGROUP_SIZE;
+
+#[derive(Default)]
+pub struct DirectPassNode;
+
+impl ViewNode for DirectPassNode {
+  type ViewQuery =
+    (&'static VoxelViewBindGroup, &'static VoxelViewUniformOffset);
 
-impl Node for DirectPassNode {
   fn run<'w>(
     &self,
-    _graph: &mut bevy::render::render_graph::RenderGraphContext,
-    render_context: &mut bevy::render::renderer::RenderContext<'w>,
+    _graph: &mut RenderGraphContext,
+    render_context: &mut RenderContext<'w>,
+    (view_bind_group, view_offset): QueryItem<'w, Self::ViewQuery>,
     world: &'w World,
-  ) -> Result<(), bevy::render::render_graph::NodeRunError> {
-    let pipelines = world.resource::<DirectPassPipeline>();
+  ) -> Result<(), NodeRunError> {
+    let pipeline_id = world.resource::<DirectPassPipelineId>();
     let pipeline_cache = world.resource::<PipelineCache>();
     let bind_groups = world.resource::<DirectPassBindGroups>();
 
-    let Some(pipeline) =
-      pipeline_cache.get_compute_pipeline(pipelines.pipeline)
+    let Some(pipeline) = pipeline_cache.get_compute_pipeline(pipeline_id.0)
     else {
       return Ok(());
     };
@@ -60,36 +81,26 @@ impl Node for DirectPassNode {
     );
     pass.set_pipeline(pipeline);
     pass.set_bind_group(0, &bind_groups.common, &[]);
+    pass.set_bind_group(2, &view_bind_group.0, &[view_offset.offset]);
     for (_, specific_bind_group) in bind_groups.specific.iter() {
       pass.set_bind_group(1, &specific_bind_group, &[]);
-      pass.dispatch_workgroups(16, 16, 16);
+      pass.dispatch_workgroups(
+        DIRECT_PASS_WORKGROUPS,
+        DIRECT_PASS_WORKGROUPS,
+        DIRECT_PASS_WORKGROUPS,
+      );
     }
 
     Ok(())
   }
-
-  fn update(&mut self, world: &mut World) { self.query_state = world.query(); }
-}
-
-impl DirectPassNode {
-  pub fn new(world: &mut World) -> Self {
-    Self {
-      query_state: world.query(),
-    }
-  }
-}
-
-impl FromWorld for DirectPassNode {
-  fn from_world(world: &mut World) -> Self { Self::new(world) }
 }
 
 #[derive()]
 pub struct RenderedChunk {
-  index:                     u32,
-  transform:                 GlobalTransform,
-  chunk_asset:               Handle<Chunk>,
-  direct_pass_output_buffer: Buffer,
-  direct_pass_uniform:       UniformBuffer<DirectPassUniform>,
+  /// The chunk's entry in the global transform buffer.
+  index:               u32,
+  chunk_asset:         Handle<Chunk>,
+  direct_pass_uniform: UniformBuffer<DirectPassUniform>,
 }
 
 #[derive(Resource)]
@@ -97,8 +108,109 @@ pub struct ChunksToRender(pub EntityHashMap<RenderedChunk>);
 
 #[derive(Resource)]
 pub struct DirectPassGlobalBuffers {
-  om_buffer:        StorageBuffer<Vec<GpuChunkOccupancy>>,
-  transform_buffer: StorageBuffer<Vec<Mat4>>,
+  transform_buffer:       StorageBuffer<Vec<Mat4>>,
+  /// The slot in the chunk occupancy buffer of each chunk in
+  /// `transform_buffer`.
+  occupancy_index_buffer: StorageBuffer<Vec<u32>>,
+  /// The nodes of [`ChunkBvh`], over the chunks in `transform_buffer`.
+  bvh_buffer:             StorageBuffer<Vec<BvhNode>>,
+}
+
+/// The [`DirectPassOutput`] of each chunk in the transform buffer, in order,
+/// so the primary pass can resolve every chunk at once.
+///
+/// At several MiB per chunk this is the largest buffer the renderer binds,
+/// so it's kept across frames and only reallocated when more chunks are
+/// drawn than it holds.
+#[derive(Resource, Default)]
+pub(super) struct DirectPassOutputBuffer {
+  buffer:   Option<Buffer>,
+  /// The number of chunks `buffer` has room for.
+  capacity: usize,
+}
+
+impl DirectPassOutputBuffer {
+  /// The most chunks whose output `render_device` can bind at once.
+  fn max_chunks(render_device: &RenderDevice) -> usize {
+    let limits = render_device.limits();
+    let max_size = limits
+      .max_buffer_size
+      .min(limits.max_storage_buffer_binding_size as u64);
+    (max_size / DirectPassOutput::min_size().get()) as usize
+  }
+
+  /// The buffer, once chunks have been prepared.
+  pub(super) fn buffer(&self) -> Option<&Buffer> { self.buffer.as_ref() }
+
+  /// Grows the buffer to hold `chunks` chunks, which must be at most
+  /// [`Self::max_chunks`].
+  fn reserve(&mut self, render_device: &RenderDevice, chunks: usize) {
+    let max_chunks = Self::max_chunks(render_device);
+    debug_assert!(chunks <= max_chunks);
+    if self.buffer.is_some() && chunks <= self.capacity {
+      return;
+    }
+
+    self.capacity = chunks.next_power_of_two().min(max_chunks);
+    let size = self.capacity as u64 * DirectPassOutput::min_size().get();
+    debug!(
+      "allocating direct pass output: {} chunks, {:.1} MiB",
+      self.capacity,
+      size as f32 / (1024.0 * 1024.0)
+    );
+    self.buffer = Some(render_device.create_buffer(&BufferDescriptor {
+      label: Some("direct_pass_output_buffer"),
+      size,
+      usage: BufferUsages::STORAGE,
+      mapped_at_creation: false,
+    }));
+  }
+}
+
+/// How much worse refitting may make [`ChunkBvh`] by its SAH cost before
+/// it is rebuilt.
+///
+/// By the `bvh` bench, rebuilding over `MAX_CHUNKS` chunks takes about 280µs
+/// against 3µs to refit and cost the tree. When every chunk moves at two
+/// chunks a second, refitting passes 1.1 after 24 frames, so rebuilding then
+/// costs about 12µs a frame. At 1.5 it would last 79 frames, while rays visit
+/// up to half again as many nodes and chunks as needed.
+const BVH_REBUILD_RATIO: f32 = 1.1;
+
+/// The BVH over the rendered chunks, kept between frames so it can be refit
+/// while the same chunks are rendered.
+#[derive(Resource, Default)]
+pub struct ChunkBvh {
+  bvh:        Bvh,
+  /// The chunks the BVH was built over, in order.
+  entities:   Option<Vec<Entity>>,
+  /// The SAH cost of the BVH when it was last rebuilt.
+  built_cost: f32,
+}
+
+impl ChunkBvh {
+  /// Refits the BVH to the chunks' new bounds, or rebuilds it if the chunks
+  /// changed or refitting has degraded it too far.
+  fn update(&mut self, entities: &[Entity], bounds: &[(Vec3, Vec3)]) {
+    let same_chunks = self.entities.as_deref() == Some(entities);
+    if same_chunks {
+      self.bvh.refit(bounds);
+      if self.bvh.sah_cost() <= self.built_cost * BVH_REBUILD_RATIO {
+        return;
+      }
+    }
+
+    let refit_cost = self.bvh.sah_cost();
+    self.bvh = Bvh::build(bounds);
+    self.built_cost = self.bvh.sah_cost();
+    self.entities = Some(entities.to_vec());
+    if same_chunks {
+      debug!(
+        "rebuilt chunk BVH: SAH cost {refit_cost:.2} refit, {:.2} rebuilt",
+        self.built_cost
+      );
+    }
+  }
 }
 
 #[allow(clippy::type_complexity)]
@@ -107,119 +219,146 @@ fn prepare_renderable_chunks(
   query: Query<(Entity, &Handle<Chunk>, &GlobalTransform, &ViewVisibility)>,
   render_device: Res<RenderDevice>,
   render_queue: Res<RenderQueue>,
-  chunks: Res<RenderAssets<Chunk>>,
+  chunks: Res<GpuChunks>,
+  mut chunk_bvh: ResMut<ChunkBvh>,
+  mut output_buffer: ResMut<DirectPassOutputBuffer>,
 ) {
-  let mut sorted_entities =
-    query.iter().map(|(e, _, _, _)| e).collect::<Vec<_>>();
+  let mut sorted_entities = query
+    .iter()
+    .filter(|(_, _, _, vv)| vv.get())
+    .map(|(e, _, _, _)| e)
+    .collect::<Vec<_>>();
   sorted_entities.sort_unstable_by_key(|e| e.index());
 
+  // the output of every drawn chunk is bound at once, so draw no more than
+  // the device can bind
+  let max_chunks = DirectPassOutputBuffer::max_chunks(&render_device);
+  if sorted_entities.len() > max_chunks {
+    warn_once!(
+      "{} chunks are visible but the device can only draw {max_chunks}",
+      sorted_entities.len()
+    );
+    sorted_entities.truncate(max_chunks);
+  }
+
+  let mut transforms = Vec::new();
+  let mut chunk_occupancy_indices = Vec::new();
+
   let mut chunks_to_render = EntityHashMap::default();
   for (i, entity) in sorted_entities.iter().enumerate() {
-    let (entity, chunk_handle, transform, vv) = query.get(*entity).unwrap();
-    if !vv.get() {
-      continue;
-    }
+    let (entity, chunk_handle, transform, _) = query.get(*entity).unwrap();
+
+    // entities sharing a chunk asset share its occupancy
+    let occupancy_index = chunks
+      .get(chunk_handle.id())
+      .expect("failed to find chunk render asset from id")
+      .slot;
 
     let mut direct_pass_uniform = UniformBuffer::from(DirectPassUniform {
       current_chunk: i as _,
+      occupancy_index,
     });
     direct_pass_uniform.write_buffer(&render_device, &render_queue);
+    transforms.push(transform.compute_matrix());
+    chunk_occupancy_indices.push(occupancy_index);
     chunks_to_render.insert(entity, RenderedChunk {
       index: i as _,
-      transform: transform.clone(),
       chunk_asset: chunk_handle.clone(),
-      direct_pass_output_buffer: render_device.create_buffer(
-        &BufferDescriptor {
-          label:              Some("direct_pass_output_buffer"),
-          size:               DirectPassOutput::min_size().get(),
-          usage:              BufferUsages::STORAGE,
-          mapped_at_creation: false,
-        },
-      ),
       direct_pass_uniform,
     });
   }
 
-  let mut global_om_buffer = StorageBuffer::from(
-    sorted_entities
-      .iter()
-      .map(|e| chunks_to_render.get(e).unwrap())
-      .map(|r| {
-        chunks
-          .get(r.chunk_asset.id())
-          .expect("failed to find chunk render asset from id")
-          .occupancy
-          .clone()
-      })
-      .collect::<Vec<_>>(),
-  );
-  global_om_buffer.write_buffer(&render_device, &render_queue);
-
-  let mut global_transform_buffer = StorageBuffer::from(
-    sorted_entities
-      .iter()
-      .map(|e| chunks_to_render.get(e).unwrap())
-      .map(|r| r.transform.compute_matrix())
-      .collect::<Vec<_>>(),
-  );
+  // storage bindings can't be empty, so stand in the empty slot 0
+  if transforms.is_empty() {
+    transforms.push(Mat4::IDENTITY);
+    chunk_occupancy_indices.push(0);
+  }
+
+  let bounds = transforms
+    .iter()
+    .copied()
+    .map(chunk_bounds)
+    .collect::<Vec<_>>();
+  chunk_bvh.update(&sorted_entities, &bounds);
+  let mut bvh_buffer = StorageBuffer::from(chunk_bvh.bvh.nodes.clone());
+  bvh_buffer.write_buffer(&render_device, &render_queue);
+
+  let transforms_len = transforms.len();
+  let mut global_transform_buffer = StorageBuffer::from(transforms);
   global_transform_buffer.write_buffer(&render_device, &render_queue);
 
+  let mut occupancy_index_buffer = StorageBuffer::from(chunk_occupancy_indices);
+  occupancy_index_buffer.write_buffer(&render_device, &render_queue);
+
+  output_buffer.reserve(&render_device, transforms_len);
+
   commands.insert_resource(ChunksToRender(chunks_to_render));
   commands.insert_resource(DirectPassGlobalBuffers {
-    om_buffer:        global_om_buffer,
     transform_buffer: global_transform_buffer,
+    occupancy_index_buffer,
+    bvh_buffer,
   });
 }
 
 #[derive(Resource)]
 pub struct DirectPassBindGroups {
-  common:   BindGroup,
-  specific: EntityHashMap<BindGroup>,
+  /// Also bound by the primary pass, which reads the same chunk buffers.
+  pub(super) common: BindGroup,
+  specific:          EntityHashMap<BindGroup>,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn prepare_direct_pass_bind_groups(
   mut commands: Commands,
   pipeline: Res<DirectPassPipeline>,
   renderable_chunks: Res<ChunksToRender>,
   global_buffers: Res<DirectPassGlobalBuffers>,
+  output_buffer: Res<DirectPassOutputBuffer>,
   sun_light_buffer: Res<SunLightsBuffer>,
+  materials: Res<GpuMaterials>,
+  brick_pool: Res<BrickPool>,
   render_device: Res<RenderDevice>,
-  chunks: Res<RenderAssets<Chunk>>,
+  chunks: Res<GpuChunks>,
 ) {
+  let (Some(occupancy_buffer), Some(brick_materials), Some(output_buffer)) = (
+    chunks.occupancy_buffer(),
+    brick_pool.material_buffer(),
+    output_buffer.buffer(),
+  ) else {
+    return;
+  };
+
+  // unused by the pipeline for derived normals, but still bound
+  let brick_normals = brick_pool
+    .normal_buffer()
+    .unwrap_or(&pipeline.no_normals_buffer);
   let common = render_device.create_bind_group(
     Some("direct_pass_common_bind_group"),
     &pipeline.common_bind_group_layout,
     &BindGroupEntries::with_indices((
-      (0, global_buffers.om_buffer.binding().unwrap()),
+      (0, occupancy_buffer.as_entire_binding()),
       (1, global_buffers.transform_buffer.binding().unwrap()),
       (2, sun_light_buffer.0.binding().unwrap()),
+      (3, materials.buffer.binding().unwrap()),
+      (4, global_buffers.occupancy_index_buffer.binding().unwrap()),
+      (5, global_buffers.bvh_buffer.binding().unwrap()),
+      (6, brick_materials.as_entire_binding()),
+      (7, brick_normals.as_entire_binding()),
     )),
   );
 
   let mut specific = EntityHashMap::default();
   for (entity, renderable_chunk) in renderable_chunks.0.iter() {
+    let chunk = chunks.get(renderable_chunk.chunk_asset.id()).unwrap();
     specific.insert(
       entity.clone(),
       render_device.create_bind_group(
         Some("direct_pass_specific_bind_group"),
         &pipeline.specific_bind_group_layout,
         &BindGroupEntries::with_indices((
-          (
-            0,
-            chunks
-              .get(renderable_chunk.chunk_asset.id())
-              .unwrap()
-              .attribute_buffer
-              .binding()
-              .unwrap(),
-          ),
+          (0, chunk.grid_buffer.as_entire_binding()),
           (1, renderable_chunk.direct_pass_uniform.binding().unwrap()),
-          (
-            2,
-            renderable_chunk
-              .direct_pass_output_buffer
-              .as_entire_binding(),
-          ),
+          (2, output_buffer.as_entire_binding()),
         )),
       ),
     );
@@ -230,28 +369,40 @@ fn prepare_direct_pass_bind_groups(
 
 #[derive(Debug, ShaderType)]
 struct DirectPassUniform {
-  current_chunk: u32,
+  current_chunk:   u32,
+  /// The chunk's entry in the occupancy buffer.
+  occupancy_index: u32,
 }
 
 #[derive(ShaderType)]
-struct DirectPassOutput {
+pub(super) struct DirectPassOutput {
   output: [Vec3; CHUNK_VOXEL_COUNT],
 }
 
 #[derive(Resource)]
-struct DirectPassPipeline {
-  common_bind_group_layout:   BindGroupLayout,
+pub(super) struct DirectPassPipeline {
+  pub(super) common_bind_group_layout: BindGroupLayout,
   specific_bind_group_layout: BindGroupLayout,
-  pipeline:                   CachedComputePipelineId,
+  view_bind_group_layout: BindGroupLayout,
+  /// Bound in place of the brick pool's normals under
+  /// [`NormalMode::Derived`], when they aren't uploaded.
+  no_normals_buffer: Buffer,
+  shader: Handle<Shader>,
+  /// Kept loaded for shaders that import `manoka::chunk`.
+  _chunk_shader: Handle<Shader>,
 }
 
+/// The direct pass pipeline specialized for the current [`NormalMode`].
+#[derive(Resource)]
+struct DirectPassPipelineId(CachedComputePipelineId);
+
 impl FromWorld for DirectPassPipeline {
   fn from_world(world: &mut World) -> Self {
     let render_device = world.resource::<RenderDevice>();
 
-    let shader = world
-      .resource::<AssetServer>()
-      .load("shaders/direct_pass.wgsl");
+    let asset_server = world.resource::<AssetServer>();
+    let shader = asset_server.load("shaders/direct_pass.wgsl");
+    let chunk_shader = asset_server.load("shaders/chunk.wgsl");
 
     let common_bind_group_layout = render_device.create_bind_group_layout(
       "direct_pass_common_layout",
@@ -261,6 +412,11 @@ impl FromWorld for DirectPassPipeline {
           (0, storage_buffer_read_only::<Vec<GpuChunkOccupancy>>(false)),
           (1, storage_buffer_read_only::<Vec<Mat4>>(false)),
           (2, storage_buffer_read_only::<Vec<GpuSunLight>>(false)),
+          (3, storage_buffer_read_only::<GpuVoxelMaterials>(false)),
+          (4, storage_buffer_read_only::<Vec<u32>>(false)),
+          (5, storage_buffer_read_only::<Vec<BvhNode>>(false)),
+          (6, storage_buffer_read_only::<Vec<MaterialId>>(false)),
+          (7, storage_buffer_read_only::<Vec<Vec3>>(false)),
         ),
       ),
     );
@@ -269,46 +425,93 @@ impl FromWorld for DirectPassPipeline {
       &BindGroupLayoutEntries::with_indices(
         ShaderStages::COMPUTE,
         (
-          (0, storage_buffer_read_only::<GpuChunkAttributes>(false)),
+          (0, storage_buffer_read_only::<Vec<u32>>(false)),
           (1, uniform_buffer::<DirectPassUniform>(false)),
-          (2, storage_buffer::<DirectPassOutput>(false)),
+          (2, storage_buffer::<Vec<DirectPassOutput>>(false)),
         ),
       ),
     );
 
-    let pipeline_cache = world.resource::<PipelineCache>();
-    let pipeline =
-      pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-        label: Some(Cow::from("direct_pass_pipeline")),
-        layout: vec![
-          common_bind_group_layout.clone(),
-          specific_bind_group_layout.clone(),
-        ],
-        push_constant_ranges: vec![],
-        shader,
-        shader_defs: vec![],
-        entry_point: Cow::from("update"),
-      });
+    let no_normals_buffer = render_device.create_buffer(&BufferDescriptor {
+      label:              Some("direct_pass_no_normals_buffer"),
+      size:               Vec4::SHADER_SIZE.get(),
+      usage:              BufferUsages::STORAGE,
+      mapped_at_creation: false,
+    });
 
     DirectPassPipeline {
       common_bind_group_layout,
       specific_bind_group_layout,
-      pipeline,
+      view_bind_group_layout: world
+        .resource::<VoxelViewBindGroupLayout>()
+        .0
+        .clone(),
+      no_normals_buffer,
+      shader,
+      _chunk_shader: chunk_shader,
     }
   }
 }
 
+impl SpecializedComputePipeline for DirectPassPipeline {
+  type Key = NormalMode;
+
+  fn specialize(&self, normal_mode: NormalMode) -> ComputePipelineDescriptor {
+    let mut shader_defs = chunk_shader_defs();
+    if let NormalMode::Derived { kernel } = normal_mode {
+      shader_defs.extend([
+        "DERIVED_NORMALS".into(),
+        ShaderDefVal::Int("NORMAL_KERNEL_RADIUS".into(), kernel.radius as i32),
+      ]);
+    }
+
+    ComputePipelineDescriptor {
+      label: Some(Cow::from("direct_pass_pipeline")),
+      layout: vec![
+        self.common_bind_group_layout.clone(),
+        self.specific_bind_group_layout.clone(),
+        self.view_bind_group_layout.clone(),
+      ],
+      push_constant_ranges: vec![],
+      shader: self.shader.clone(),
+      shader_defs,
+      entry_point: Cow::from("update"),
+    }
+  }
+}
+
+/// Picks the pipeline for the current [`NormalMode`], which may change at
+/// runtime. Each mode is only compiled once.
+fn queue_direct_pass_pipeline(
+  mut commands: Commands,
+  pipeline: Res<DirectPassPipeline>,
+  mut pipelines: ResMut<SpecializedComputePipelines<DirectPassPipeline>>,
+  pipeline_cache: Res<PipelineCache>,
+  normal_mode: Res<NormalMode>,
+) {
+  let id = pipelines.specialize(&pipeline_cache, &pipeline, *normal_mode);
+  commands.insert_resource(DirectPassPipelineId(id));
+}
+
 pub struct DirectPassPlugin;
 
 impl Plugin for DirectPassPlugin {
-  fn build(&self, _app: &mut App) {}
+  fn build(&self, app: &mut App) {
+    app.add_plugins(ExtractResourcePlugin::<NormalMode>::default());
+  }
   fn finish(&self, app: &mut App) {
     let render_app = app.sub_app_mut(RenderApp);
 
-    render_app.init_resource::<DirectPassPipeline>();
+    render_app
+      .init_resource::<NormalMode>()
+      .init_resource::<ChunkBvh>()
+      .init_resource::<DirectPassOutputBuffer>()
+      .init_resource::<DirectPassPipeline>()
+      .init_resource::<SpecializedComputePipelines<DirectPassPipeline>>();
     render_app.add_systems(
       Render,
       (
+        queue_direct_pass_pipeline.in_set(RenderSet::Queue),
         prepare_renderable_chunks.in_set(RenderSet::Prepare),
         prepare_direct_pass_bind_groups.in_set(RenderSet::PrepareBindGroups),
       ),
@@ -0,0 +1,196 @@
+//! A CPU reference of the lighting computed by the direct pass.
+//!
+//! Each occupied voxel reflects every sun light it can see with a Lambert
+//! BRDF on its normal, plus whatever it emits. A light is blocked when a
+//! shadow ray from the voxel towards it hits any chunk of the scene. These
+//! functions mirror their namesakes in `direct_pass.wgsl`, so the GPU's
+//! results can be checked against them.
+
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+
+use crate::{
+  chunk::{material::VoxelMaterial, voxel_index, FullVoxel},
+  render::traversal::ChunkScene,
+  sun::render::GpuSunLight,
+  CHUNK_SIZE,
+};
+
+/// Scales how far a shadow ray starts from its voxel's center, so that
+/// rounding can't leave it inside the voxel it starts from.
+const SHADOW_RAY_BIAS: f32 = 1.001;
+
+/// Where a shadow ray from the center of `voxel` towards `to_light` leaves
+/// the voxel, in world space.
+pub fn shadow_ray_origin(
+  transform: Mat4,
+  voxel: UVec3,
+  to_light: Vec3,
+) -> Vec3 {
+  let center = voxel.as_vec3() + 0.5;
+  let chunk_dir = transform.inverse().transform_vector3(to_light);
+  let t = 0.5 / chunk_dir.abs().max_element() * SHADOW_RAY_BIAS;
+  transform.transform_point3(center) + to_light * t
+}
+
+/// The light leaving the surface of `voxel` in `scene.chunks[chunk]`, in
+/// the same units as the lights' illuminance. The direct pass scales this by
+/// the view's exposure.
+pub fn voxel_radiance(
+  scene: &ChunkScene,
+  chunk: usize,
+  voxel: UVec3,
+  normal: Vec3,
+  material: &VoxelMaterial,
+  lights: &[GpuSunLight],
+) -> Vec3 {
+  let transform = scene.chunks[chunk].transform;
+  let diffuse = material.albedo / PI;
+
+  let mut radiance = material.emissive;
+  for light in lights {
+    let to_light = -light.direction.normalize();
+    let n_dot_l = normal.dot(to_light);
+    if n_dot_l <= 0.0 {
+      continue;
+    }
+    let origin = shadow_ray_origin(transform, voxel, to_light);
+    if scene.occluded(origin, to_light) {
+      continue;
+    }
+    let color = Vec4::from_array(light.color).truncate();
+    radiance += diffuse * color * light.illuminance * n_dot_l;
+  }
+  radiance
+}
+
+/// The radiance of every voxel of `scene.chunks[chunk]`, in dense order like
+/// the direct pass' output. Empty voxels are black.
+///
+/// `voxels` is the chunk's dense data and `materials` the material table.
+/// Normals are taken as stored, so derived normals have to be written into
+/// the voxels first: voxels without a stored normal panic.
+pub fn chunk_radiance(
+  scene: &ChunkScene,
+  chunk: usize,
+  voxels: &[Option<FullVoxel>],
+  materials: &[VoxelMaterial],
+  lights: &[GpuSunLight],
+) -> Vec<Vec3> {
+  let size = CHUNK_SIZE as u32;
+  let mut output = vec![Vec3::ZERO; voxels.len()];
+  for z in 0..size {
+    for y in 0..size {
+      for x in 0..size {
+        let pos = UVec3::new(x, y, z);
+        let index = voxel_index(pos);
+        let Some(voxel) = &voxels[index] else {
+          continue;
+        };
+        output[index] = voxel_radiance(
+          scene,
+          chunk,
+          pos,
+          voxel.normal.expect("voxel has no stored normal"),
+          &materials[voxel.material as usize],
+          lights,
+        );
+      }
+    }
+  }
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    chunk::{test_utils::dense_mask, GpuChunkOccupancy},
+    render::traversal::SceneChunk,
+    CHUNK_VOXEL_COUNT,
+  };
+
+  const ALBEDO: Vec3 = Vec3::new(0.5, 0.25, 1.0);
+  const EMISSIVE: Vec3 = Vec3::new(0.0, 2.0, 0.0);
+
+  /// A light shining straight down.
+  fn light() -> GpuSunLight {
+    GpuSunLight {
+      color:       [1.0, 0.5, 0.25, 1.0],
+      illuminance: 10.0,
+      direction:   Vec3::NEG_Y,
+    }
+  }
+
+  /// What a voxel with normal `normal` reflects of [`light`], unoccluded.
+  fn lambert(normal: Vec3) -> Vec3 {
+    let light = light();
+    let color = Vec4::from_array(light.color).truncate();
+    ALBEDO / PI * color * light.illuminance * normal.dot(Vec3::Y)
+  }
+
+  /// The radiance of a lone chunk holding `voxels`, under [`light`]. Material
+  /// 0 only reflects, material 1 also emits.
+  fn radiance(voxels: &[(UVec3, FullVoxel)]) -> Vec<Vec3> {
+    let mut data = vec![None; CHUNK_VOXEL_COUNT];
+    for (pos, voxel) in voxels {
+      data[voxel_index(*pos)] = Some(voxel.clone());
+    }
+    let occupancy = GpuChunkOccupancy::from_mask(dense_mask(&data));
+    let scene = ChunkScene::new(vec![SceneChunk {
+      occupancy: &occupancy,
+      transform: Mat4::from_translation(Vec3::new(-3.0, 5.0, 2.0)),
+    }]);
+    let materials = [
+      VoxelMaterial {
+        albedo: ALBEDO,
+        ..default()
+      },
+      VoxelMaterial {
+        albedo: ALBEDO,
+        emissive: EMISSIVE,
+        ..default()
+      },
+    ];
+    chunk_radiance(&scene, 0, &data, &materials, &[light()])
+  }
+
+  #[test]
+  fn lit_voxels_reflect_the_lambert_value() {
+    let tilted = Vec3::new(1.0, 1.0, 0.0).normalize();
+    let output = radiance(&[
+      (UVec3::new(1, 0, 1), FullVoxel::new(Vec3::Y, 0)),
+      (UVec3::new(3, 0, 3), FullVoxel::new(tilted, 0)),
+    ]);
+
+    assert!(output[voxel_index(UVec3::new(1, 0, 1))]
+      .abs_diff_eq(lambert(Vec3::Y), 1e-6));
+    assert!(output[voxel_index(UVec3::new(3, 0, 3))]
+      .abs_diff_eq(lambert(tilted), 1e-6));
+    assert_eq!(output[voxel_index(UVec3::new(2, 0, 2))], Vec3::ZERO);
+  }
+
+  #[test]
+  fn occluded_voxels_are_black() {
+    let output = radiance(&[
+      (UVec3::new(5, 0, 5), FullVoxel::new(Vec3::Y, 0)),
+      (UVec3::new(5, 3, 5), FullVoxel::new(Vec3::Y, 0)),
+    ]);
+
+    assert_eq!(output[voxel_index(UVec3::new(5, 0, 5))], Vec3::ZERO);
+    assert!(output[voxel_index(UVec3::new(5, 3, 5))]
+      .abs_diff_eq(lambert(Vec3::Y), 1e-6));
+  }
+
+  #[test]
+  fn voxels_facing_away_only_emit() {
+    let output = radiance(&[
+      (UVec3::new(1, 0, 1), FullVoxel::new(Vec3::NEG_Y, 1)),
+      (UVec3::new(3, 0, 3), FullVoxel::new(Vec3::X, 0)),
+    ]);
+
+    assert_eq!(output[voxel_index(UVec3::new(1, 0, 1))], EMISSIVE);
+    assert_eq!(output[voxel_index(UVec3::new(3, 0, 3))], Vec3::ZERO);
+  }
+}
@@ -1,14 +1,26 @@
+pub mod bvh;
 mod direct_pass;
+pub mod lighting;
+mod primary_pass;
+pub mod traversal;
+pub mod view;
 
 use bevy::{
+  core_pipeline::{tonemapping::TonemappingNode, upscaling::UpscalingNode},
   prelude::*,
   render::{
-    render_graph::{RenderGraphApp, RenderLabel, RenderSubGraph},
+    render_graph::{
+      RenderGraphApp, RenderLabel, RenderSubGraph, ViewNodeRunner,
+    },
     RenderApp,
   },
 };
 
-use self::direct_pass::{DirectPassNode, DirectPassPlugin};
+use self::{
+  direct_pass::{DirectPassNode, DirectPassPlugin},
+  primary_pass::{PrimaryPassNode, PrimaryPassPlugin},
+  view::VoxelViewPlugin,
+};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderSubGraph)]
 pub struct CoreVoxel;
@@ -16,22 +28,48 @@ pub struct CoreVoxel;
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub enum NodeVoxel {
   DirectPass,
+  PrimaryPass,
+  Tonemapping,
+  Upscaling,
 }
 
 pub struct ManokaRenderPlugin;
 
 impl Plugin for ManokaRenderPlugin {
   fn build(&self, app: &mut App) {
-    app.add_plugins(DirectPassPlugin);
+    // the view plugin goes first, since the passes' pipelines use its layout
+    app.add_plugins((VoxelViewPlugin, DirectPassPlugin, PrimaryPassPlugin));
 
     let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
       panic!("render_app not found");
     };
 
-    render_app.add_render_sub_graph(CoreVoxel);
-    render_app.add_render_graph_node::<DirectPassNode>(
-      CoreVoxel,
-      NodeVoxel::DirectPass,
-    );
+    render_app
+      .add_render_sub_graph(CoreVoxel)
+      .add_render_graph_node::<ViewNodeRunner<DirectPassNode>>(
+        CoreVoxel,
+        NodeVoxel::DirectPass,
+      )
+      .add_render_graph_node::<ViewNodeRunner<PrimaryPassNode>>(
+        CoreVoxel,
+        NodeVoxel::PrimaryPass,
+      )
+      .add_render_graph_node::<ViewNodeRunner<TonemappingNode>>(
+        CoreVoxel,
+        NodeVoxel::Tonemapping,
+      )
+      .add_render_graph_node::<ViewNodeRunner<UpscalingNode>>(
+        CoreVoxel,
+        NodeVoxel::Upscaling,
+      )
+      .add_render_graph_edges(
+        CoreVoxel,
+        (
+          NodeVoxel::DirectPass,
+          NodeVoxel::PrimaryPass,
+          NodeVoxel::Tonemapping,
+          NodeVoxel::Upscaling,
+        ),
+      );
   }
 }
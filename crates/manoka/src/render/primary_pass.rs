@@ -0,0 +1,350 @@
+//! The primary visibility pass.
+//!
+//! For every pixel of a view, a ray is marched through the occupancy of each
+//! rendered chunk, and the nearest hit is written to the view's depth, normal
+//! and visibility textures. The visibility texture holds the chunk and voxel
+//! that was hit, which a single resolve dispatch turns into color using the
+//! direct pass outputs of every chunk.
+
+use std::borrow::Cow;
+
+use bevy::{
+  ecs::query::QueryItem,
+  prelude::*,
+  render::{
+    camera::ExtractedCamera,
+    render_graph::{
+      NodeRunError, RenderGraphContext, RenderSubGraph, ViewNode,
+    },
+    render_resource::{
+      binding_types::{
+        storage_buffer_read_only, texture_2d, texture_storage_2d,
+      },
+      BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries,
+      CachedComputePipelineId, ComputePipelineDescriptor, PipelineCache,
+    },
+    renderer::{RenderContext, RenderDevice},
+    texture::{CachedTexture, TextureCache},
+    view::{ExtractedView, ViewTarget},
+    Render, RenderApp, RenderSet,
+  },
+};
+use wgpu::{
+  ComputePassDescriptor, Extent3d, ShaderStages, StorageTextureAccess,
+  TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType,
+  TextureUsages,
+};
+
+use super::{
+  direct_pass::{
+    DirectPassBindGroups, DirectPassOutput, DirectPassOutputBuffer,
+    DirectPassPipeline,
+  },
+  view::{
+    VoxelViewBindGroup, VoxelViewBindGroupLayout, VoxelViewUniformOffset,
+  },
+  CoreVoxel,
+};
+use crate::chunk::render::chunk_shader_defs;
+
+/// The workgroup size of both primary pass shaders along x and y.
+const PRIMARY_PASS_WORKGROUP_SIZE: UVec2 = UVec2::splat(8);
+
+const DEPTH_FORMAT: TextureFormat = TextureFormat::R32Float;
+const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const VISIBILITY_FORMAT: TextureFormat = TextureFormat::Rg32Uint;
+
+/// The primary pass outputs of a view, besides color, which goes to the
+/// view's main texture.
+#[derive(Component)]
+pub struct PrimaryPassTextures {
+  /// The distance along the primary ray to the nearest hit.
+  pub depth:      CachedTexture,
+  /// The world space normal of the face that was hit.
+  pub normal:     CachedTexture,
+  /// The index of the chunk that was hit and of the voxel within it.
+  pub visibility: CachedTexture,
+}
+
+fn prepare_primary_pass_textures(
+  mut commands: Commands,
+  mut texture_cache: ResMut<TextureCache>,
+  views: Query<(Entity, &ExtractedCamera)>,
+  render_device: Res<RenderDevice>,
+) {
+  for (entity, camera) in views.iter() {
+    if camera.render_graph != CoreVoxel.intern() {
+      continue;
+    }
+    let Some(size) = camera.physical_target_size else {
+      continue;
+    };
+
+    let mut texture = |label, format| {
+      texture_cache.get(&render_device, TextureDescriptor {
+        label: Some(label),
+        size: Extent3d {
+          width:                 size.x,
+          height:                size.y,
+          depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+      })
+    };
+    commands.entity(entity).insert(PrimaryPassTextures {
+      depth:      texture("primary_pass_depth_texture", DEPTH_FORMAT),
+      normal:     texture("primary_pass_normal_texture", NORMAL_FORMAT),
+      visibility: texture("primary_pass_visibility_texture", VISIBILITY_FORMAT),
+    });
+  }
+}
+
+/// The direct pass outputs the resolve dispatch reads from.
+#[derive(Resource)]
+struct PrimaryResolveBindGroup(BindGroup);
+
+fn prepare_primary_resolve_bind_group(
+  mut commands: Commands,
+  pipeline: Res<PrimaryPassPipeline>,
+  output_buffer: Res<DirectPassOutputBuffer>,
+  render_device: Res<RenderDevice>,
+) {
+  let Some(output_buffer) = output_buffer.buffer() else {
+    return;
+  };
+  let bind_group = render_device.create_bind_group(
+    Some("primary_resolve_chunks_bind_group"),
+    &pipeline.resolve_chunks_bind_group_layout,
+    &BindGroupEntries::single(output_buffer.as_entire_binding()),
+  );
+  commands.insert_resource(PrimaryResolveBindGroup(bind_group));
+}
+
+#[derive(Default)]
+pub struct PrimaryPassNode;
+
+impl ViewNode for PrimaryPassNode {
+  type ViewQuery = (
+    &'static ExtractedView,
+    &'static ViewTarget,
+    &'static PrimaryPassTextures,
+    &'static VoxelViewBindGroup,
+    &'static VoxelViewUniformOffset,
+  );
+
+  fn run<'w>(
+    &self,
+    _graph: &mut RenderGraphContext,
+    render_context: &mut RenderContext<'w>,
+    (view, target, textures, view_bind_group, view_offset): QueryItem<
+      'w,
+      Self::ViewQuery,
+    >,
+    world: &'w World,
+  ) -> Result<(), NodeRunError> {
+    let pipelines = world.resource::<PrimaryPassPipeline>();
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let common_bind_group = &world.resource::<DirectPassBindGroups>().common;
+    let resolve_bind_group = world.resource::<PrimaryResolveBindGroup>();
+
+    let (Some(primary_pipeline), Some(resolve_pipeline)) = (
+      pipeline_cache.get_compute_pipeline(pipelines.primary_pipeline),
+      pipeline_cache.get_compute_pipeline(pipelines.resolve_pipeline),
+    ) else {
+      return Ok(());
+    };
+    // the pass writes color as a storage texture, which needs an hdr format
+    if !view.hdr {
+      warn_once!("voxel cameras need `Camera::hdr` set to be drawn");
+      return Ok(());
+    }
+
+    let render_device = render_context.render_device();
+    let output_bind_group = render_device.create_bind_group(
+      Some("primary_pass_output_bind_group"),
+      &pipelines.output_bind_group_layout,
+      &BindGroupEntries::sequential((
+        target.main_texture_view(),
+        &textures.depth.default_view,
+        &textures.normal.default_view,
+        &textures.visibility.default_view,
+      )),
+    );
+    let resolve_view_bind_group = render_device.create_bind_group(
+      Some("primary_resolve_view_bind_group"),
+      &pipelines.resolve_view_bind_group_layout,
+      &BindGroupEntries::sequential((
+        &textures.visibility.default_view,
+        target.main_texture_view(),
+      )),
+    );
+
+    let workgroups = |size: UVec2| {
+      (size + PRIMARY_PASS_WORKGROUP_SIZE - 1) / PRIMARY_PASS_WORKGROUP_SIZE
+    };
+    let view_workgroups = workgroups(view.viewport.zw());
+    let target_size = textures.visibility.texture.size();
+    let target_workgroups =
+      workgroups(UVec2::new(target_size.width, target_size.height));
+
+    render_context
+      .command_encoder()
+      .push_debug_group("primary_pass");
+
+    {
+      let mut pass = render_context.command_encoder().begin_compute_pass(
+        &ComputePassDescriptor {
+          label:            Some("primary_pass_main_pass"),
+          timestamp_writes: None,
+        },
+      );
+      pass.set_pipeline(primary_pipeline);
+      pass.set_bind_group(0, common_bind_group, &[]);
+      pass.set_bind_group(1, &view_bind_group.0, &[view_offset.offset]);
+      pass.set_bind_group(2, &output_bind_group, &[]);
+      pass.dispatch_workgroups(view_workgroups.x, view_workgroups.y, 1);
+    }
+
+    // the visibility texture is sampled from here on, so it needs its own pass
+    {
+      let mut pass = render_context.command_encoder().begin_compute_pass(
+        &ComputePassDescriptor {
+          label:            Some("primary_pass_resolve_pass"),
+          timestamp_writes: None,
+        },
+      );
+      pass.set_pipeline(resolve_pipeline);
+      pass.set_bind_group(0, &resolve_view_bind_group, &[]);
+      pass.set_bind_group(1, &resolve_bind_group.0, &[]);
+      pass.dispatch_workgroups(target_workgroups.x, target_workgroups.y, 1);
+    }
+
+    render_context.command_encoder().pop_debug_group();
+
+    Ok(())
+  }
+}
+
+#[derive(Resource)]
+struct PrimaryPassPipeline {
+  output_bind_group_layout:         BindGroupLayout,
+  resolve_view_bind_group_layout:   BindGroupLayout,
+  resolve_chunks_bind_group_layout: BindGroupLayout,
+  primary_pipeline:                 CachedComputePipelineId,
+  resolve_pipeline:                 CachedComputePipelineId,
+}
+
+impl FromWorld for PrimaryPassPipeline {
+  fn from_world(world: &mut World) -> Self {
+    let render_device = world.resource::<RenderDevice>();
+    let asset_server = world.resource::<AssetServer>();
+    let primary_shader = asset_server.load("shaders/primary_pass.wgsl");
+    let resolve_shader = asset_server.load("shaders/primary_resolve.wgsl");
+
+    let output_bind_group_layout = render_device.create_bind_group_layout(
+      "primary_pass_output_layout",
+      &BindGroupLayoutEntries::sequential(
+        ShaderStages::COMPUTE,
+        (
+          texture_storage_2d(
+            ViewTarget::TEXTURE_FORMAT_HDR,
+            StorageTextureAccess::WriteOnly,
+          ),
+          texture_storage_2d(DEPTH_FORMAT, StorageTextureAccess::WriteOnly),
+          texture_storage_2d(NORMAL_FORMAT, StorageTextureAccess::WriteOnly),
+          texture_storage_2d(
+            VISIBILITY_FORMAT,
+            StorageTextureAccess::WriteOnly,
+          ),
+        ),
+      ),
+    );
+    let resolve_view_bind_group_layout = render_device
+      .create_bind_group_layout(
+        "primary_resolve_view_layout",
+        &BindGroupLayoutEntries::sequential(
+          ShaderStages::COMPUTE,
+          (
+            texture_2d(TextureSampleType::Uint),
+            texture_storage_2d(
+              ViewTarget::TEXTURE_FORMAT_HDR,
+              StorageTextureAccess::WriteOnly,
+            ),
+          ),
+        ),
+      );
+    let resolve_chunks_bind_group_layout = render_device
+      .create_bind_group_layout(
+        "primary_resolve_chunks_layout",
+        &BindGroupLayoutEntries::single(
+          ShaderStages::COMPUTE,
+          storage_buffer_read_only::<Vec<DirectPassOutput>>(false),
+        ),
+      );
+
+    let common_bind_group_layout = world
+      .resource::<DirectPassPipeline>()
+      .common_bind_group_layout
+      .clone();
+    let view_bind_group_layout =
+      world.resource::<VoxelViewBindGroupLayout>().0.clone();
+
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let primary_pipeline =
+      pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label:                Some(Cow::from("primary_pass_pipeline")),
+        layout:               vec![
+          common_bind_group_layout,
+          view_bind_group_layout,
+          output_bind_group_layout.clone(),
+        ],
+        push_constant_ranges: vec![],
+        shader:               primary_shader,
+        shader_defs:          chunk_shader_defs(),
+        entry_point:          Cow::from("primary"),
+      });
+    let resolve_pipeline =
+      pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label:                Some(Cow::from("primary_resolve_pipeline")),
+        layout:               vec![
+          resolve_view_bind_group_layout.clone(),
+          resolve_chunks_bind_group_layout.clone(),
+        ],
+        push_constant_ranges: vec![],
+        shader:               resolve_shader,
+        shader_defs:          chunk_shader_defs(),
+        entry_point:          Cow::from("resolve"),
+      });
+
+    PrimaryPassPipeline {
+      output_bind_group_layout,
+      resolve_view_bind_group_layout,
+      resolve_chunks_bind_group_layout,
+      primary_pipeline,
+      resolve_pipeline,
+    }
+  }
+}
+
+pub struct PrimaryPassPlugin;
+
+impl Plugin for PrimaryPassPlugin {
+  fn build(&self, _app: &mut App) {}
+  fn finish(&self, app: &mut App) {
+    let render_app = app.sub_app_mut(RenderApp);
+
+    render_app.init_resource::<PrimaryPassPipeline>();
+    render_app.add_systems(
+      Render,
+      (
+        prepare_primary_pass_textures.in_set(RenderSet::PrepareResources),
+        prepare_primary_resolve_bind_group.in_set(RenderSet::PrepareBindGroups),
+      ),
+    );
+  }
+}
@@ -0,0 +1,236 @@
+//! Ray traversal across every rendered chunk.
+//!
+//! Traversal has two levels. The top level walks a [`Bvh`] over the world
+//! space bounds of the chunks. Each chunk whose bounds the ray enters is
+//! marched through its occupancy pyramid in chunk space, and once a chunk is
+//! hit, nodes beyond the hit are skipped.
+//!
+//! [`ChunkScene`] is the CPU twin of `raycast_scene` in `chunk.wgsl`, which
+//! both the primary pass and the direct pass' shadow rays use.
+
+use bevy::prelude::*;
+
+use super::bvh::Bvh;
+use crate::{chunk::GpuChunkOccupancy, CHUNK_SIZE};
+
+/// A chunk as the voxel passes see it, like an entry of the direct pass'
+/// transform and occupancy buffers.
+#[derive(Clone, Copy, Debug)]
+pub struct SceneChunk<'a> {
+  pub occupancy: &'a GpuChunkOccupancy,
+  /// Chunk to world.
+  pub transform: Mat4,
+}
+
+/// The world space bounds of a chunk with the given transform.
+pub fn chunk_bounds(transform: Mat4) -> (Vec3, Vec3) {
+  (0..8)
+    .map(|i| {
+      let corner = UVec3::new(i & 1, (i >> 1) & 1, i >> 2).as_vec3();
+      transform.transform_point3(corner * CHUNK_SIZE as f32)
+    })
+    .fold(
+      (Vec3::INFINITY, Vec3::NEG_INFINITY),
+      |(min, max), corner| (min.min(corner), max.max(corner)),
+    )
+}
+
+/// Where a ray first hits any chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SceneHit {
+  /// The index of the chunk that was hit.
+  pub chunk:  usize,
+  /// The voxel that was hit, in chunk coordinates.
+  pub voxel:  UVec3,
+  /// The ray parameter at which the ray enters the voxel.
+  pub t:      f32,
+  /// The world space normal of the face the ray entered through.
+  pub normal: Vec3,
+}
+
+/// Every rendered chunk with the BVH over them, for tracing world space
+/// rays on the CPU.
+pub struct ChunkScene<'a> {
+  pub chunks: Vec<SceneChunk<'a>>,
+  pub bvh:    Bvh,
+}
+
+impl<'a> ChunkScene<'a> {
+  pub fn new(chunks: Vec<SceneChunk<'a>>) -> Self {
+    let bounds = chunks
+      .iter()
+      .map(|chunk| chunk_bounds(chunk.transform))
+      .collect::<Vec<_>>();
+    let bvh = Bvh::build(&bounds);
+    Self { chunks, bvh }
+  }
+
+  /// The nearest voxel along a world space ray.
+  pub fn raycast(&self, origin: Vec3, dir: Vec3) -> Option<SceneHit> {
+    self.traverse(origin, dir, false)
+  }
+
+  /// Whether a world space ray hits any voxel.
+  pub fn occluded(&self, origin: Vec3, dir: Vec3) -> bool {
+    self.traverse(origin, dir, true).is_some()
+  }
+
+  /// Marches one chunk, keeping the hit if it is nearer than `nearest`.
+  fn raycast_chunk(
+    &self,
+    chunk: usize,
+    origin: Vec3,
+    dir: Vec3,
+    nearest: &mut Option<SceneHit>,
+  ) {
+    // chunk space rays keep the world space ray parameter
+    let transform = self.chunks[chunk].transform;
+    let chunk_from_world = transform.inverse();
+    let Some(hit) = self.chunks[chunk]
+      .occupancy
+      .raycast(
+        chunk_from_world.transform_point3(origin),
+        chunk_from_world.transform_vector3(dir),
+      )
+      .hit
+    else {
+      return;
+    };
+    if nearest.is_some_and(|nearest| nearest.t <= hit.t) {
+      return;
+    }
+
+    let normal_from_chunk = Mat3::from_mat4(chunk_from_world).transpose();
+    *nearest = Some(SceneHit {
+      chunk,
+      voxel: hit.voxel,
+      t: hit.t,
+      normal: (normal_from_chunk * hit.normal.as_vec3()).normalize(),
+    });
+  }
+
+  /// Walks the BVH, marching the chunks whose bounds the ray enters.
+  /// Mirrors `raycast_scene` in `chunk.wgsl`.
+  fn traverse(
+    &self,
+    origin: Vec3,
+    dir: Vec3,
+    any_hit: bool,
+  ) -> Option<SceneHit> {
+    // nudge axes the ray runs parallel to, so they are just never crossed
+    let dir = Vec3::select(dir.cmpeq(Vec3::ZERO), Vec3::splat(1e-8), dir);
+
+    let mut nearest = None;
+    self.bvh.traverse(origin, dir.recip(), |chunk| {
+      self.raycast_chunk(chunk, origin, dir, &mut nearest);
+      match nearest {
+        Some(_) if any_hit => None,
+        Some(hit) => Some(hit.t),
+        None => Some(f32::INFINITY),
+      }
+    });
+    nearest
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::{
+    test_utils::{dense_mask, random_blocks},
+    voxel_pos,
+  };
+
+  /// The nearest voxel along a ray by testing it against every occupied
+  /// voxel of every chunk, as the chunk, the voxel and the ray parameter.
+  fn brute_force(
+    scene: &ChunkScene,
+    voxels: &[Vec<UVec3>],
+    origin: Vec3,
+    dir: Vec3,
+  ) -> Option<(usize, UVec3, f32)> {
+    let mut nearest: Option<(usize, UVec3, f32)> = None;
+    for (chunk, (scene_chunk, voxels)) in
+      scene.chunks.iter().zip(voxels).enumerate()
+    {
+      let chunk_from_world = scene_chunk.transform.inverse();
+      let origin = chunk_from_world.transform_point3(origin);
+      let inv_dir = chunk_from_world.transform_vector3(dir).recip();
+      for &voxel in voxels {
+        let t0 = (voxel.as_vec3() - origin) * inv_dir;
+        let t1 = (voxel.as_vec3() + 1.0 - origin) * inv_dir;
+        let t_enter = t0.min(t1).max_element().max(0.0);
+        let t_exit = t0.max(t1).min_element();
+        if t_enter <= t_exit && nearest.is_none_or(|(_, _, t)| t_enter < t) {
+          nearest = Some((chunk, voxel, t_enter));
+        }
+      }
+    }
+    nearest
+  }
+
+  #[test]
+  fn hits_what_brute_force_hits() {
+    let mut rng = fastrand::Rng::with_seed(24);
+    let size = CHUNK_SIZE as f32;
+
+    let data = (0..4)
+      .map(|_| random_blocks(&mut rng, 2, 0.02, 1))
+      .collect::<Vec<_>>();
+    let occupancies = data
+      .iter()
+      .map(|data| GpuChunkOccupancy::from_mask(dense_mask(data)))
+      .collect::<Vec<_>>();
+    let voxels = data
+      .iter()
+      .map(|data| {
+        (0..data.len())
+          .filter(|&i| data[i].is_some())
+          .map(voxel_pos)
+          .collect::<Vec<_>>()
+      })
+      .collect::<Vec<_>>();
+
+    // rotated chunks, some of them overlapping
+    let mut point = |extent: f32| {
+      (Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 2.0 - 1.0) * extent
+    };
+    let chunks = occupancies
+      .iter()
+      .map(|occupancy| {
+        let axis = point(1.0).normalize();
+        SceneChunk {
+          occupancy,
+          transform: Mat4::from_rotation_translation(
+            Quat::from_axis_angle(axis, axis.x * 3.0),
+            point(size),
+          ),
+        }
+      })
+      .collect();
+    let scene = ChunkScene::new(chunks);
+
+    for _ in 0..500 {
+      let origin = point(size * 3.0);
+      let dir = (point(size) - origin).normalize();
+      let hit = scene.raycast(origin, dir);
+      let expected = brute_force(&scene, &voxels, origin, dir);
+
+      match (hit, expected) {
+        (None, None) => {}
+        (Some(hit), Some((_, _, t))) => {
+          assert!(
+            (hit.t - t).abs() < 1e-3,
+            "hit at {} instead of {t}: {hit:?}",
+            hit.t
+          );
+          assert!(voxels[hit.chunk].contains(&hit.voxel), "{hit:?}");
+          assert!(hit.normal.dot(dir) < 0.0, "{hit:?}");
+        }
+        (hit, expected) => {
+          panic!("ray {origin} {dir}: hit {hit:?}, expected {expected:?}")
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,216 @@
+//! View uniforms for cameras rendered with the [`CoreVoxel`] graph.
+//!
+//! [`VoxelViewUniform`] has the layout of Bevy's own `ViewUniform`, so voxel
+//! shaders can declare the binding as `bevy_render::view::View` and share
+//! Bevy's helpers for it.
+
+use bevy::{
+  prelude::*,
+  render::{
+    camera::{ExtractedCamera, MipBias, TemporalJitter},
+    primitives::Frustum,
+    render_graph::RenderSubGraph,
+    render_resource::{
+      binding_types::uniform_buffer, BindGroup, BindGroupEntries,
+      BindGroupLayout, BindGroupLayoutEntries, DynamicUniformBuffer,
+      ShaderType,
+    },
+    renderer::{RenderDevice, RenderQueue},
+    view::{ColorGrading, ExtractedView, RenderLayers},
+    Render, RenderApp, RenderSet,
+  },
+};
+use wgpu::ShaderStages;
+
+use super::CoreVoxel;
+
+#[derive(Clone, ShaderType)]
+pub struct VoxelViewUniform {
+  view_proj:            Mat4,
+  unjittered_view_proj: Mat4,
+  inverse_view_proj:    Mat4,
+  view:                 Mat4,
+  inverse_view:         Mat4,
+  projection:           Mat4,
+  inverse_projection:   Mat4,
+  world_position:       Vec3,
+  exposure:             f32,
+  /// `origin.x, origin.y, width, height`, in physical pixels.
+  viewport:             Vec4,
+  frustum:              [Vec4; 6],
+  color_grading:        ColorGrading,
+  mip_bias:             f32,
+  render_layers:        u32,
+}
+
+impl VoxelViewUniform {
+  /// The uniform of a view, with the projection jittered by `jitter` if the
+  /// camera has one. Works for any projection, perspective or orthographic.
+  pub fn new(
+    view: &ExtractedView,
+    exposure: f32,
+    frustum: Option<&Frustum>,
+    jitter: Option<&TemporalJitter>,
+    mip_bias: f32,
+    render_layers: RenderLayers,
+  ) -> Self {
+    let viewport = view.viewport.as_vec4();
+    let unjittered_projection = view.projection;
+    let mut projection = unjittered_projection;
+    if let Some(jitter) = jitter {
+      jitter.jitter_projection(&mut projection, viewport.zw());
+    }
+
+    let world_from_view = view.transform.compute_matrix();
+    let view_from_world = world_from_view.inverse();
+    let inverse_projection = projection.inverse();
+    let view_proj = match jitter {
+      Some(_) => projection * view_from_world,
+      None => view
+        .view_projection
+        .unwrap_or_else(|| projection * view_from_world),
+    };
+
+    Self {
+      view_proj,
+      unjittered_view_proj: unjittered_projection * view_from_world,
+      inverse_view_proj: world_from_view * inverse_projection,
+      view: world_from_view,
+      inverse_view: view_from_world,
+      projection,
+      inverse_projection,
+      world_position: view.transform.translation(),
+      exposure,
+      viewport,
+      frustum: frustum
+        .map(|frustum| frustum.half_spaces.map(|h| h.normal_d()))
+        .unwrap_or([Vec4::ZERO; 6]),
+      color_grading: view.color_grading,
+      mip_bias,
+      render_layers: render_layers.bits(),
+    }
+  }
+}
+
+/// The view uniform of every voxel camera, in one dynamic buffer.
+#[derive(Resource, Default)]
+pub struct VoxelViewUniforms {
+  pub uniforms: DynamicUniformBuffer<VoxelViewUniform>,
+}
+
+/// The offset of a view's entry in [`VoxelViewUniforms`].
+#[derive(Component)]
+pub struct VoxelViewUniformOffset {
+  pub offset: u32,
+}
+
+/// The layout of [`VoxelViewBindGroup`], with the view uniform at binding 0.
+#[derive(Resource)]
+pub struct VoxelViewBindGroupLayout(pub BindGroupLayout);
+
+impl FromWorld for VoxelViewBindGroupLayout {
+  fn from_world(world: &mut World) -> Self {
+    Self(world.resource::<RenderDevice>().create_bind_group_layout(
+      "voxel_view_layout",
+      &BindGroupLayoutEntries::single(
+        ShaderStages::COMPUTE,
+        uniform_buffer::<VoxelViewUniform>(true),
+      ),
+    ))
+  }
+}
+
+/// The view bind group, bound by every node of the voxel graph with the
+/// view's [`VoxelViewUniformOffset`].
+#[derive(Component)]
+pub struct VoxelViewBindGroup(pub BindGroup);
+
+#[allow(clippy::type_complexity)]
+fn prepare_voxel_view_uniforms(
+  mut commands: Commands,
+  mut view_uniforms: ResMut<VoxelViewUniforms>,
+  views: Query<(
+    Entity,
+    &ExtractedCamera,
+    &ExtractedView,
+    Option<&Frustum>,
+    Option<&TemporalJitter>,
+    Option<&MipBias>,
+    Option<&RenderLayers>,
+  )>,
+  render_device: Res<RenderDevice>,
+  render_queue: Res<RenderQueue>,
+) {
+  let voxel_views = views
+    .iter()
+    .filter(|(_, camera, ..)| camera.render_graph == CoreVoxel.intern())
+    .collect::<Vec<_>>();
+  let Some(mut writer) = view_uniforms.uniforms.get_writer(
+    voxel_views.len(),
+    &render_device,
+    &render_queue,
+  ) else {
+    return;
+  };
+
+  for (entity, camera, view, frustum, jitter, mip_bias, layers) in voxel_views {
+    let uniform = VoxelViewUniform::new(
+      view,
+      camera.exposure,
+      frustum,
+      jitter,
+      mip_bias.map_or(0.0, |bias| bias.0),
+      layers.copied().unwrap_or_default(),
+    );
+    commands.entity(entity).insert(VoxelViewUniformOffset {
+      offset: writer.write(&uniform),
+    });
+  }
+}
+
+fn prepare_voxel_view_bind_groups(
+  mut commands: Commands,
+  layout: Res<VoxelViewBindGroupLayout>,
+  view_uniforms: Res<VoxelViewUniforms>,
+  views: Query<Entity, With<VoxelViewUniformOffset>>,
+  render_device: Res<RenderDevice>,
+) {
+  let Some(binding) = view_uniforms.uniforms.binding() else {
+    return;
+  };
+
+  for entity in views.iter() {
+    let bind_group = render_device.create_bind_group(
+      Some("voxel_view_bind_group"),
+      &layout.0,
+      &BindGroupEntries::single(binding.clone()),
+    );
+    commands
+      .entity(entity)
+      .insert(VoxelViewBindGroup(bind_group));
+  }
+}
+
+pub struct VoxelViewPlugin;
+
+impl Plugin for VoxelViewPlugin {
+  fn build(&self, app: &mut App) {
+    let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+      panic!("render_app not found");
+    };
+
+    render_app.init_resource::<VoxelViewUniforms>().add_systems(
+      Render,
+      (
+        prepare_voxel_view_uniforms.in_set(RenderSet::PrepareResources),
+        prepare_voxel_view_bind_groups.in_set(RenderSet::PrepareBindGroups),
+      ),
+    );
+  }
+
+  fn finish(&self, app: &mut App) {
+    app
+      .sub_app_mut(RenderApp)
+      .init_resource::<VoxelViewBindGroupLayout>();
+  }
+}
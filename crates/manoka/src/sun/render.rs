@@ -34,9 +34,12 @@ fn extract_sun_lights(
 
 #[derive(Clone, Debug, ShaderType)]
 pub struct GpuSunLight {
-  color:       [f32; 4],
-  illuminance: f32,
-  direction:   Vec3,
+  /// Linear RGBA.
+  pub color:       [f32; 4],
+  /// In lux.
+  pub illuminance: f32,
+  /// The direction the light travels in, in world space.
+  pub direction:   Vec3,
 }
 
 impl From<ExtractedSunLight> for GpuSunLight {
@@ -0,0 +1,218 @@
+//! Rule-based material layering for generated terrain.
+//!
+//! After terrain occupancy is generated, each solid voxel takes the material
+//! of the first matching rule of the biome it falls in. The rules are loaded
+//! from `.layers.ron` files, for example:
+//!
+//! ```ron
+//! (
+//!   biome_frequency: 0.001,
+//!   biomes: [
+//!     (
+//!       name: "temperate",
+//!       climate: (-1.0, 1.0),
+//!       layers: [
+//!         (color: (0.3, 0.6, 0.2), max_depth: Some(1.0), max_slope: Some(40.0)),
+//!         (color: (0.5, 0.5, 0.5)),
+//!       ],
+//!     ),
+//!   ],
+//!   fallback_color: (0.5, 0.5, 0.5),
+//! )
+//! ```
+
+use std::fmt;
+
+use bevy::{
+  asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+  prelude::*,
+  utils::BoxedFuture,
+};
+use serde::Deserialize;
+
+use crate::chunk::material::VoxelMaterial;
+
+/// The sRGB `color` as an opaque material.
+fn color_material((r, g, b): (f32, f32, f32)) -> VoxelMaterial {
+  let [r, g, b, _] = Color::rgb(r, g, b).as_linear_rgba_f32();
+  VoxelMaterial::from_color(Vec3::new(r, g, b))
+}
+
+/// Where a voxel sits in the terrain, as seen by the layering rules.
+#[derive(Clone, Copy, Debug)]
+pub struct LayerSample {
+  /// The world height of the voxel center.
+  pub height:  f32,
+  /// How far the voxel lies below the terrain surface of its column.
+  pub depth:   f32,
+  /// The steepness of the terrain surface above the voxel, in degrees.
+  pub slope:   f32,
+  /// The value of the biome map at the voxel's column, in `-1..=1`.
+  pub climate: f32,
+}
+
+/// A stratum of terrain material. Every bound that is set must hold for the
+/// rule to match.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LayerRule {
+  /// The sRGB color given to matching voxels.
+  pub color:      (f32, f32, f32),
+  #[serde(default)]
+  pub min_height: Option<f32>,
+  #[serde(default)]
+  pub max_height: Option<f32>,
+  #[serde(default)]
+  pub min_depth:  Option<f32>,
+  #[serde(default)]
+  pub max_depth:  Option<f32>,
+  #[serde(default)]
+  pub min_slope:  Option<f32>,
+  #[serde(default)]
+  pub max_slope:  Option<f32>,
+}
+
+impl LayerRule {
+  fn matches(&self, sample: &LayerSample) -> bool {
+    let above = |value: f32, min: Option<f32>| min.is_none_or(|m| value >= m);
+    let below = |value: f32, max: Option<f32>| max.is_none_or(|m| value < m);
+    above(sample.height, self.min_height)
+      && below(sample.height, self.max_height)
+      && above(sample.depth, self.min_depth)
+      && below(sample.depth, self.max_depth)
+      && above(sample.slope, self.min_slope)
+      && below(sample.slope, self.max_slope)
+  }
+}
+
+/// The layering rules of one region of the biome map.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Biome {
+  /// Only identifies the biome in the data file.
+  pub name:    String,
+  /// The range of the biome map this biome covers.
+  pub climate: (f32, f32),
+  /// Tried in order; the first match gives the voxel its material.
+  pub layers:  Vec<LayerRule>,
+}
+
+/// The complete set of layering rules for a world.
+#[derive(Asset, TypePath, Clone, Debug, Deserialize)]
+pub struct TerrainLayers {
+  /// The frequency of the biome map, in cycles per voxel.
+  pub biome_frequency: f32,
+  /// Tried in order; the first whose climate range holds the biome map
+  /// value is used.
+  pub biomes:          Vec<Biome>,
+  /// The sRGB color of voxels that no rule matches.
+  pub fallback_color:  (f32, f32, f32),
+}
+
+impl TerrainLayers {
+  /// The biome and layer index of the rule that matches a voxel, if any.
+  pub fn find(&self, sample: &LayerSample) -> Option<(usize, usize)> {
+    let biome = self.biomes.iter().position(|biome| {
+      (biome.climate.0..=biome.climate.1).contains(&sample.climate)
+    })?;
+    let layer = self.biomes[biome]
+      .layers
+      .iter()
+      .position(|layer| layer.matches(sample))?;
+    Some((biome, layer))
+  }
+
+  /// The material of every rule, indexed like the rules.
+  pub fn layer_materials(&self) -> Vec<Vec<VoxelMaterial>> {
+    self
+      .biomes
+      .iter()
+      .map(|biome| {
+        biome
+          .layers
+          .iter()
+          .map(|layer| color_material(layer.color))
+          .collect()
+      })
+      .collect()
+  }
+
+  /// The material of voxels that no rule matches.
+  pub fn fallback_material(&self) -> VoxelMaterial {
+    color_material(self.fallback_color)
+  }
+}
+
+impl Default for TerrainLayers {
+  /// A single biome of grass over dirt over stone.
+  fn default() -> Self {
+    let rule = |color, max_depth| LayerRule {
+      color,
+      min_height: None,
+      max_height: None,
+      min_depth: None,
+      max_depth,
+      min_slope: None,
+      max_slope: None,
+    };
+    Self {
+      biome_frequency: 0.0,
+      biomes:          vec![Biome {
+        name:    "default".to_string(),
+        climate: (-1.0, 1.0),
+        layers:  vec![
+          rule((0.38, 0.63, 0.27), Some(1.0)),
+          rule((0.58, 0.46, 0.33), Some(4.0)),
+        ],
+      }],
+      fallback_color:  (0.63, 0.63, 0.64),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum LayersError {
+  Io(std::io::Error),
+  Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for LayersError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LayersError::Io(e) => write!(f, "failed to read terrain layers: {e}"),
+      LayersError::Ron(e) => write!(f, "invalid terrain layers: {e}"),
+    }
+  }
+}
+
+impl std::error::Error for LayersError {}
+
+impl From<std::io::Error> for LayersError {
+  fn from(value: std::io::Error) -> Self { LayersError::Io(value) }
+}
+
+impl From<ron::error::SpannedError> for LayersError {
+  fn from(value: ron::error::SpannedError) -> Self { LayersError::Ron(value) }
+}
+
+#[derive(Default)]
+pub struct TerrainLayersLoader;
+
+impl AssetLoader for TerrainLayersLoader {
+  type Asset = TerrainLayers;
+  type Settings = ();
+  type Error = LayersError;
+
+  fn load<'a>(
+    &'a self,
+    reader: &'a mut Reader,
+    _settings: &'a (),
+    _load_context: &'a mut LoadContext,
+  ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+    Box::pin(async move {
+      let mut bytes = Vec::new();
+      reader.read_to_end(&mut bytes).await?;
+      Ok(ron::de::from_bytes(&bytes)?)
+    })
+  }
+
+  fn extensions(&self) -> &[&str] { &["layers.ron"] }
+}
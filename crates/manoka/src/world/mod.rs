@@ -0,0 +1,263 @@
+pub mod layers;
+pub mod streaming;
+pub mod terrain;
+
+use std::fmt;
+
+use bevy::{prelude::*, transform::TransformSystem, utils::HashMap};
+
+use self::{
+  layers::{TerrainLayers, TerrainLayersLoader},
+  streaming::ChunkStreamingPlugin,
+};
+use crate::{
+  chunk::{normals::NormalKernel, Chunk, FullVoxel},
+  CHUNK_SIZE,
+};
+
+/// The position of a chunk entity on the chunk grid, in chunks.
+///
+/// The chunk's `Transform` translation is kept at `ChunkPos * CHUNK_SIZE`.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Component, Reflect, Deref,
+)]
+#[reflect(Component)]
+pub struct ChunkPos(pub IVec3);
+
+impl ChunkPos {
+  /// The world-space voxel position of the chunk's minimum corner.
+  pub fn origin(&self) -> IVec3 { self.0 * CHUNK_SIZE as i32 }
+}
+
+/// No chunk is loaded at the given chunk grid position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkNotLoaded(pub IVec3);
+
+impl fmt::Display for ChunkNotLoaded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "no chunk is loaded at chunk position {}", self.0)
+  }
+}
+
+impl std::error::Error for ChunkNotLoaded {}
+
+/// Maps the chunk grid to chunk entities, for addressing voxels in world
+/// space.
+///
+/// Edits go through the chunk's asset, so entities sharing a
+/// `Handle<Chunk>` see each other's edits.
+#[derive(Resource, Default)]
+pub struct VoxelWorld {
+  chunks:    HashMap<IVec3, (Entity, Handle<Chunk>)>,
+  /// The position each chunk entity is registered at.
+  positions: HashMap<Entity, IVec3>,
+}
+
+impl VoxelWorld {
+  /// Splits a world voxel position into its chunk position and its position
+  /// within that chunk.
+  pub fn split(pos: IVec3) -> (IVec3, UVec3) {
+    let size = IVec3::splat(CHUNK_SIZE as i32);
+    (pos.div_euclid(size), pos.rem_euclid(size).as_uvec3())
+  }
+
+  /// The inverse of [`VoxelWorld::split`].
+  pub fn join(chunk_pos: IVec3, local: UVec3) -> IVec3 {
+    chunk_pos * CHUNK_SIZE as i32 + local.as_ivec3()
+  }
+
+  /// The number of chunks on the grid.
+  pub fn chunk_count(&self) -> usize { self.chunks.len() }
+
+  /// The entity of the chunk at `chunk_pos`, if one is loaded.
+  pub fn chunk_entity(&self, chunk_pos: IVec3) -> Option<Entity> {
+    self.chunks.get(&chunk_pos).map(|(entity, _)| *entity)
+  }
+
+  /// The asset of the chunk at `chunk_pos`, if one is loaded.
+  pub fn chunk_handle(&self, chunk_pos: IVec3) -> Option<&Handle<Chunk>> {
+    self.chunks.get(&chunk_pos).map(|(_, handle)| handle)
+  }
+
+  /// Returns the voxel at world position `pos`, or `None` if it is empty or
+  /// its chunk isn't loaded.
+  pub fn get_voxel<'a>(
+    &self,
+    chunks: &'a Assets<Chunk>,
+    pos: IVec3,
+  ) -> Option<&'a FullVoxel> {
+    let (chunk_pos, local) = Self::split(pos);
+    let chunk = chunks.get(self.chunk_handle(chunk_pos)?)?;
+    chunk.get(local).ok().flatten()
+  }
+
+  /// Writes the voxel at world position `pos`. A `voxel` of `None` clears it.
+  pub fn set_voxel(
+    &self,
+    chunks: &mut Assets<Chunk>,
+    pos: IVec3,
+    voxel: Option<FullVoxel>,
+  ) -> Result<(), ChunkNotLoaded> {
+    let (chunk_pos, local) = Self::split(pos);
+    let chunk = self
+      .chunk_handle(chunk_pos)
+      .and_then(|handle| chunks.get_mut(handle))
+      .ok_or(ChunkNotLoaded(chunk_pos))?;
+
+    // `split` always produces an in-bounds local position
+    match voxel {
+      Some(voxel) => chunk.set(local, voxel),
+      None => chunk.clear(local),
+    }
+    .expect("local voxel position out of bounds");
+    Ok(())
+  }
+
+  /// Re-estimates the normals of the chunk at `chunk_pos` from occupancy,
+  /// reading across its borders into any loaded neighbors. Unloaded
+  /// neighbors count as empty.
+  pub fn derive_normals(
+    &self,
+    chunks: &mut Assets<Chunk>,
+    chunk_pos: IVec3,
+    kernel: NormalKernel,
+  ) -> Result<(), ChunkNotLoaded> {
+    let handle = self
+      .chunk_handle(chunk_pos)
+      .ok_or(ChunkNotLoaded(chunk_pos))?;
+    let mut chunk =
+      chunks.get(handle).ok_or(ChunkNotLoaded(chunk_pos))?.clone();
+    let origin = chunk_pos * CHUNK_SIZE as i32;
+    chunk.derive_normals(kernel, |local| {
+      self.get_voxel(chunks, origin + local).is_some()
+    });
+    chunks.insert(handle.id(), chunk);
+    Ok(())
+  }
+
+  /// Drops `entity` from the grid, unless another chunk has taken its
+  /// position since.
+  fn unregister(&mut self, entity: Entity) {
+    let Some(chunk_pos) = self.positions.remove(&entity) else {
+      return;
+    };
+    if self.chunk_entity(chunk_pos) == Some(entity) {
+      self.chunks.remove(&chunk_pos);
+    }
+  }
+}
+
+/// Updates the grid map for chunks that were added, moved or removed, and
+/// moves chunk entities to their grid position.
+#[allow(clippy::type_complexity)]
+fn sync_chunk_grid(
+  mut world: ResMut<VoxelWorld>,
+  mut moved: Query<(&ChunkPos, &mut Transform), Changed<ChunkPos>>,
+  changed: Query<
+    (Entity, &ChunkPos, &Handle<Chunk>),
+    Or<(Changed<ChunkPos>, Changed<Handle<Chunk>>)>,
+  >,
+  mut removed_positions: RemovedComponents<ChunkPos>,
+  mut removed_handles: RemovedComponents<Handle<Chunk>>,
+) {
+  for (chunk_pos, mut transform) in moved.iter_mut() {
+    transform.translation = chunk_pos.origin().as_vec3();
+  }
+
+  for entity in removed_positions.read().chain(removed_handles.read()) {
+    world.unregister(entity);
+  }
+
+  for (entity, chunk_pos, handle) in changed.iter() {
+    world.unregister(entity);
+    if let Some((other, _)) =
+      world.chunks.insert(chunk_pos.0, (entity, handle.clone()))
+    {
+      warn!(
+        "chunks {other:?} and {entity:?} share chunk position {}",
+        chunk_pos.0
+      );
+      world.positions.remove(&other);
+    }
+    world.positions.insert(entity, chunk_pos.0);
+  }
+}
+
+pub struct VoxelWorldPlugin;
+
+impl Plugin for VoxelWorldPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .register_type::<ChunkPos>()
+      .init_resource::<VoxelWorld>()
+      .init_asset::<TerrainLayers>()
+      .init_asset_loader::<TerrainLayersLoader>()
+      .add_plugins(ChunkStreamingPlugin)
+      .add_systems(Update, terrain::update_terrain_generator)
+      .add_systems(
+        PostUpdate,
+        sync_chunk_grid.before(TransformSystem::TransformPropagate),
+      );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn app() -> App {
+    let mut app = App::new();
+    app
+      .init_resource::<VoxelWorld>()
+      .add_systems(Update, sync_chunk_grid);
+    app
+  }
+
+  fn spawn_chunk(app: &mut App, chunk_pos: IVec3) -> Entity {
+    let bundle = (
+      ChunkPos(chunk_pos),
+      Handle::<Chunk>::default(),
+      Transform::default(),
+    );
+    app.world.spawn(bundle).id()
+  }
+
+  fn grid(app: &App) -> &VoxelWorld { app.world.resource::<VoxelWorld>() }
+
+  #[test]
+  fn tracks_added_moved_and_removed_chunks() {
+    let mut app = app();
+    let a = spawn_chunk(&mut app, IVec3::ZERO);
+    let b = spawn_chunk(&mut app, IVec3::X);
+    app.update();
+    assert_eq!(grid(&app).chunk_entity(IVec3::ZERO), Some(a));
+    assert_eq!(grid(&app).chunk_entity(IVec3::X), Some(b));
+    let origin = ChunkPos(IVec3::X).origin().as_vec3();
+    assert_eq!(app.world.get::<Transform>(b).unwrap().translation, origin);
+
+    app.world.get_mut::<ChunkPos>(a).unwrap().0 = IVec3::Y;
+    app.update();
+    assert_eq!(grid(&app).chunk_entity(IVec3::ZERO), None);
+    assert_eq!(grid(&app).chunk_entity(IVec3::Y), Some(a));
+
+    app.world.despawn(b);
+    app.world.entity_mut(a).remove::<ChunkPos>();
+    app.update();
+    assert_eq!(grid(&app).chunk_count(), 0);
+  }
+
+  #[test]
+  fn keeps_a_chunk_that_took_a_removed_chunks_position() {
+    let mut app = app();
+    let a = spawn_chunk(&mut app, IVec3::ZERO);
+    app.update();
+
+    // `b` replaces `a` at the origin, and `a` leaving mustn't drop `b`
+    let b = spawn_chunk(&mut app, IVec3::ZERO);
+    app.update();
+    app.world.despawn(a);
+    app.update();
+    assert_eq!(grid(&app).chunk_entity(IVec3::ZERO), Some(b));
+    assert_eq!(grid(&app).chunk_count(), 1);
+  }
+}
@@ -0,0 +1,498 @@
+//! Loads and unloads chunks around the active camera.
+//!
+//! Every frame the grid cells within [`ChunkStreaming::load_radius`] of the
+//! camera that aren't resident are queued, nearest first, until the world
+//! holds [`MAX_CHUNKS`]. Reading a chunk from disk or generating it happens
+//! on the [`AsyncComputeTaskPool`], and the chunk entity is spawned once the
+//! task finishes, unless the cell left the load radius in the meantime.
+//! Chunks past [`ChunkStreaming::unload_radius`] are despawned, after saving
+//! them if they were edited. Until a save lands, its cell is reloaded from the
+//! chunk being saved rather than from disk.
+
+use std::{
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+use bevy::{
+  prelude::*,
+  tasks::{block_on, poll_once, AsyncComputeTaskPool, Task},
+  utils::{HashMap, HashSet},
+};
+
+use super::{ChunkPos, VoxelWorld};
+use crate::{
+  chunk::{material::VoxelMaterials, region::RegionStore, Chunk},
+  CHUNK_SIZE, MAX_CHUNKS,
+};
+
+/// Produces the contents of a grid cell that has never been saved, or `None`
+/// if the cell is empty.
+pub type ChunkGenerator = Arc<dyn Fn(IVec3) -> Option<Chunk> + Send + Sync>;
+
+/// Configures chunk streaming.
+#[derive(Resource)]
+pub struct ChunkStreaming {
+  /// Cells whose center is within this many chunks of the camera are loaded.
+  pub load_radius:     f32,
+  /// Chunks whose center is farther than this many chunks from the camera
+  /// are unloaded. Keeping this above `load_radius` stops chunks on the
+  /// boundary from reloading every time the camera moves.
+  pub unload_radius:   f32,
+  /// The most loads started in a single frame.
+  pub loads_per_frame: usize,
+  /// Where chunks are loaded from and edited chunks are saved to.
+  pub save_dir:        Option<PathBuf>,
+  /// Fills cells that aren't found in `save_dir`.
+  pub generator:       Option<ChunkGenerator>,
+}
+
+impl Default for ChunkStreaming {
+  fn default() -> Self {
+    Self {
+      load_radius:     3.0,
+      unload_radius:   4.0,
+      loads_per_frame: 8,
+      save_dir:        None,
+      generator:       None,
+    }
+  }
+}
+
+/// Marks a chunk entity as owned by streaming, which may unload it.
+#[derive(Component)]
+pub struct StreamedChunk;
+
+/// A save that is in flight.
+struct PendingSave {
+  /// The chunk being written, which is newer than the cell on disk.
+  chunk: Arc<Chunk>,
+  task:  Task<()>,
+}
+
+/// The bookkeeping of chunk streaming.
+#[derive(Resource, Default)]
+struct StreamingState {
+  /// The region store for `save_dir`, shared with the load tasks.
+  store:    Option<(PathBuf, Arc<Mutex<RegionStore>>)>,
+  /// Loads that are in flight.
+  pending:  HashMap<IVec3, Task<Option<Chunk>>>,
+  /// Saves that are in flight, by cell.
+  saving:   HashMap<IVec3, PendingSave>,
+  /// Loaded cells that turned out to be empty, so they aren't loaded again
+  /// while they stay in range.
+  empty:    HashSet<IVec3>,
+  /// Streamed chunks edited since they were loaded.
+  modified: HashSet<AssetId<Chunk>>,
+}
+
+impl StreamingState {
+  /// The region store for `save_dir`, opened on first use.
+  fn store(
+    &mut self,
+    save_dir: Option<&PathBuf>,
+    materials: &VoxelMaterials,
+  ) -> Option<Arc<Mutex<RegionStore>>> {
+    let save_dir = save_dir?;
+    match &self.store {
+      Some((dir, store)) if dir == save_dir => Some(store.clone()),
+      _ => {
+        let store = Arc::new(Mutex::new(RegionStore::new(
+          save_dir.clone(),
+          materials.clone(),
+        )));
+        self.store = Some((save_dir.clone(), store.clone()));
+        Some(store)
+      }
+    }
+  }
+}
+
+/// The center of the active camera, in chunks.
+fn camera_chunk_center(
+  cameras: &Query<(&Camera, &GlobalTransform)>,
+) -> Option<Vec3> {
+  let (_, transform) = cameras
+    .iter()
+    .filter(|(camera, _)| camera.is_active)
+    .min_by_key(|(camera, _)| camera.order)?;
+  Some(transform.translation() / CHUNK_SIZE as f32)
+}
+
+/// The distance in chunks from `center` to the center of the cell.
+fn cell_distance(cell: IVec3, center: Vec3) -> f32 {
+  (cell.as_vec3() + 0.5).distance(center)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn queue_chunk_loads(
+  mut commands: Commands,
+  streaming: Res<ChunkStreaming>,
+  world: Res<VoxelWorld>,
+  mut state: ResMut<StreamingState>,
+  mut chunks: ResMut<Assets<Chunk>>,
+  materials: Res<VoxelMaterials>,
+  cameras: Query<(&Camera, &GlobalTransform)>,
+) {
+  let Some(center) = camera_chunk_center(&cameras) else {
+    return;
+  };
+  // nothing to load from yet, and loading would mark every cell empty
+  if streaming.save_dir.is_none() && streaming.generator.is_none() {
+    return;
+  }
+
+  let budget = MAX_CHUNKS
+    .saturating_sub(world.chunk_count() + state.pending.len())
+    .min(streaming.loads_per_frame);
+  if budget == 0 {
+    return;
+  }
+
+  let radius = streaming.load_radius;
+  let min = (center - radius).floor().as_ivec3();
+  let max = (center + radius).ceil().as_ivec3();
+  let mut cells = Vec::new();
+  for z in min.z..=max.z {
+    for y in min.y..=max.y {
+      for x in min.x..=max.x {
+        let cell = IVec3::new(x, y, z);
+        if cell_distance(cell, center) <= radius
+          && world.chunk_entity(cell).is_none()
+          && !state.pending.contains_key(&cell)
+          && !state.empty.contains(&cell)
+        {
+          cells.push(cell);
+        }
+      }
+    }
+  }
+  cells.sort_by(|a, b| {
+    cell_distance(*a, center).total_cmp(&cell_distance(*b, center))
+  });
+
+  let store = state.store(streaming.save_dir.as_ref(), &materials);
+  let pool = AsyncComputeTaskPool::get();
+  for cell in cells.into_iter().take(budget) {
+    // a chunk that is still being saved is newer than the one on disk
+    if let Some(save) = state.saving.get(&cell) {
+      spawn_chunk(&mut commands, &mut chunks, cell, Chunk::clone(&save.chunk));
+      continue;
+    }
+    let store = store.clone();
+    let generator = streaming.generator.clone();
+    let task = pool.spawn(async move {
+      let saved = store.and_then(|store| {
+        store.lock().unwrap().load_chunk(cell).unwrap_or_else(|e| {
+          error!("failed to load chunk {cell}: {e}");
+          None
+        })
+      });
+      saved.or_else(|| generator.and_then(|generate| generate(cell)))
+    });
+    state.pending.insert(cell, task);
+  }
+}
+
+fn finish_chunk_loads(
+  mut commands: Commands,
+  mut state: ResMut<StreamingState>,
+  mut chunks: ResMut<Assets<Chunk>>,
+) {
+  state.saving.retain(|_, save| !save.task.is_finished());
+
+  let mut finished = Vec::new();
+  for (cell, task) in state.pending.iter_mut() {
+    if let Some(chunk) = block_on(poll_once(task)) {
+      finished.push((*cell, chunk));
+    }
+  }
+
+  for (cell, chunk) in finished {
+    state.pending.remove(&cell);
+    match chunk {
+      Some(chunk) => spawn_chunk(&mut commands, &mut chunks, cell, chunk),
+      None => {
+        state.empty.insert(cell);
+      }
+    }
+  }
+}
+
+fn spawn_chunk(
+  commands: &mut Commands,
+  chunks: &mut Assets<Chunk>,
+  cell: IVec3,
+  chunk: Chunk,
+) {
+  commands.spawn((
+    chunks.add(chunk),
+    ChunkPos(cell),
+    StreamedChunk,
+    SpatialBundle::default(),
+    Name::new(format!("chunk {cell}")),
+  ));
+}
+
+fn track_modified_chunks(
+  mut state: ResMut<StreamingState>,
+  mut events: EventReader<AssetEvent<Chunk>>,
+) {
+  for event in events.read() {
+    match event {
+      AssetEvent::Modified { id } => {
+        state.modified.insert(*id);
+      }
+      AssetEvent::Removed { id } => {
+        state.modified.remove(id);
+      }
+      _ => {}
+    }
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn unload_chunks(
+  mut commands: Commands,
+  streaming: Res<ChunkStreaming>,
+  world: Res<VoxelWorld>,
+  mut state: ResMut<StreamingState>,
+  chunks: Res<Assets<Chunk>>,
+  materials: Res<VoxelMaterials>,
+  cameras: Query<(&Camera, &GlobalTransform)>,
+  streamed: Query<(Entity, &ChunkPos, &Handle<Chunk>), With<StreamedChunk>>,
+) {
+  let Some(center) = camera_chunk_center(&cameras) else {
+    return;
+  };
+
+  let unload_radius = streaming.unload_radius;
+  state
+    .empty
+    .retain(|cell| cell_distance(*cell, center) <= unload_radius);
+  // dropping a task cancels it
+  let load_radius = streaming.load_radius;
+  state
+    .pending
+    .retain(|cell, _| cell_distance(*cell, center) <= load_radius);
+
+  let mut distant = streamed
+    .iter()
+    .filter(|(_, chunk_pos, _)| {
+      cell_distance(chunk_pos.0, center) > unload_radius
+    })
+    .collect::<Vec<_>>();
+
+  // if the world is over capacity, shed the farthest chunks as well
+  let excess = world.chunk_count().saturating_sub(MAX_CHUNKS);
+  if excess > distant.len() {
+    let mut by_distance = streamed.iter().collect::<Vec<_>>();
+    by_distance.sort_by(|(_, a, _), (_, b, _)| {
+      cell_distance(b.0, center).total_cmp(&cell_distance(a.0, center))
+    });
+    distant = by_distance.into_iter().take(excess).collect();
+  }
+
+  let store = state.store(streaming.save_dir.as_ref(), &materials);
+  let pool = AsyncComputeTaskPool::get();
+  for (entity, chunk_pos, handle) in distant {
+    commands.entity(entity).despawn_recursive();
+
+    if !state.modified.remove(&handle.id()) {
+      continue;
+    }
+    let (Some(store), Some(chunk)) = (store.clone(), chunks.get(handle)) else {
+      continue;
+    };
+    let (cell, chunk) = (chunk_pos.0, Arc::new(chunk.clone()));
+    // saves of one cell must land in order, so wait for the previous one
+    let previous = state.saving.remove(&cell).map(|save| save.task);
+    let task = pool.spawn({
+      let chunk = chunk.clone();
+      async move {
+        if let Some(previous) = previous {
+          previous.await;
+        }
+        if let Err(e) = store.lock().unwrap().save_chunk(cell, &chunk) {
+          error!("failed to save chunk {cell}: {e}");
+        }
+      }
+    });
+    state.saving.insert(cell, PendingSave { chunk, task });
+  }
+}
+
+pub struct ChunkStreamingPlugin;
+
+impl Plugin for ChunkStreamingPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<ChunkStreaming>()
+      .init_resource::<StreamingState>()
+      .add_systems(
+        Update,
+        (
+          track_modified_chunks,
+          unload_chunks,
+          queue_chunk_loads,
+          finish_chunk_loads,
+        )
+          .chain(),
+      );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs,
+    time::{Duration, Instant},
+  };
+
+  use super::*;
+  use crate::chunk::{
+    material::{MaterialId, VoxelMaterial},
+    FullVoxel,
+  };
+
+  struct TempDir(PathBuf);
+
+  impl TempDir {
+    fn new(name: &str) -> Self {
+      let dir = format!("manoka-{name}-{}", std::process::id());
+      let path = std::env::temp_dir().join(dir);
+      let _ = fs::remove_dir_all(&path);
+      Self(path)
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+  }
+
+  /// An app streaming just the cell the camera is in, and a material to edit
+  /// its chunk with.
+  fn app(streaming: ChunkStreaming) -> (App, MaterialId) {
+    let mut app = App::new();
+    app
+      .add_plugins((MinimalPlugins, AssetPlugin::default()))
+      .init_asset::<Chunk>()
+      .init_resource::<VoxelMaterials>()
+      .init_resource::<VoxelWorld>()
+      .insert_resource(ChunkStreaming {
+        load_radius: 0.5,
+        unload_radius: 0.5,
+        ..streaming
+      })
+      .add_plugins(ChunkStreamingPlugin)
+      .add_systems(PostUpdate, super::super::sync_chunk_grid);
+    let red = VoxelMaterial::from_color(Vec3::X);
+    let material = app.world.resource::<VoxelMaterials>().intern(&red);
+    app
+      .world
+      .spawn((Camera::default(), GlobalTransform::default()));
+    move_camera(&mut app, IVec3::ZERO);
+    (app, material)
+  }
+
+  fn move_camera(app: &mut App, cell: IVec3) {
+    let center = (cell.as_vec3() + 0.5) * CHUNK_SIZE as f32;
+    let mut cameras = app
+      .world
+      .query_filtered::<&mut GlobalTransform, With<Camera>>();
+    *cameras.single_mut(&mut app.world) =
+      GlobalTransform::from_translation(center);
+  }
+
+  fn update_until(app: &mut App, done: impl Fn(&World) -> bool) {
+    let start = Instant::now();
+    while !done(&app.world) {
+      assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+      app.update();
+      std::thread::sleep(Duration::from_millis(1));
+    }
+  }
+
+  fn state(world: &World) -> &StreamingState {
+    world.resource::<StreamingState>()
+  }
+
+  fn chunk_count(world: &World) -> usize {
+    world.resource::<VoxelWorld>().chunk_count()
+  }
+
+  #[test]
+  fn reloads_are_served_from_pending_saves() {
+    let dir = TempDir::new("pending-saves");
+    let (mut app, material) = app(ChunkStreaming {
+      save_dir: Some(dir.0.clone()),
+      generator: Some(Arc::new(|_| Some(Chunk::new_empty()))),
+      ..default()
+    });
+    update_until(&mut app, |world| chunk_count(world) == 1);
+
+    let voxel = FullVoxel::new(Vec3::Y, material);
+    app
+      .world
+      .resource_scope(|world, mut chunks: Mut<Assets<Chunk>>| {
+        let grid = world.resource::<VoxelWorld>();
+        grid
+          .set_voxel(&mut chunks, IVec3::ZERO, Some(voxel.clone()))
+          .unwrap();
+      });
+    app.update();
+
+    // hold the store so the save can't land before the chunk reloads
+    let store = state(&app.world).store.as_ref().unwrap().1.clone();
+    let guard = store.lock().unwrap();
+    app.world.resource_mut::<ChunkStreaming>().loads_per_frame = 0;
+    move_camera(&mut app, IVec3::X * 10);
+    update_until(&mut app, |world| chunk_count(world) == 0);
+    assert!(state(&app.world).saving.contains_key(&IVec3::ZERO));
+
+    app.world.resource_mut::<ChunkStreaming>().loads_per_frame = 8;
+    move_camera(&mut app, IVec3::ZERO);
+    update_until(&mut app, |world| chunk_count(world) == 1);
+    app
+      .world
+      .resource_scope(|world, chunks: Mut<Assets<Chunk>>| {
+        let grid = world.resource::<VoxelWorld>();
+        assert_eq!(grid.get_voxel(&chunks, IVec3::ZERO), Some(&voxel));
+      });
+
+    drop(guard);
+    update_until(&mut app, |world| state(world).saving.is_empty());
+    let saved = store.lock().unwrap().load_chunk(IVec3::ZERO).unwrap();
+    assert_eq!(saved.unwrap().get(UVec3::ZERO).unwrap(), Some(&voxel));
+  }
+
+  #[test]
+  fn drops_loads_that_leave_the_load_radius() {
+    // hold the generator so the load stays in flight
+    let gate = Arc::new(Mutex::new(()));
+    let guard = gate.lock().unwrap();
+    let (mut app, _) = app(ChunkStreaming {
+      generator: Some(Arc::new({
+        let gate = gate.clone();
+        move |_| {
+          let _gate = gate.lock().unwrap();
+          Some(Chunk::new_empty())
+        }
+      })),
+      ..default()
+    });
+    app.update();
+    assert!(state(&app.world).pending.contains_key(&IVec3::ZERO));
+
+    app.world.resource_mut::<ChunkStreaming>().loads_per_frame = 0;
+    move_camera(&mut app, IVec3::X * 10);
+    app.update();
+    assert!(state(&app.world).pending.is_empty());
+
+    drop(guard);
+    for _ in 0..10 {
+      app.update();
+    }
+    assert_eq!(chunk_count(&app.world), 0);
+  }
+}
@@ -0,0 +1,547 @@
+//! Deterministic procedural terrain.
+//!
+//! Terrain is a fractal noise heightmap with 3D noise caves carved out of it,
+//! given materials afterwards by the rules in [`TerrainLayers`].
+//! Every voxel is a pure function of its world position and the seed, so
+//! neighboring chunks meet seamlessly and a seed always produces the same
+//! world.
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+use super::{
+  layers::{LayerSample, TerrainLayers},
+  streaming::{ChunkGenerator, ChunkStreaming},
+  VoxelWorld,
+};
+use crate::{
+  chunk::{
+    material::{MaterialId, VoxelMaterials},
+    voxel_index, voxel_pos, Chunk, ChunkData, FullVoxel,
+  },
+  CHUNK_SIZE, CHUNK_VOXEL_COUNT,
+};
+
+/// Tunes the shape of generated terrain. Frequencies are in cycles per voxel
+/// and heights in voxels.
+#[derive(Clone, Debug)]
+pub struct TerrainSettings {
+  pub seed:              u32,
+  /// The height the heightmap varies around.
+  pub base_height:       f32,
+  /// The largest distance the heightmap strays from `base_height`.
+  pub height_amplitude:  f32,
+  pub height_frequency:  f32,
+  /// The number of noise layers summed into the heightmap.
+  pub octaves:           u32,
+  /// How much the frequency grows with each octave.
+  pub lacunarity:        f32,
+  /// How much the amplitude shrinks with each octave.
+  pub persistence:       f32,
+  pub cave_frequency:    f32,
+  /// Caves are carved where the cave noise is within this distance of zero,
+  /// so larger values make wider tunnels.
+  pub cave_width:        f32,
+  /// Caves don't break the surface within this many voxels of it.
+  pub cave_surface_skin: f32,
+}
+
+impl Default for TerrainSettings {
+  fn default() -> Self {
+    Self {
+      seed:              0,
+      base_height:       0.0,
+      height_amplitude:  48.0,
+      height_frequency:  1.0 / 256.0,
+      octaves:           5,
+      lacunarity:        2.0,
+      persistence:       0.5,
+      cave_frequency:    1.0 / 64.0,
+      cave_width:        0.06,
+      cave_surface_skin: 4.0,
+    }
+  }
+}
+
+/// Generates terrain chunks from [`TerrainSettings`], with materials from
+/// [`TerrainLayers`].
+#[derive(Clone, Debug)]
+pub struct TerrainGenerator {
+  pub settings:      TerrainSettings,
+  pub layers:        TerrainLayers,
+  /// The material id of each layer rule, indexed like the rules.
+  layer_materials:   Vec<Vec<MaterialId>>,
+  fallback_material: MaterialId,
+}
+
+impl TerrainGenerator {
+  /// Creates a generator, adding the materials of `layers` to `materials`.
+  pub fn new(
+    settings: TerrainSettings,
+    layers: TerrainLayers,
+    materials: &VoxelMaterials,
+  ) -> Self {
+    let layer_materials = layers
+      .layer_materials()
+      .iter()
+      .map(|biome| biome.iter().map(|m| materials.intern(m)).collect())
+      .collect();
+    let fallback_material = materials.intern(&layers.fallback_material());
+    Self {
+      settings,
+      layers,
+      layer_materials,
+      fallback_material,
+    }
+  }
+
+  /// Wraps the generator for use by chunk streaming.
+  pub fn into_chunk_generator(self) -> ChunkGenerator {
+    Arc::new(move |chunk_pos| self.generate(chunk_pos))
+  }
+
+  /// The terrain surface height of the column at world `(x, z)`.
+  pub fn height(&self, x: f32, z: f32) -> f32 {
+    let s = &self.settings;
+    let p = Vec2::new(x, z) * s.height_frequency;
+    s.base_height
+      + fbm2(p, s.seed, s.octaves, s.lacunarity, s.persistence)
+        * s.height_amplitude
+  }
+
+  /// Whether the voxel at world position `pos` is carved out by a cave.
+  fn is_cave(&self, pos: Vec3, height: f32) -> bool {
+    let s = &self.settings;
+    if pos.y > height - s.cave_surface_skin {
+      return false;
+    }
+    // two noise fields are near zero along a line, which makes tunnels
+    let p = pos * s.cave_frequency;
+    let a = fbm3(p, s.seed.wrapping_add(1), 2, 2.0, 0.5);
+    let b = fbm3(p, s.seed.wrapping_add(2), 2, 2.0, 0.5);
+    a.abs() < s.cave_width && b.abs() < s.cave_width
+  }
+
+  /// Whether the voxel at world position `pos` is solid.
+  fn is_solid(&self, pos: IVec3) -> bool {
+    let pos = pos.as_vec3() + 0.5;
+    let height = self.height(pos.x, pos.z);
+    pos.y <= height && !self.is_cave(pos, height)
+  }
+
+  /// The outward normal of the solid voxel at `pos`, from which of its
+  /// neighbors are open.
+  fn normal(&self, pos: IVec3) -> Vec3 {
+    let mut normal = Vec3::ZERO;
+    for offset in [IVec3::X, IVec3::Y, IVec3::Z] {
+      let open = |p| !self.is_solid(p) as i32 as f32;
+      normal += offset.as_vec3() * (open(pos + offset) - open(pos - offset));
+    }
+    normal.try_normalize().unwrap_or(Vec3::Y)
+  }
+
+  /// The surface height, slope and climate of every column of the chunk at
+  /// `origin`, indexed `z * CHUNK_SIZE + x`.
+  fn columns(&self, origin: IVec3) -> Vec<Column> {
+    let s = &self.settings;
+    let size = CHUNK_SIZE as i32;
+    (0..size * size)
+      .map(|i| {
+        let x = (origin.x + i % size) as f32 + 0.5;
+        let z = (origin.z + i / size) as f32 + 0.5;
+        let height = self.height(x, z);
+
+        // central differences, so the slope agrees across chunk borders
+        let gradient = Vec2::new(
+          self.height(x + 1.0, z) - self.height(x - 1.0, z),
+          self.height(x, z + 1.0) - self.height(x, z - 1.0),
+        ) / 2.0;
+        let climate = fbm2(
+          Vec2::new(x, z) * self.layers.biome_frequency,
+          s.seed.wrapping_add(3),
+          3,
+          2.0,
+          0.5,
+        );
+
+        Column {
+          height,
+          slope: gradient.length().atan().to_degrees(),
+          climate,
+        }
+      })
+      .collect()
+  }
+
+  /// Which voxels of the chunk at `origin` are solid.
+  fn occupancy(&self, origin: IVec3, columns: &[Column]) -> Vec<bool> {
+    let mut solid = vec![false; CHUNK_VOXEL_COUNT];
+    for (index, solid) in solid.iter_mut().enumerate() {
+      let local = voxel_pos(index);
+      let pos = (origin + local.as_ivec3()).as_vec3() + 0.5;
+      let height =
+        columns[local.z as usize * CHUNK_SIZE + local.x as usize].height;
+      *solid = pos.y <= height && !self.is_cave(pos, height);
+    }
+    solid
+  }
+
+  /// Whether the solid voxel at `local` has an open neighbor, looking into
+  /// the neighboring chunks at the border.
+  fn is_exposed(&self, origin: IVec3, local: IVec3, solid: &[bool]) -> bool {
+    let size = IVec3::splat(CHUNK_SIZE as i32);
+    [IVec3::X, IVec3::Y, IVec3::Z]
+      .into_iter()
+      .flat_map(|o| [local + o, local - o])
+      .any(
+        |n| match n.cmpge(IVec3::ZERO).all() && n.cmplt(size).all() {
+          true => !solid[voxel_index(n.as_uvec3())],
+          false => !self.is_solid(origin + n),
+        },
+      )
+  }
+
+  /// Generates the chunk at `chunk_pos`, or `None` if it is empty.
+  pub fn generate(&self, chunk_pos: IVec3) -> Option<Chunk> {
+    let origin = VoxelWorld::join(chunk_pos, UVec3::ZERO);
+    let columns = self.columns(origin);
+    let max_height = columns.iter().map(|c| c.height).fold(f32::MIN, f32::max);
+    if (origin.y as f32) > max_height {
+      return None;
+    }
+
+    let solid = self.occupancy(origin, &columns);
+    if !solid.contains(&true) {
+      return None;
+    }
+
+    // layering runs once occupancy is known, so rules can't affect shape
+    let data = solid
+      .iter()
+      .enumerate()
+      .map(|(index, is_solid)| {
+        if !is_solid {
+          return None;
+        }
+        let local = voxel_pos(index);
+        let pos = origin + local.as_ivec3();
+        let column = &columns[local.z as usize * CHUNK_SIZE + local.x as usize];
+        let height = pos.y as f32 + 0.5;
+
+        // only voxels with an open neighbor are visible, so only they pay
+        // for a real normal
+        let normal = match self.is_exposed(origin, local.as_ivec3(), &solid) {
+          true => self.normal(pos),
+          false => Vec3::Y,
+        };
+        let sample = LayerSample {
+          height,
+          depth: column.height - height,
+          slope: column.slope,
+          climate: column.climate,
+        };
+        let material = match self.layers.find(&sample) {
+          Some((biome, layer)) => self.layer_materials[biome][layer],
+          None => self.fallback_material,
+        };
+        Some(FullVoxel::new(normal, material))
+      })
+      .collect();
+
+    Some(Chunk::new(ChunkData::Full { data }).to_palette())
+  }
+}
+
+/// Per-column terrain values shared by every voxel in the column.
+struct Column {
+  height:  f32,
+  slope:   f32,
+  climate: f32,
+}
+
+/// The terrain of the world, whose generator chunk streaming uses.
+#[derive(Resource)]
+pub struct Terrain {
+  pub settings: TerrainSettings,
+  pub layers:   Handle<TerrainLayers>,
+}
+
+/// Hands chunk streaming a new generator whenever the terrain or its layers
+/// change. Chunks that are already loaded keep their old contents.
+pub fn update_terrain_generator(
+  terrain: Option<Res<Terrain>>,
+  layers: Res<Assets<TerrainLayers>>,
+  materials: Res<VoxelMaterials>,
+  mut events: EventReader<AssetEvent<TerrainLayers>>,
+  mut streaming: ResMut<ChunkStreaming>,
+) {
+  let Some(terrain) = terrain else {
+    return;
+  };
+  let layers_changed = events.read().any(|event| match event {
+    AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+      *id == terrain.layers.id()
+    }
+    _ => false,
+  });
+  if !layers_changed && !terrain.is_changed() {
+    return;
+  }
+
+  let Some(layers) = layers.get(&terrain.layers) else {
+    return;
+  };
+  streaming.generator = Some(
+    TerrainGenerator::new(terrain.settings.clone(), layers.clone(), &materials)
+      .into_chunk_generator(),
+  );
+}
+
+/// Hashes integer lattice coordinates with the seed.
+fn hash(seed: u32, coords: &[i32]) -> u32 {
+  let mut h = seed.wrapping_mul(0x9e37_79b9);
+  for &c in coords {
+    h ^= c as u32;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+  }
+  h
+}
+
+fn fade(t: f32) -> f32 { t * t * t * (t * (t * 6.0 - 15.0) + 10.0) }
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 { a + (b - a) * t }
+
+/// 2D gradient noise in roughly `-1..=1`.
+fn noise2(p: Vec2, seed: u32) -> f32 {
+  let cell = p.floor();
+  let f = p - cell;
+  let cell = cell.as_ivec2();
+
+  let corner = |offset: IVec2| {
+    let angle = hash(seed, &[cell.x + offset.x, cell.y + offset.y]) as f32
+      * (std::f32::consts::TAU / u32::MAX as f32);
+    Vec2::from_angle(angle).dot(f - offset.as_vec2())
+  };
+  let (u, v) = (fade(f.x), fade(f.y));
+  let bottom = lerp(corner(IVec2::new(0, 0)), corner(IVec2::new(1, 0)), u);
+  let top = lerp(corner(IVec2::new(0, 1)), corner(IVec2::new(1, 1)), u);
+  lerp(bottom, top, v) * std::f32::consts::SQRT_2
+}
+
+/// The gradients of 3D noise, the edge midpoints of a cube.
+const GRADIENTS_3D: [Vec3; 12] = [
+  Vec3::new(1.0, 1.0, 0.0),
+  Vec3::new(-1.0, 1.0, 0.0),
+  Vec3::new(1.0, -1.0, 0.0),
+  Vec3::new(-1.0, -1.0, 0.0),
+  Vec3::new(1.0, 0.0, 1.0),
+  Vec3::new(-1.0, 0.0, 1.0),
+  Vec3::new(1.0, 0.0, -1.0),
+  Vec3::new(-1.0, 0.0, -1.0),
+  Vec3::new(0.0, 1.0, 1.0),
+  Vec3::new(0.0, -1.0, 1.0),
+  Vec3::new(0.0, 1.0, -1.0),
+  Vec3::new(0.0, -1.0, -1.0),
+];
+
+/// 3D gradient noise in roughly `-1..=1`.
+fn noise3(p: Vec3, seed: u32) -> f32 {
+  let cell = p.floor();
+  let f = p - cell;
+  let cell = cell.as_ivec3();
+
+  let corner = |offset: IVec3| {
+    let c = cell + offset;
+    let gradient = GRADIENTS_3D[hash(seed, &[c.x, c.y, c.z]) as usize % 12];
+    gradient.dot(f - offset.as_vec3())
+  };
+  let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+  let layer = |z| {
+    let bottom =
+      lerp(corner(IVec3::new(0, 0, z)), corner(IVec3::new(1, 0, z)), u);
+    let top = lerp(corner(IVec3::new(0, 1, z)), corner(IVec3::new(1, 1, z)), u);
+    lerp(bottom, top, v)
+  };
+  lerp(layer(0), layer(1), w)
+}
+
+/// Sums octaves of noise, normalized back to roughly `-1..=1`.
+fn fbm(
+  octaves: u32,
+  lacunarity: f32,
+  persistence: f32,
+  mut sample: impl FnMut(f32, u32) -> f32,
+) -> f32 {
+  let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, 1.0);
+  for octave in 0..octaves.max(1) {
+    sum += sample(frequency, octave) * amplitude;
+    total += amplitude;
+    amplitude *= persistence;
+    frequency *= lacunarity;
+  }
+  sum / total
+}
+
+fn fbm2(
+  p: Vec2,
+  seed: u32,
+  octaves: u32,
+  lacunarity: f32,
+  persistence: f32,
+) -> f32 {
+  fbm(octaves, lacunarity, persistence, |frequency, octave| {
+    noise2(p * frequency, hash(seed, &[octave as i32]))
+  })
+}
+
+fn fbm3(
+  p: Vec3,
+  seed: u32,
+  octaves: u32,
+  lacunarity: f32,
+  persistence: f32,
+) -> f32 {
+  fbm(octaves, lacunarity, persistence, |frequency, octave| {
+    noise3(p * frequency, hash(seed, &[octave as i32]))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn generator(seed: u32) -> TerrainGenerator {
+    let settings = TerrainSettings { seed, ..default() };
+    let materials = VoxelMaterials::default();
+    TerrainGenerator::new(settings, TerrainLayers::default(), &materials)
+  }
+
+  /// The edge length of the snapshot box, in voxels.
+  const SNAPSHOT_SIZE: i32 = 16;
+
+  /// A column of the snapshot box: the height of its highest solid voxel,
+  /// how many of its voxels are solid, and the material on top.
+  #[derive(Debug, PartialEq)]
+  struct Column {
+    top:      i32,
+    solid:    i32,
+    material: Option<MaterialId>,
+  }
+
+  /// The columns of the world box from `min`, in rows along x. Only
+  /// occupancy and materials are kept, as normals depend on the platform's
+  /// `sin`, `cos` and `atan`.
+  fn snapshot(generator: &TerrainGenerator, min: IVec3) -> Vec<Column> {
+    let mut chunks = bevy::utils::HashMap::new();
+    let mut columns = Vec::new();
+    for z in min.z..min.z + SNAPSHOT_SIZE {
+      for x in min.x..min.x + SNAPSHOT_SIZE {
+        let mut column = Column {
+          top:      min.y - 1,
+          solid:    0,
+          material: None,
+        };
+        for y in min.y..min.y + SNAPSHOT_SIZE {
+          let (chunk_pos, local) = VoxelWorld::split(IVec3::new(x, y, z));
+          let chunk = chunks
+            .entry(chunk_pos)
+            .or_insert_with(|| generator.generate(chunk_pos));
+          if let Some(voxel) =
+            chunk.as_ref().and_then(|c| c.get(local).unwrap())
+          {
+            column = Column {
+              top:      y,
+              solid:    column.solid + 1,
+              material: Some(voxel.material),
+            };
+          }
+        }
+        columns.push(column);
+      }
+    }
+    columns
+  }
+
+  /// One line per row, each column as `top/solid/material`.
+  fn format_snapshot(columns: &[Column]) -> String {
+    let columns = columns.iter().map(|c| {
+      let material = c.material.map_or("-".into(), |m| m.to_string());
+      format!("{}/{}/{material}", c.top, c.solid)
+    });
+    let columns = columns.collect::<Vec<_>>();
+    let rows = columns
+      .chunks(SNAPSHOT_SIZE as usize)
+      .map(|row| row.join(" "));
+    rows.map(|row| row + "\n").collect()
+  }
+
+  fn parse_snapshot(text: &str) -> Vec<Column> {
+    text
+      .split_whitespace()
+      .map(|column| {
+        let [top, solid, material] = column.split('/').collect::<Vec<_>>()[..]
+        else {
+          panic!("invalid snapshot column {column}");
+        };
+        Column {
+          top:      top.parse().unwrap(),
+          solid:    solid.parse().unwrap(),
+          material: material.parse().ok(),
+        }
+      })
+      .collect()
+  }
+
+  /// The dense voxels of the chunk at `chunk_pos`.
+  fn voxels(
+    generator: &TerrainGenerator,
+    chunk_pos: IVec3,
+  ) -> Option<Vec<Option<FullVoxel>>> {
+    let chunk = generator.generate(chunk_pos)?.to_full();
+    match chunk.data() {
+      ChunkData::Full { data } => Some(data.clone()),
+      _ => unreachable!(),
+    }
+  }
+
+  #[test]
+  fn generates_the_same_chunk_every_time() {
+    let (a, b) = (generator(7), generator(7));
+    for chunk_pos in [IVec3::ZERO, IVec3::NEG_Y, IVec3::new(3, -1, -2)] {
+      assert_eq!(
+        voxels(&a, chunk_pos),
+        voxels(&b, chunk_pos),
+        "chunk {chunk_pos}"
+      );
+    }
+    assert_ne!(
+      voxels(&generator(7), IVec3::NEG_Y),
+      voxels(&generator(8), IVec3::NEG_Y)
+    );
+  }
+
+  #[test]
+  fn matches_the_snapshot() {
+    // voxels are a function of world position alone, so the snapshot holds
+    // for every chunk size. Update it only on purpose, since it changes every
+    // saved world's terrain.
+    let expected = parse_snapshot(include_str!("terrain_snapshot.txt"));
+    let actual = snapshot(&generator(0), IVec3::new(-8, -12, -8));
+    let message = format!("snapshot is now:\n{}", format_snapshot(&actual));
+    assert_eq!(actual.len(), expected.len(), "{message}");
+
+    // a float function a last bit off on another platform may move a
+    // threshold by a voxel, but not much further
+    let mut other_materials = 0;
+    for (actual, expected) in actual.iter().zip(&expected) {
+      assert!((actual.top - expected.top).abs() <= 1, "{message}");
+      assert!((actual.solid - expected.solid).abs() <= 1, "{message}");
+      other_materials += (actual.material != expected.material) as usize;
+    }
+    assert!(other_materials <= 4, "{message}");
+  }
+}